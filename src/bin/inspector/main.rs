@@ -1,11 +1,15 @@
-use std::error::Error;
+use std::{error::Error, time::Duration};
 
 use clap::{command, Parser, Subcommand, ValueEnum};
 
 use ups::{
+    command::{self, Command},
+    detect::{detect, ProbeOutcome},
+    device::{enumerate, DeviceFilter, DeviceSelector},
+    framing::CrcPolicy,
     hid_device::HidDevice,
     megatec_hid_ups::MegatecHidUps,
-    ups::{Ups, UpsStatusFlags},
+    ups::{SelfTest, Ups, UpsCapabilities, UpsStatusFlags},
     voltronic_hid_ups::VoltronicHidUps,
 };
 
@@ -13,6 +17,8 @@ use ups::{
 enum Model {
     Voltronic,
     Megatec,
+    /// Probe the UPS for its protocol
+    Auto,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
@@ -41,15 +47,15 @@ impl From<&OnOff> for bool {
 struct Cli {
     /// The UPS model
     #[arg(short = 'm', long)]
-    model: Model,
+    model: Option<Model>,
 
     /// The VID of the UPS
     #[arg(short = 'v', long)]
-    vendor_id: u16,
+    vendor_id: Option<u16>,
 
     /// The PID of the UPS
     #[arg(short = 'p', long)]
-    product_id: u16,
+    product_id: Option<u16>,
 
     /// The HID usage ID of the UPS
     #[arg(short = 'U', long)]
@@ -59,47 +65,165 @@ struct Cli {
     #[arg(short = 'P', long)]
     usage_page: Option<u16>,
 
+    /// The serial number of the UPS, to choose among identical devices
+    #[arg(short = 's', long, group = "selector")]
+    serial_number: Option<String>,
+
+    /// The device path of the UPS, as shown by the list command
+    #[arg(long, group = "selector")]
+    path: Option<String>,
+
+    /// The position of the UPS in the list of matching devices, counting from 0
+    #[arg(short = 'n', long, group = "selector")]
+    ordinal: Option<usize>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
+    /// Lists the connected HID devices matching the given IDs and usages
+    List,
+
+    /// Probes the UPS for its protocol and reports what was found
+    Detect,
+
     /// Displays the UPS status
     Status,
 
+    /// Lists what the UPS supports
+    Capabilities,
+
+    /// Displays the UPS identity and nominal ratings
+    Info,
+
     /// Beeper control
     Beeper {
         /// Beeper state to set
         state: Option<OnOff>,
     },
+
+    /// Runs a battery test and waits for its outcome
+    SelfTest {
+        /// Test for this many minutes, instead of about 10 seconds
+        #[arg(long, group = "kind")]
+        minutes: Option<u8>,
+
+        /// Test until the battery runs low
+        #[arg(long, group = "kind")]
+        until_low: bool,
+
+        /// Cancel the running test instead
+        #[arg(long, group = "kind")]
+        cancel: bool,
+    },
+
+    /// Sends a single protocol command and displays the parsed response
+    Raw {
+        /// The command, in its wire form, like QS or PEa
+        command: String,
+    },
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
-    let device =
-        HidDevice::new(cli.usage_page, cli.usage_id, cli.vendor_id, cli.product_id).await?;
+    if let Commands::List = cli.command {
+        let filter = DeviceFilter {
+            vendor_id: cli.vendor_id,
+            product_id: cli.product_id,
+            usage_page: cli.usage_page,
+            usage_id: cli.usage_id,
+        };
+        for (ordinal, device) in enumerate(&filter).await?.iter().enumerate() {
+            println!("{}: {}", ordinal, device);
+        }
+        return Ok(());
+    }
 
-    let ups: Box<dyn Ups> = match cli.model {
-        Model::Voltronic => Box::new(VoltronicHidUps::new(device)?),
-        Model::Megatec => Box::new(MegatecHidUps::new(device)?),
-    };
+    if let Commands::Detect = cli.command {
+        let detection = detect(open_device(&cli).await?).await?;
+        for result in &detection.report.probes {
+            match &result.outcome {
+                ProbeOutcome::Matched(response) => {
+                    println!("{}: matched {:?}", result.probe, response)
+                }
+                ProbeOutcome::Unexpected(response) => {
+                    println!("{}: unexpected {:?}", result.probe, response)
+                }
+                ProbeOutcome::Unsupported(response) => {
+                    println!("{}: unsupported {:?}", result.probe, response)
+                }
+                ProbeOutcome::Failed(error) => println!("{}: failed ({})", result.probe, error),
+            }
+        }
+        println!("Protocol: {}", detection.protocol);
+        if let Some(confidence) = detection.report.confidence {
+            println!("Confidence: {}", confidence);
+        }
+        return Ok(());
+    }
+
+    if let Commands::Raw { command } = &cli.command {
+        let command: Command = command.parse()?;
+        let device = open_device(&cli).await?;
+        let response = match cli.model {
+            Some(Model::Megatec) => command::transact_indexed(&device, command).await?,
+            _ => command::transact(&device, command, CrcPolicy::Optional).await?,
+        };
+        println!("{:#?}", response);
+        return Ok(());
+    }
+
+    let ups = open_ups(&cli).await?;
 
     match cli.command {
+        Commands::List | Commands::Detect | Commands::Raw { .. } => unreachable!(),
         Commands::Status => {
             let status = ups.status().await?;
             println!("{:#?}", status);
         }
+        Commands::Capabilities => {
+            println!("{:?}", ups.capabilities().await?);
+        }
+        Commands::Info => {
+            println!("{:#?}", ups.info().await?);
+            if ups.capabilities().await?.contains(UpsCapabilities::RATINGS) {
+                println!("{:#?}", ups.ratings().await?);
+            }
+        }
+        Commands::SelfTest {
+            minutes,
+            until_low,
+            cancel,
+        } => {
+            ups.capabilities()
+                .await?
+                .require(UpsCapabilities::SELF_TEST)?;
+
+            if cancel {
+                ups.cancel_self_test().await?;
+                println!("Battery test cancelled");
+                return Ok(());
+            }
+
+            let test = match minutes {
+                Some(minutes) => SelfTest::Timed(minutes),
+                None if until_low => SelfTest::UntilBatteryLow,
+                None => SelfTest::Quick,
+            };
+            let mut handle = ups.start_self_test(test).await?;
+            println!("Battery test started");
+
+            let state = handle.wait(ups.as_ref(), Duration::from_secs(1)).await?;
+            println!("Battery test: {:?}", state);
+        }
         Commands::Beeper { state } => {
             if let Some(state) = state {
-                let on: bool = state.into();
-                let should_toggle = on ^ beeper_on(ups.as_ref()).await?;
-
-                if should_toggle {
-                    ups.beeper_toggle().await?;
-                }
+                ups.capabilities().await?.require(UpsCapabilities::BEEPER)?;
+                ups.set_beeper(state.into()).await?;
             }
 
             println!(
@@ -116,6 +240,40 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+async fn open_ups(cli: &Cli) -> Result<Box<dyn Ups>, Box<dyn Error>> {
+    let model = cli.model.ok_or("The UPS model is required")?;
+    let device = open_device(cli).await?;
+
+    Ok(match model {
+        Model::Voltronic => Box::new(VoltronicHidUps::new(device)?),
+        Model::Megatec => Box::new(MegatecHidUps::new(device)?),
+        Model::Auto => detect(device).await?.ups,
+    })
+}
+
+async fn open_device(cli: &Cli) -> Result<HidDevice, Box<dyn Error>> {
+    let vendor_id = cli.vendor_id.ok_or("The VID of the UPS is required")?;
+    let product_id = cli.product_id.ok_or("The PID of the UPS is required")?;
+
+    let filter = DeviceFilter {
+        vendor_id: Some(vendor_id),
+        product_id: Some(product_id),
+        usage_page: cli.usage_page,
+        usage_id: cli.usage_id,
+    };
+    let selector = if let Some(serial_number) = &cli.serial_number {
+        DeviceSelector::SerialNumber(serial_number.clone())
+    } else if let Some(path) = &cli.path {
+        DeviceSelector::Path(path.clone())
+    } else if let Some(ordinal) = cli.ordinal {
+        DeviceSelector::Ordinal(ordinal)
+    } else {
+        DeviceSelector::Any
+    };
+
+    Ok(HidDevice::open(&filter, &selector).await?)
+}
+
 async fn beeper_on(ups: &dyn Ups) -> Result<bool, Box<dyn Error>> {
     Ok(ups
         .status()
@@ -1,17 +1,20 @@
-use std::convert::TryInto;
+use std::{convert::TryInto, io};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
+use ups::{battery::Chemistry, device::DeviceSelector};
 use winreg::{enums::HKEY_LOCAL_MACHINE, RegKey};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
 pub enum Model {
     Voltronic = 0,
     Megatec = 1,
+    /// Probe the UPS for its protocol on every connection
+    Auto = 2,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub(crate) struct RuntimeConfig {
     pub model: Model,
     pub hibernate: bool,
@@ -22,6 +25,27 @@ pub(crate) struct RuntimeConfig {
     pub hid_usage_id: Option<u16>,
     pub vendor_id: u16,
     pub product_id: u16,
+    /// Which of several devices matching the IDs above to use
+    pub device_selector: DeviceSelector,
+    /// `host:port` of a serial-to-Ethernet server to reach the UPS through,
+    /// instead of looking for it by VID/PID
+    pub tcp_address: Option<String>,
+    /// When set, have the UPS turn its output off this long after the host
+    /// starts shutting down, so the battery isn't drained into a dead load.
+    /// Has to cover the time the host takes to shut down.
+    pub kill_power_delay_s: Option<u32>,
+    /// How long after killing power the UPS turns its output back on, if
+    /// mains power is there. Without it, the output stays off even once mains
+    /// power returns. UPSes that can do neither bring their output back with
+    /// the mains.
+    pub kill_power_restore_min: Option<u32>,
+    /// Shut down once the battery charge drops below this many percent,
+    /// on top of when the UPS flags the battery as low
+    pub low_battery_percent: Option<u32>,
+    /// Used to estimate the charge of UPSes that only report a voltage
+    pub battery_chemistry: Chemistry,
+    /// Cells in the battery string, for UPSes whose ratings don't say
+    pub battery_cells: Option<u32>,
 }
 
 impl RuntimeConfig {
@@ -38,6 +62,25 @@ impl RuntimeConfig {
         let hid_usage_id: Option<u32> = key.get_value("hid_usage_id").ok();
         let vendor_id: u32 = key.get_value("vendor_id")?;
         let product_id: u32 = key.get_value("product_id")?;
+        let device_serial_number: Option<String> = key.get_value("device_serial_number").ok();
+        let device_path: Option<String> = key.get_value("device_path").ok();
+        let device_ordinal: Option<u32> = key.get_value("device_ordinal").ok();
+        let tcp_address: Option<String> = key.get_value("tcp_address").ok();
+        let kill_power_delay_s: Option<u32> = key.get_value("kill_power_delay_s").ok();
+        let kill_power_restore_min: Option<u32> = key.get_value("kill_power_restore_min").ok();
+        let low_battery_percent: Option<u32> = key.get_value("low_battery_percent").ok();
+        let battery_chemistry: Option<String> = key.get_value("battery_chemistry").ok();
+        let battery_cells: Option<u32> = key.get_value("battery_cells").ok();
+
+        let device_selector = match (device_serial_number, device_path, device_ordinal) {
+            (None, None, None) => DeviceSelector::Any,
+            (Some(serial_number), None, None) => DeviceSelector::SerialNumber(serial_number),
+            (None, Some(path), None) => DeviceSelector::Path(path),
+            (None, None, Some(ordinal)) => DeviceSelector::Ordinal(ordinal.try_into()?),
+            _ => bail!(
+                "At most one of device_serial_number, device_path and device_ordinal may be set"
+            ),
+        };
 
         Ok(Self {
             model,
@@ -49,6 +92,16 @@ impl RuntimeConfig {
             hid_usage_id: hid_usage_id.map(u32::try_into).transpose()?,
             vendor_id: vendor_id.try_into()?,
             product_id: product_id.try_into()?,
+            device_selector,
+            tcp_address,
+            kill_power_delay_s,
+            kill_power_restore_min,
+            low_battery_percent,
+            battery_chemistry: battery_chemistry
+                .map(|chemistry| chemistry.parse())
+                .transpose()?
+                .unwrap_or_default(),
+            battery_cells,
         })
     }
 
@@ -81,6 +134,41 @@ impl RuntimeConfig {
         let product_id: u32 = self.product_id.into();
         key.set_value("product_id", &product_id)?;
 
+        for name in ["device_serial_number", "device_path", "device_ordinal"] {
+            delete_value_if_exists(&key, name)?;
+        }
+        match &self.device_selector {
+            DeviceSelector::Any => {}
+            DeviceSelector::SerialNumber(serial_number) => {
+                key.set_value("device_serial_number", serial_number)?
+            }
+            DeviceSelector::Path(path) => key.set_value("device_path", path)?,
+            DeviceSelector::Ordinal(ordinal) => {
+                let ordinal: u32 = (*ordinal).try_into()?;
+                key.set_value("device_ordinal", &ordinal)?;
+            }
+        }
+
+        if let Some(tcp_address) = &self.tcp_address {
+            key.set_value("tcp_address", tcp_address)?;
+        } else {
+            delete_value_if_exists(&key, "tcp_address")?;
+        }
+
+        for (name, value) in [
+            ("kill_power_delay_s", self.kill_power_delay_s),
+            ("kill_power_restore_min", self.kill_power_restore_min),
+            ("low_battery_percent", self.low_battery_percent),
+            ("battery_cells", self.battery_cells),
+        ] {
+            match value {
+                Some(value) => key.set_value(name, &value)?,
+                None => delete_value_if_exists(&key, name)?,
+            }
+        }
+
+        key.set_value("battery_chemistry", &self.battery_chemistry.to_string())?;
+
         Ok(())
     }
 
@@ -104,10 +192,24 @@ impl Default for RuntimeConfig {
             hid_usage_id: Some(0x0001),
             vendor_id: 0x0665,
             product_id: 0x5161,
+            device_selector: DeviceSelector::Any,
+            tcp_address: None,
+            kill_power_delay_s: None,
+            kill_power_restore_min: None,
+            low_battery_percent: None,
+            battery_chemistry: Chemistry::LeadAcid,
+            battery_cells: None,
         }
     }
 }
 
+fn delete_value_if_exists(key: &RegKey, name: &str) -> io::Result<()> {
+    match key.delete_value(name) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
 pub(crate) struct HardCodedConfig;
 
 impl HardCodedConfig {
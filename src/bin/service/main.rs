@@ -7,6 +7,7 @@ mod sessions;
 mod token;
 
 use std::{
+    convert::Infallible,
     env,
     error::Error,
     ffi::c_void,
@@ -21,7 +22,7 @@ use lazy_static::lazy_static;
 use log::{debug, error, info, trace, warn};
 use tokio::{
     sync::{watch, Notify},
-    time::sleep,
+    time::{sleep, timeout},
 };
 use utf16_lit::utf16_null;
 use windows::{
@@ -58,14 +59,22 @@ use services::{ScManager, ScManagerAccessRights, ServiceAccessRights};
 use sessions::WTSServer;
 use token::Token;
 use ups::{
+    battery::BatteryModel,
+    detect::detect,
+    device::{enumerate, DeviceFilter},
+    error::UpsError,
     hid_device::HidDevice,
     megatec_hid_ups::MegatecHidUps,
-    ups::{Ups, UpsStatus, UpsStatusFlags, UpsWorkMode},
+    megatec_serial_ups::MegatecSerialUps,
+    tcp_transport::TcpTransport,
+    transport::Transport,
+    ups::{Ups, UpsCapabilities, UpsRatings, UpsStatus, UpsStatusFlags, UpsWorkMode},
     voltronic_hid_ups::VoltronicHidUps,
 };
 
 static SERVICE_HANDLE: AtomicIsize = AtomicIsize::new(0);
 static SHUTDOWN: Notify = Notify::const_new();
+const KILL_POWER_ACK_TIMEOUT: Duration = Duration::from_secs(10);
 lazy_static! {
     static ref WAKEUP: Event = Event::new(true, false).unwrap();
 }
@@ -231,11 +240,14 @@ async fn run_service() {
     debug!("{:?}", config);
 
     let (tx, rx) = watch::channel(None);
+    let (kill_power_tx, kill_power_rx) = watch::channel(false);
+    // The kill power state the UPS last accepted, or None if that's unknown
+    let (kill_power_ack_tx, kill_power_ack_rx) = watch::channel(Some(false));
 
     report_service_status(SERVICE_RUNNING, ERROR_SUCCESS.0, 0);
 
     tokio::select! {
-        result = ups_query_task(&config, tx) => {
+        result = ups_query_task(&config, tx, kill_power_rx, kill_power_ack_tx) => {
             if let Err(error) = result {
                 error!("UPS query failed with {:?}", error);
                 report_service_status(SERVICE_STOPPED, ERROR_ARENA_TRASHED.0, 0);
@@ -244,7 +256,7 @@ async fn run_service() {
                 unreachable!();
             }
         }
-        result = main_loop(&config, rx) => {
+        result = main_loop(&config, rx, kill_power_tx, kill_power_ack_rx) => {
             if let Err(error) = result {
                 error!("Main loop failed with {:?}", error);
                 report_service_status(SERVICE_STOPPED, ERROR_ARENA_TRASHED.0, 0);
@@ -262,36 +274,227 @@ async fn run_service() {
 async fn ups_query_task(
     config: &RuntimeConfig,
     tx: watch::Sender<Option<UpsStatus>>,
+    mut kill_power: watch::Receiver<bool>,
+    kill_power_ack: watch::Sender<Option<bool>>,
 ) -> anyhow::Result<()> {
     loop {
-        {
-            let device = HidDevice::new(
-                config.hid_usage_page,
-                config.hid_usage_id,
-                config.vendor_id,
-                config.product_id,
-            )
-            .await?;
+        let error = match query_ups(config, &tx, &mut kill_power, &kill_power_ack).await {
+            Ok(never) => match never {},
+            Err(error) => error,
+        };
 
-            let ups: Box<dyn Ups> = match config.model {
-                config::Model::Voltronic => Box::new(VoltronicHidUps::new(device)?),
-                config::Model::Megatec => Box::new(MegatecHidUps::new(device)?),
-            };
+        match UpsError::of(&error) {
+            // Retrying won't help with these, the configuration has to change
+            Some(UpsError::Unsupported(_)) | Some(UpsError::Ambiguous(_)) => return Err(error),
+            Some(UpsError::DeviceNotFound(_)) => warn!("UPS not found: {}", error),
+            Some(UpsError::Timeout(_)) => warn!("UPS stopped responding: {}", error),
+            Some(UpsError::Disconnected(_)) => warn!("UPS disconnected: {}", error),
+            _ => warn!("UPS query failed with {:?}", error),
+        }
 
-            while let Ok(status) = ups.status().await {
-                let _ignore = tx.send(Some(status));
-                sleep(Duration::from_millis(config.poll_interval_ms.into())).await;
+        sleep(Duration::from_millis(config.poll_failure_timeout_ms.into())).await;
+    }
+}
+
+/// Connect to the UPS and publish its status until something fails. Also
+/// arms and disarms the kill power as `kill_power` changes, acknowledging
+/// each attempt on `kill_power_ack`.
+async fn query_ups(
+    config: &RuntimeConfig,
+    tx: &watch::Sender<Option<UpsStatus>>,
+    kill_power: &mut watch::Receiver<bool>,
+    kill_power_ack: &watch::Sender<Option<bool>>,
+) -> anyhow::Result<Infallible> {
+    let device: Box<dyn Transport> = match &config.tcp_address {
+        Some(address) => Box::new(TcpTransport::connect(address.as_str()).await?),
+        None => {
+            log_candidate_devices(config).await;
+
+            Box::new(HidDevice::open(&device_filter(config), &config.device_selector).await?)
+        }
+    };
+
+    let ups: Box<dyn Ups> = match (config.model, &config.tcp_address) {
+        (config::Model::Voltronic, _) => Box::new(VoltronicHidUps::new(device)?),
+        (config::Model::Megatec, None) => Box::new(MegatecHidUps::new(device)?),
+        // Serial-to-Ethernet servers carry the plain command protocol
+        (config::Model::Megatec, Some(_)) => Box::new(MegatecSerialUps::new(device)?),
+        (config::Model::Auto, _) => {
+            let detection = detect(device).await?;
+            info!(
+                "Detected {} UPS protocol ({})",
+                detection.protocol, detection.report
+            );
+            detection.ups
+        }
+    };
+    let capabilities = ups.capabilities().await?;
+    info!("UPS capabilities: {:?}", capabilities);
+    // Only informational, so don't give up on the UPS over these
+    match ups.info().await {
+        Ok(info) => info!("UPS identity: {:?}", info),
+        Err(error) => warn!("Could not identify the UPS: {:?}", error),
+    }
+    let ratings = if capabilities.contains(UpsCapabilities::RATINGS) {
+        match ups.ratings().await {
+            Ok(ratings) => {
+                info!("UPS ratings: {:?}", ratings);
+                ratings
+            }
+            Err(error) => {
+                warn!("Could not read the UPS ratings: {:?}", error);
+                UpsRatings::default()
             }
         }
+    } else {
+        UpsRatings::default()
+    };
+    let battery = battery_model(config, &ratings);
+
+    // The UPS may have been replaced or restarted since the kill power was
+    // last set, so set it again, as well as any change that didn't make it
+    let armed = *kill_power.borrow_and_update();
+    if armed || *kill_power_ack.borrow() != Some(armed) {
+        let applied = set_kill_power(config, ups.as_ref(), capabilities, armed).await;
+        kill_power_ack.send_replace(applied.then_some(armed));
+    }
 
-        warn!("UPS query failed");
-        sleep(Duration::from_millis(config.poll_failure_timeout_ms.into())).await;
+    loop {
+        let mut status = ups.status().await?;
+        if let (None, Some(battery)) = (status.battery_capacity, &battery) {
+            status.battery_capacity = battery.charge(&status).map(|charge| charge.round() as u32);
+        }
+        if status.is_valid() {
+            let _ignore = tx.send(Some(status));
+        } else {
+            // Garbage may well read as "on line, no faults", so keep acting
+            // on the last good status instead
+            let invalid_fields: Vec<_> = status
+                .invalid_fields
+                .iter()
+                .map(|invalid| format!("invalid {}", invalid))
+                .collect();
+            warn!(
+                "Ignoring UPS status with {}: {:?}",
+                invalid_fields.join(", "),
+                status
+            );
+        }
+
+        tokio::select! {
+            () = sleep(Duration::from_millis(config.poll_interval_ms.into())) => {}
+            result = kill_power.changed() => {
+                result?;
+                let armed = *kill_power.borrow_and_update();
+                let applied = set_kill_power(config, ups.as_ref(), capabilities, armed).await;
+                kill_power_ack.send_replace(applied.then_some(armed));
+            }
+        }
+    }
+}
+
+/// Have the UPS turn its output off after the configured delay, or cancel
+/// that, returning whether the UPS took it. Failing is logged but not fatal,
+/// as the shutdown goes on regardless.
+async fn set_kill_power(
+    config: &RuntimeConfig,
+    ups: &dyn Ups,
+    capabilities: UpsCapabilities,
+    armed: bool,
+) -> bool {
+    let delay = match config.kill_power_delay_s {
+        Some(delay) => Duration::from_secs(delay.into()),
+        None => return !armed,
+    };
+    if !capabilities.contains(UpsCapabilities::SHUTDOWN) {
+        warn!("Kill power is configured, but the UPS can't turn its output off");
+        return !armed;
+    }
+
+    if armed {
+        let mut restore_after = config
+            .kill_power_restore_min
+            .map(|minutes| Duration::from_secs(u64::from(minutes) * 60));
+        if !capabilities.contains(UpsCapabilities::OUTLET_CONTROL) {
+            // Such UPSes turn their output back on with the mains
+            if restore_after != Some(Duration::ZERO) {
+                warn!("The UPS can't hold its output off, so it comes back with mains power");
+            }
+            restore_after = Some(Duration::ZERO);
+        }
+        match ups.schedule_shutdown(delay, restore_after).await {
+            Ok(()) => {
+                info!("UPS output will turn off in {}", format_duration(delay));
+                true
+            }
+            Err(error) => {
+                error!("Arming kill power failed with {:?}", error);
+                false
+            }
+        }
+    } else {
+        match ups.cancel_shutdown().await {
+            Ok(()) => {
+                info!("UPS output shutdown cancelled");
+                true
+            }
+            Err(error) => {
+                error!("Cancelling kill power failed with {:?}", error);
+                false
+            }
+        }
+    }
+}
+
+/// The model to estimate the battery charge with, when a charge threshold is
+/// configured. Without one, only the low battery flag of the UPS is acted on.
+fn battery_model(config: &RuntimeConfig, ratings: &UpsRatings) -> Option<BatteryModel> {
+    if config.low_battery_percent.is_none() {
+        return None;
+    }
+
+    let model = match config.battery_cells {
+        Some(cells) => BatteryModel::new(config.battery_chemistry, cells),
+        None => BatteryModel::from_ratings(ratings, config.battery_chemistry),
+    };
+    match model {
+        Ok(model) => {
+            debug!("Battery model: {:?}", model);
+            Some(model)
+        }
+        Err(error) => {
+            warn!("Can't estimate the battery charge: {}", error);
+            None
+        }
+    }
+}
+
+fn device_filter(config: &RuntimeConfig) -> DeviceFilter {
+    DeviceFilter {
+        vendor_id: Some(config.vendor_id),
+        product_id: Some(config.product_id),
+        usage_page: config.hid_usage_page,
+        usage_id: config.hid_usage_id,
+    }
+}
+
+async fn log_candidate_devices(config: &RuntimeConfig) {
+    match enumerate(&device_filter(config)).await {
+        Ok(devices) if devices.is_empty() => warn!("No matching HID devices found"),
+        Ok(devices) => {
+            for (ordinal, device) in devices.iter().enumerate() {
+                debug!("Found HID device #{}: {}", ordinal, device);
+            }
+        }
+        Err(error) => warn!("HID device enumeration failed: {}", error),
     }
 }
 
 async fn main_loop(
     config: &RuntimeConfig,
     rx: watch::Receiver<Option<UpsStatus>>,
+    kill_power: watch::Sender<bool>,
+    mut kill_power_ack: watch::Receiver<Option<bool>>,
 ) -> Result<(), Box<dyn Error>> {
     loop {
         wait_for_power_loss(rx.clone()).await?;
@@ -305,12 +508,14 @@ async fn main_loop(
                 () = sleep(shutdown_timeout) => {
                     info!("Timer elapsed, initiating shutdown...");
                     WAKEUP.reset()?;
+                    arm_kill_power(config, &kill_power, &mut kill_power_ack).await;
                     initiate_shutdown(config.hibernate)?;
                 }
-                result = wait_for_low_battery(rx.clone()) => {
+                result = wait_for_low_battery(rx.clone(), config.low_battery_percent) => {
                     result?;
                     warn!("Low battery detected, shutting down ahead of time...");
                     WAKEUP.reset()?;
+                    arm_kill_power(config, &kill_power, &mut kill_power_ack).await;
                     initiate_shutdown(config.hibernate)?;
                 }
                 result = wait_for_power_recovery(rx.clone()) => {
@@ -338,6 +543,38 @@ async fn main_loop(
                 }
             }
         }
+
+        // Still running, so the output has to stay on
+        if *kill_power.borrow() {
+            let _ignore = kill_power.send(false);
+        }
+    }
+}
+
+/// Arm the kill power, as the last thing before shutting down, and wait for
+/// the query task to get it to the UPS. The shutdown goes on either way, but
+/// not before the UPS had its chance.
+async fn arm_kill_power(
+    config: &RuntimeConfig,
+    kill_power: &watch::Sender<bool>,
+    kill_power_ack: &mut watch::Receiver<Option<bool>>,
+) {
+    if config.kill_power_delay_s.is_none() {
+        return;
+    }
+
+    // Only an acknowledgement of this request counts
+    kill_power_ack.borrow_and_update();
+    kill_power.send_replace(true);
+
+    match timeout(KILL_POWER_ACK_TIMEOUT, kill_power_ack.changed()).await {
+        Ok(Ok(())) if *kill_power_ack.borrow() == Some(true) => {}
+        Ok(Ok(())) => error!("Kill power was not armed, the UPS output stays on"),
+        Ok(Err(_)) => error!("Kill power was not armed, the UPS query task is gone"),
+        Err(_) => error!(
+            "Kill power was not armed within {}, the UPS output may stay on",
+            format_duration(KILL_POWER_ACK_TIMEOUT)
+        ),
     }
 }
 
@@ -364,9 +601,19 @@ async fn wait_for_power_recovery(
 
 async fn wait_for_low_battery(
     rx: watch::Receiver<Option<UpsStatus>>,
+    low_battery_percent: Option<u32>,
 ) -> Result<(), Box<dyn Error>> {
     wait_for_ups_status(rx, |status| {
-        status.flags.contains(UpsStatusFlags::BATTERY_LOW)
+        if status.flags.contains(UpsStatusFlags::BATTERY_LOW) {
+            return true;
+        }
+        match (status.battery_capacity, low_battery_percent) {
+            (Some(capacity), Some(threshold)) if capacity < threshold => {
+                warn!("Battery charge down to {}%", capacity);
+                true
+            }
+            _ => false,
+        }
     })
     .await
 }
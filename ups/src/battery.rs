@@ -0,0 +1,336 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    error::UpsError,
+    ups::{UpsRatings, UpsStatus, UpsWorkMode},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Chemistry {
+    /// Sealed lead-acid (VRLA/AGM), which nearly every UPS uses
+    #[default]
+    LeadAcid,
+    /// LiFePO4
+    LithiumIronPhosphate,
+}
+
+impl Chemistry {
+    pub fn nominal_cell_voltage(self) -> f32 {
+        match self {
+            Chemistry::LeadAcid => 2.0,
+            Chemistry::LithiumIronPhosphate => 3.2,
+        }
+    }
+
+    /// Cells in one nominally 12 V battery
+    fn cells_per_battery(self) -> u32 {
+        match self {
+            Chemistry::LeadAcid => 6,
+            Chemistry::LithiumIronPhosphate => 4,
+        }
+    }
+
+    /// Resting cell voltage against percent charge, by ascending voltage
+    fn curve(self) -> &'static [(f32, f32)] {
+        match self {
+            Chemistry::LeadAcid => &[
+                (1.750, 0.0),
+                (1.885, 10.0),
+                (1.930, 20.0),
+                (1.958, 30.0),
+                (1.983, 40.0),
+                (2.010, 50.0),
+                (2.033, 60.0),
+                (2.053, 70.0),
+                (2.070, 80.0),
+                (2.083, 90.0),
+                (2.117, 100.0),
+            ],
+            Chemistry::LithiumIronPhosphate => &[
+                (2.50, 0.0),
+                (3.00, 10.0),
+                (3.20, 20.0),
+                (3.22, 30.0),
+                (3.25, 40.0),
+                (3.26, 50.0),
+                (3.27, 60.0),
+                (3.30, 70.0),
+                (3.32, 80.0),
+                (3.35, 90.0),
+                (3.40, 100.0),
+            ],
+        }
+    }
+
+    /// Typical drop of the cell voltage when the UPS runs at full load
+    fn full_load_sag(self) -> f32 {
+        match self {
+            Chemistry::LeadAcid => 0.15,
+            Chemistry::LithiumIronPhosphate => 0.08,
+        }
+    }
+}
+
+impl fmt::Display for Chemistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chemistry::LeadAcid => write!(f, "lead-acid"),
+            Chemistry::LithiumIronPhosphate => write!(f, "lifepo4"),
+        }
+    }
+}
+
+impl FromStr for Chemistry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "lead-acid" | "vrla" | "agm" => Chemistry::LeadAcid,
+            "lifepo4" | "lfp" => Chemistry::LithiumIronPhosphate,
+            _ => bail!(UpsError::InvalidField {
+                field: "chemistry",
+                value: s.to_string(),
+                reason: "expected lead-acid or lifepo4".to_string(),
+            }),
+        })
+    }
+}
+
+/// What the battery voltage in a status stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VoltageReporting {
+    /// Tell from the voltage itself
+    #[default]
+    Auto,
+    /// The voltage of a single cell, as some Megatec UPSes report
+    PerCell,
+    /// The voltage of the whole battery string
+    Total,
+}
+
+/// Estimates the battery charge of UPSes that only report a voltage.
+///
+/// The estimate follows the resting voltage curve of the chemistry, after
+/// making up for the sag a load causes. It is only meaningful on battery:
+/// while charging, the voltage reads high and the estimate stays at 100%.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryModel {
+    chemistry: Chemistry,
+    cells: u32,
+    reporting: VoltageReporting,
+    full_load_sag: f32,
+}
+
+impl BatteryModel {
+    /// Model a string of `cells` cells in series
+    pub fn new(chemistry: Chemistry, cells: u32) -> Result<Self> {
+        if cells == 0 {
+            bail!(UpsError::InvalidField {
+                field: "cells",
+                value: cells.to_string(),
+                reason: "a battery has at least one cell".to_string(),
+            });
+        }
+
+        Ok(Self {
+            chemistry,
+            cells,
+            reporting: VoltageReporting::Auto,
+            full_load_sag: chemistry.full_load_sag(),
+        })
+    }
+
+    /// Model the battery described by the nominal ratings, going by its
+    /// voltage, or failing that by how many batteries there are
+    pub fn from_ratings(ratings: &UpsRatings, chemistry: Chemistry) -> Result<Self> {
+        let cells = match (ratings.battery_voltage, ratings.battery_count) {
+            (Some(voltage), _) if voltage > 0.0 => {
+                (voltage / chemistry.nominal_cell_voltage()).round() as u32
+            }
+            (_, Some(count)) => count * chemistry.cells_per_battery(),
+            _ => bail!(UpsError::Unsupported(
+                "The UPS ratings have neither the battery voltage nor count".to_string()
+            )),
+        };
+
+        Self::new(chemistry, cells)
+    }
+
+    pub fn with_reporting(mut self, reporting: VoltageReporting) -> Self {
+        self.reporting = reporting;
+        self
+    }
+
+    /// Override how far the cell voltage drops at full load
+    pub fn with_full_load_sag(mut self, volts_per_cell: f32) -> Self {
+        self.full_load_sag = volts_per_cell;
+        self
+    }
+
+    pub fn chemistry(&self) -> Chemistry {
+        self.chemistry
+    }
+
+    pub fn cells(&self) -> u32 {
+        self.cells
+    }
+
+    pub fn nominal_voltage(&self) -> f32 {
+        self.cells as f32 * self.chemistry.nominal_cell_voltage()
+    }
+
+    /// The charge in percent, going by the battery voltage and load of
+    /// `status`. `None` if the status has no usable battery voltage.
+    pub fn charge(&self, status: &UpsStatus) -> Option<f32> {
+        // Only a discharging battery sags under the load
+        let load = match status.work_mode() {
+            UpsWorkMode::Battery | UpsWorkMode::BatteryTest => {
+                status.output_load_level as f32 / 100.0
+            }
+            _ => 0.0,
+        };
+
+        self.charge_at(status.battery_voltage, load)
+    }
+
+    /// The charge in percent at `voltage`, while supplying `load` as a
+    /// fraction of the full load
+    pub fn charge_at(&self, voltage: f32, load: f32) -> Option<f32> {
+        if !voltage.is_finite() || voltage <= 0.0 {
+            return None;
+        }
+
+        let cell_voltage = self.cell_voltage(voltage) + self.full_load_sag * load.max(0.0);
+        Some(interpolate(self.chemistry.curve(), cell_voltage))
+    }
+
+    fn cell_voltage(&self, voltage: f32) -> f32 {
+        let per_cell = match self.reporting {
+            VoltageReporting::PerCell => true,
+            VoltageReporting::Total => false,
+            // Split the difference, on a log scale, between the nominal
+            // voltage of a cell and of the string. Neither a charging cell
+            // nor a flat string gets anywhere near that.
+            VoltageReporting::Auto => {
+                voltage < self.chemistry.nominal_cell_voltage() * (self.cells as f32).sqrt()
+            }
+        };
+
+        if per_cell {
+            voltage
+        } else {
+            voltage / self.cells as f32
+        }
+    }
+}
+
+fn interpolate(curve: &[(f32, f32)], voltage: f32) -> f32 {
+    let (first_voltage, first_charge) = curve[0];
+    if voltage <= first_voltage {
+        return first_charge;
+    }
+
+    for window in curve.windows(2) {
+        let ((low_voltage, low_charge), (high_voltage, high_charge)) = (window[0], window[1]);
+        if voltage <= high_voltage {
+            let position = (voltage - low_voltage) / (high_voltage - low_voltage);
+            return low_charge + position * (high_charge - low_charge);
+        }
+    }
+
+    curve[curve.len() - 1].1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ups::UpsStatusFlags;
+
+    fn assert_close(actual: Option<f32>, expected: f32) {
+        let actual = actual.unwrap();
+        assert!(
+            (actual - expected).abs() < 0.5,
+            "{} is not about {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn charge_follows_the_curve() {
+        let model = BatteryModel::new(Chemistry::LeadAcid, 12).unwrap();
+
+        assert_close(model.charge_at(24.12, 0.0), 50.0);
+        assert_close(model.charge_at(23.796, 0.0), 40.0);
+        assert_close(model.charge_at(20.0, 0.0), 0.0);
+        assert_close(model.charge_at(27.5, 0.0), 100.0);
+        assert_eq!(model.charge_at(f32::NAN, 0.0), None);
+    }
+
+    #[test]
+    fn per_cell_voltage_is_recognized() {
+        let model = BatteryModel::new(Chemistry::LeadAcid, 12).unwrap();
+
+        assert_eq!(model.charge_at(2.01, 0.0), model.charge_at(24.12, 0.0));
+        assert_close(
+            model
+                .with_reporting(VoltageReporting::Total)
+                .charge_at(2.01, 0.0),
+            0.0,
+        );
+    }
+
+    #[test]
+    fn load_sag_is_only_made_up_for_on_battery() {
+        let model = BatteryModel::new(Chemistry::LeadAcid, 6).unwrap();
+        let mut status = UpsStatus {
+            battery_voltage: 11.55,
+            output_load_level: 60,
+            ..Default::default()
+        };
+
+        // 1.925 V per cell at rest, 2.015 V with 60% of 0.15 V made up
+        assert_close(model.charge(&status), 18.9);
+        status.flags = UpsStatusFlags::UTILITY_FAIL;
+        assert_close(model.charge(&status), 52.2);
+    }
+
+    #[test]
+    fn from_ratings_counts_cells() {
+        let ratings = UpsRatings {
+            battery_voltage: Some(24.0),
+            ..Default::default()
+        };
+        let model = BatteryModel::from_ratings(&ratings, Chemistry::LeadAcid).unwrap();
+        assert_eq!(model.cells(), 12);
+        assert_eq!(model.nominal_voltage(), 24.0);
+
+        let ratings = UpsRatings {
+            battery_count: Some(2),
+            ..Default::default()
+        };
+        let model = BatteryModel::from_ratings(&ratings, Chemistry::LithiumIronPhosphate).unwrap();
+        assert_eq!(model.cells(), 8);
+
+        let error =
+            BatteryModel::from_ratings(&UpsRatings::default(), Chemistry::LeadAcid).unwrap_err();
+        assert!(matches!(
+            UpsError::of(&error),
+            Some(UpsError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn chemistry_round_trips() {
+        for chemistry in [Chemistry::LeadAcid, Chemistry::LithiumIronPhosphate] {
+            assert_eq!(
+                chemistry.to_string().parse::<Chemistry>().unwrap(),
+                chemistry
+            );
+        }
+        assert_eq!("AGM".parse::<Chemistry>().unwrap(), Chemistry::LeadAcid);
+        assert!("NiMH".parse::<Chemistry>().is_err());
+    }
+}
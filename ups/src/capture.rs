@@ -0,0 +1,800 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+
+use crate::{command, error::UpsError, transport::Transport, ups::UpsStatusFields};
+
+const HEADER: &str = "# unlimited_power capture v1";
+
+/// A [`Transport`] wrapper that logs all traffic to a capture.
+///
+/// A capture is a UTF-8 text file with one record per line. The first line is
+/// the header `# unlimited_power capture v1`; other lines starting with `#`
+/// are comments. Every record starts with the number of milliseconds since
+/// recording began, followed by the record kind and its fields, separated by
+/// single spaces. Binary fields are lowercase hex, with `-` standing for an
+/// empty field.
+///
+/// | Record                              | Meaning                               |
+/// |-------------------------------------|---------------------------------------|
+/// | `<ms> OUT <report ID> <data>`       | Output report sent                    |
+/// | `<ms> IN <report ID> <data>`        | Input report received                 |
+/// | `<ms> STR <index> <data>`           | Indexed string fetched, UTF-8 in hex  |
+/// | `<ms> FGET <report ID> <data>`      | Feature report read                   |
+/// | `<ms> FSET <report ID> <data>`      | Feature report written                |
+/// | `<ms> DESC <data>`                  | Report descriptor fetched             |
+/// | `<ms> ERR <kind> <error> <details>` | Request failed                        |
+/// | `<ms> CANCEL <kind>`                | Request abandoned, e.g. on timeout    |
+///
+/// `<kind>` is the kind of the failed request, like `IN`. `<error>` is the
+/// [`UpsError`] variant the request failed with, so that replaying raises the
+/// same one, or `-` for other errors. The `<details>` are:
+///
+/// | `<error>`                                | `<details>`                        |
+/// |------------------------------------------|------------------------------------|
+/// | `-`                                      | Error message, free text           |
+/// | `Timeout`, `Disconnected`, `Framing`,    | The variant's message, free text   |
+/// | `Unsupported`, `Nak`, `DeviceNotFound`,  |                                    |
+/// | `Ambiguous`                              |                                    |
+/// | `ChecksumMismatch`                       | `<expected> <actual>`, in hex      |
+/// | `UnexpectedReportId`                     | `<expected> <actual>`, in hex      |
+/// | `InvalidField`                           | `<field> <value> <reason>`, with   |
+/// |                                          | the value hex and the reason text  |
+///
+/// For example, a Voltronic `M` query answered with `V`:
+///
+/// ```text
+/// # unlimited_power capture v1
+/// # started 1700000000
+/// 0 OUT 00 4d0d
+/// 3 IN 00 560d000000000000
+/// ```
+#[derive(Debug)]
+pub struct RecordingTransport<T, W> {
+    inner: T,
+    sink: Mutex<W>,
+    start: Instant,
+}
+
+impl<T: Transport> RecordingTransport<T, File> {
+    /// Record into a new file at `path`, replacing any existing one
+    pub fn create(inner: T, path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(inner, File::create(path)?)
+    }
+}
+
+impl<T: Transport, W: Write + Send> RecordingTransport<T, W> {
+    pub fn new(inner: T, mut sink: W) -> Result<Self> {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        writeln!(sink, "{}", HEADER)?;
+        writeln!(sink, "# started {}", started)?;
+        sink.flush()?;
+
+        Ok(Self {
+            inner,
+            sink: Mutex::new(sink),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn into_inner(self) -> (T, W) {
+        (self.inner, self.sink.into_inner().unwrap())
+    }
+
+    fn record(&self, record: &str) -> Result<()> {
+        let elapsed = self.start.elapsed().as_millis();
+
+        let mut sink = self.sink.lock().unwrap();
+        writeln!(sink, "{} {}", elapsed, record)?;
+        sink.flush()?;
+
+        Ok(())
+    }
+
+    fn record_result<R>(
+        &self,
+        kind: RequestKind,
+        result: Result<R>,
+        format: impl FnOnce(&R) -> String,
+    ) -> Result<R> {
+        match result {
+            Ok(value) => {
+                self.record(&format!("{} {}", kind.as_str(), format(&value)))?;
+                Ok(value)
+            }
+            Err(error) => {
+                self.record(&format!("ERR {} {}", kind.as_str(), format_error(&error)))?;
+                Err(error)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Transport, W: Write + Send> Transport for RecordingTransport<T, W> {
+    async fn send_output_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        let guard = CancelGuard::new(self, RequestKind::Output);
+        let result = self.inner.send_output_report(report_id, data).await;
+        guard.complete();
+
+        self.record_result(RequestKind::Output, result, |_| {
+            format!("{:02x} {}", report_id, to_hex(data))
+        })
+    }
+
+    async fn read_input_report(&self) -> Result<(u8, Vec<u8>)> {
+        let guard = CancelGuard::new(self, RequestKind::Input);
+        let result = self.inner.read_input_report().await;
+        guard.complete();
+
+        self.record_result(RequestKind::Input, result, |(report_id, data)| {
+            format!("{:02x} {}", report_id, to_hex(data))
+        })
+    }
+
+    async fn get_indexed_string(&self, index: u32) -> Result<String> {
+        let guard = CancelGuard::new(self, RequestKind::IndexedString);
+        let result = self.inner.get_indexed_string(index).await;
+        guard.complete();
+
+        self.record_result(RequestKind::IndexedString, result, |string| {
+            format!("{} {}", index, to_hex(string.as_bytes()))
+        })
+    }
+
+    async fn get_feature_report(&self, report_id: u8) -> Result<Vec<u8>> {
+        let guard = CancelGuard::new(self, RequestKind::GetFeature);
+        let result = self.inner.get_feature_report(report_id).await;
+        guard.complete();
+
+        self.record_result(RequestKind::GetFeature, result, |data| {
+            format!("{:02x} {}", report_id, to_hex(data))
+        })
+    }
+
+    async fn send_feature_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        let guard = CancelGuard::new(self, RequestKind::SendFeature);
+        let result = self.inner.send_feature_report(report_id, data).await;
+        guard.complete();
+
+        self.record_result(RequestKind::SendFeature, result, |_| {
+            format!("{:02x} {}", report_id, to_hex(data))
+        })
+    }
+
+    async fn report_descriptor(&self) -> Result<Vec<u8>> {
+        let guard = CancelGuard::new(self, RequestKind::ReportDescriptor);
+        let result = self.inner.report_descriptor().await;
+        guard.complete();
+
+        self.record_result(RequestKind::ReportDescriptor, result, |data| to_hex(data))
+    }
+
+    fn connection_id(&self) -> u64 {
+        self.inner.connection_id()
+    }
+}
+
+/// Records a `CANCEL` if the request future is dropped before it completes
+struct CancelGuard<'a, T: Transport, W: Write + Send> {
+    transport: &'a RecordingTransport<T, W>,
+    kind: RequestKind,
+    completed: bool,
+}
+
+impl<'a, T: Transport, W: Write + Send> CancelGuard<'a, T, W> {
+    fn new(transport: &'a RecordingTransport<T, W>, kind: RequestKind) -> Self {
+        Self {
+            transport,
+            kind,
+            completed: false,
+        }
+    }
+
+    fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl<T: Transport, W: Write + Send> Drop for CancelGuard<'_, T, W> {
+    fn drop(&mut self) {
+        if !self.completed {
+            // Nowhere to report a failure from here
+            let _ = self
+                .transport
+                .record(&format!("CANCEL {}", self.kind.as_str()));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RequestKind {
+    Output,
+    Input,
+    IndexedString,
+    GetFeature,
+    SendFeature,
+    ReportDescriptor,
+}
+
+impl RequestKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            RequestKind::Output => "OUT",
+            RequestKind::Input => "IN",
+            RequestKind::IndexedString => "STR",
+            RequestKind::GetFeature => "FGET",
+            RequestKind::SendFeature => "FSET",
+            RequestKind::ReportDescriptor => "DESC",
+        }
+    }
+
+    fn parse(string: &str) -> Result<Self> {
+        Ok(match string {
+            "OUT" => RequestKind::Output,
+            "IN" => RequestKind::Input,
+            "STR" => RequestKind::IndexedString,
+            "FGET" => RequestKind::GetFeature,
+            "FSET" => RequestKind::SendFeature,
+            "DESC" => RequestKind::ReportDescriptor,
+            _ => bail!("Unknown request kind {:?}", string),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Record {
+    Output { report_id: u8, data: Vec<u8> },
+    Input { report_id: u8, data: Vec<u8> },
+    IndexedString { index: u32, string: String },
+    GetFeature { report_id: u8, data: Vec<u8> },
+    SendFeature { report_id: u8, data: Vec<u8> },
+    ReportDescriptor { data: Vec<u8> },
+    Error { kind: RequestKind, failure: Failure },
+    Cancel { kind: RequestKind },
+}
+
+/// How a recorded request failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Failure {
+    Typed(UpsError),
+    Untyped(String),
+}
+
+/// A [`Transport`] that plays a capture made by [`RecordingTransport`] back to
+/// a driver.
+///
+/// Requests must arrive in the order they were recorded. Output and feature
+/// reports sent, and the IDs and indices asked for, are checked against the
+/// capture, and any divergence is an error. Recorded failures are replayed as
+/// the same errors, and cancelled requests never complete, so drivers hit the
+/// same timeouts they did originally.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    records: Mutex<VecDeque<(usize, Record)>>,
+}
+
+impl ReplayTransport {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_reader(File::open(path)?)
+    }
+
+    pub fn from_reader(reader: impl Read) -> Result<Self> {
+        let mut lines = BufReader::new(reader).lines().enumerate();
+
+        let header = lines.next().map(|(_, line)| line).transpose()?;
+        if header.as_deref() != Some(HEADER) {
+            bail!("Not an unlimited_power capture");
+        }
+
+        let mut records = VecDeque::new();
+        for (index, line) in lines {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let line_number = index + 1;
+            let record = Self::parse_record(&line)
+                .with_context(|| format!("Malformed capture record on line {}", line_number))?;
+            records.push_back((line_number, record));
+        }
+
+        Ok(Self {
+            records: Mutex::new(records),
+        })
+    }
+
+    /// Whether every record in the capture has been replayed
+    pub fn is_done(&self) -> bool {
+        self.records.lock().unwrap().is_empty()
+    }
+
+    fn parse_record(line: &str) -> Result<Record> {
+        let mut fields = line.splitn(5, ' ');
+        let mut next_field = || fields.next().ok_or_else(|| anyhow!("Missing field"));
+
+        let _elapsed_ms: u64 = next_field()?.parse()?;
+        let kind = next_field()?;
+
+        Ok(match kind {
+            "OUT" | "IN" | "FGET" | "FSET" => {
+                let report_id = u8::from_str_radix(next_field()?, 16)?;
+                let data = from_hex(next_field()?)?;
+                match kind {
+                    "OUT" => Record::Output { report_id, data },
+                    "IN" => Record::Input { report_id, data },
+                    "FGET" => Record::GetFeature { report_id, data },
+                    _ => Record::SendFeature { report_id, data },
+                }
+            }
+            "DESC" => Record::ReportDescriptor {
+                data: from_hex(next_field()?)?,
+            },
+            "STR" => Record::IndexedString {
+                index: next_field()?.parse()?,
+                string: String::from_utf8(from_hex(next_field()?)?)?,
+            },
+            "ERR" => {
+                let kind = RequestKind::parse(next_field()?)?;
+                let error = next_field()?;
+                let details = fields.next().unwrap_or_default();
+                Record::Error {
+                    kind,
+                    failure: parse_failure(error, details)?,
+                }
+            }
+            "CANCEL" => Record::Cancel {
+                kind: RequestKind::parse(next_field()?)?,
+            },
+            _ => bail!("Unknown record kind {:?}", kind),
+        })
+    }
+
+    /// Pops the next record, which must be a response to a `kind` request
+    fn next_record(&self, kind: RequestKind) -> Result<(usize, Record)> {
+        let mut records = self.records.lock().unwrap();
+
+        let (line, record) = records.pop_front().ok_or_else(|| {
+            anyhow!(
+                "Capture exhausted, got an unexpected {} request",
+                kind.as_str()
+            )
+        })?;
+
+        let record_kind = match &record {
+            Record::Output { .. } => RequestKind::Output,
+            Record::Input { .. } => RequestKind::Input,
+            Record::IndexedString { .. } => RequestKind::IndexedString,
+            Record::GetFeature { .. } => RequestKind::GetFeature,
+            Record::SendFeature { .. } => RequestKind::SendFeature,
+            Record::ReportDescriptor { .. } => RequestKind::ReportDescriptor,
+            Record::Error { kind, .. } | Record::Cancel { kind } => *kind,
+        };
+        if record_kind != kind {
+            let diverged = anyhow!(
+                "Replay diverged on line {}: got a {} request, capture has {:?}",
+                line,
+                kind.as_str(),
+                record
+            );
+            records.push_front((line, record));
+            return Err(diverged);
+        }
+
+        Ok((line, record))
+    }
+
+    async fn replay_failure<R>(line: usize, record: Record) -> Result<R> {
+        match record {
+            Record::Error {
+                failure: Failure::Typed(error),
+                ..
+            } => Err(error.into()),
+            Record::Error {
+                failure: Failure::Untyped(message),
+                ..
+            } => Err(anyhow!(message)),
+            Record::Cancel { .. } => std::future::pending().await,
+            record => unreachable!("Not a failure record on line {}: {:?}", line, record),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for ReplayTransport {
+    async fn send_output_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        match self.next_record(RequestKind::Output)? {
+            (
+                line,
+                Record::Output {
+                    report_id: recorded_report_id,
+                    data: recorded_data,
+                },
+            ) => {
+                if recorded_report_id != report_id || recorded_data != data {
+                    bail!(
+                        "Replay diverged on line {}: sent {:02x} {}, capture has {:02x} {}",
+                        line,
+                        report_id,
+                        to_hex(data),
+                        recorded_report_id,
+                        to_hex(&recorded_data)
+                    );
+                }
+                Ok(())
+            }
+            (line, record) => Self::replay_failure(line, record).await,
+        }
+    }
+
+    async fn read_input_report(&self) -> Result<(u8, Vec<u8>)> {
+        match self.next_record(RequestKind::Input)? {
+            (_, Record::Input { report_id, data }) => Ok((report_id, data)),
+            (line, record) => Self::replay_failure(line, record).await,
+        }
+    }
+
+    async fn get_indexed_string(&self, index: u32) -> Result<String> {
+        match self.next_record(RequestKind::IndexedString)? {
+            (
+                line,
+                Record::IndexedString {
+                    index: recorded_index,
+                    string,
+                },
+            ) => {
+                if recorded_index != index {
+                    bail!(
+                        "Replay diverged on line {}: requested string {}, capture has {}",
+                        line,
+                        index,
+                        recorded_index
+                    );
+                }
+                Ok(string)
+            }
+            (line, record) => Self::replay_failure(line, record).await,
+        }
+    }
+
+    async fn get_feature_report(&self, report_id: u8) -> Result<Vec<u8>> {
+        match self.next_record(RequestKind::GetFeature)? {
+            (
+                line,
+                Record::GetFeature {
+                    report_id: recorded_report_id,
+                    data,
+                },
+            ) => {
+                if recorded_report_id != report_id {
+                    bail!(
+                        "Replay diverged on line {}: requested feature report {:02x}, capture has {:02x}",
+                        line,
+                        report_id,
+                        recorded_report_id
+                    );
+                }
+                Ok(data)
+            }
+            (line, record) => Self::replay_failure(line, record).await,
+        }
+    }
+
+    async fn send_feature_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        match self.next_record(RequestKind::SendFeature)? {
+            (
+                line,
+                Record::SendFeature {
+                    report_id: recorded_report_id,
+                    data: recorded_data,
+                },
+            ) => {
+                if recorded_report_id != report_id || recorded_data != data {
+                    bail!(
+                        "Replay diverged on line {}: sent feature {:02x} {}, capture has {:02x} {}",
+                        line,
+                        report_id,
+                        to_hex(data),
+                        recorded_report_id,
+                        to_hex(&recorded_data)
+                    );
+                }
+                Ok(())
+            }
+            (line, record) => Self::replay_failure(line, record).await,
+        }
+    }
+
+    async fn report_descriptor(&self) -> Result<Vec<u8>> {
+        match self.next_record(RequestKind::ReportDescriptor)? {
+            (_, Record::ReportDescriptor { data }) => Ok(data),
+            (line, record) => Self::replay_failure(line, record).await,
+        }
+    }
+}
+
+/// The `<error> <details>` fields of an `ERR` record
+fn format_error(error: &anyhow::Error) -> String {
+    let text = |message: &str| message.replace(['\r', '\n'], " ");
+
+    match UpsError::of(error) {
+        None => format!("- {}", text(&format!("{:#}", error))),
+        Some(UpsError::Timeout(message)) => format!("Timeout {}", text(message)),
+        Some(UpsError::Disconnected(message)) => format!("Disconnected {}", text(message)),
+        Some(UpsError::Framing(message)) => format!("Framing {}", text(message)),
+        Some(UpsError::ChecksumMismatch { expected, actual }) => {
+            format!("ChecksumMismatch {:04x} {:04x}", expected, actual)
+        }
+        Some(UpsError::InvalidField {
+            field,
+            value,
+            reason,
+        }) => format!(
+            "InvalidField {} {} {}",
+            field,
+            to_hex(value.as_bytes()),
+            text(reason)
+        ),
+        Some(UpsError::UnexpectedReportId { expected, actual }) => {
+            format!("UnexpectedReportId {:02x} {:02x}", expected, actual)
+        }
+        Some(UpsError::Unsupported(message)) => format!("Unsupported {}", text(message)),
+        Some(UpsError::Nak(command)) => format!("Nak {}", text(command)),
+        Some(UpsError::DeviceNotFound(message)) => format!("DeviceNotFound {}", text(message)),
+        Some(UpsError::Ambiguous(message)) => format!("Ambiguous {}", text(message)),
+    }
+}
+
+/// Rebuild the failure of an `ERR` record from its `<error>` and `<details>`
+fn parse_failure(error: &str, details: &str) -> Result<Failure> {
+    let mut fields = details.splitn(3, ' ');
+    let mut next_field = || fields.next().ok_or_else(|| anyhow!("Missing error field"));
+
+    let message = details.to_string();
+    Ok(Failure::Typed(match error {
+        "-" => return Ok(Failure::Untyped(message)),
+        "Timeout" => UpsError::Timeout(message),
+        "Disconnected" => UpsError::Disconnected(message),
+        "Framing" => UpsError::Framing(message),
+        "ChecksumMismatch" => UpsError::ChecksumMismatch {
+            expected: u16::from_str_radix(next_field()?, 16)?,
+            actual: u16::from_str_radix(next_field()?, 16)?,
+        },
+        "InvalidField" => {
+            let field = next_field()?;
+            let field = (0..8)
+                .map(|bit| UpsStatusFields::from_bits_truncate(1 << bit).name())
+                .chain(command::RESPONSE_FIELDS.iter().copied())
+                .find(|name| *name == field)
+                .ok_or_else(|| anyhow!("Unknown field {:?}", field))?;
+            UpsError::InvalidField {
+                field,
+                value: String::from_utf8(from_hex(next_field()?)?)?,
+                reason: next_field().unwrap_or_default().to_string(),
+            }
+        }
+        "UnexpectedReportId" => UpsError::UnexpectedReportId {
+            expected: u8::from_str_radix(next_field()?, 16)?,
+            actual: u8::from_str_radix(next_field()?, 16)?,
+        },
+        "Unsupported" => UpsError::Unsupported(message),
+        "Nak" => UpsError::Nak(message),
+        "DeviceNotFound" => UpsError::DeviceNotFound(message),
+        "Ambiguous" => UpsError::Ambiguous(message),
+        _ => bail!("Unknown error kind {:?}", error),
+    }))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "-".to_string();
+    }
+
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(string: &str) -> Result<Vec<u8>> {
+    if string == "-" {
+        return Ok(Vec::new());
+    }
+
+    if !string.len().is_multiple_of(2) {
+        bail!("Odd number of hex digits");
+    }
+
+    (0..string.len())
+        .step_by(2)
+        .map(|index| Ok(u8::from_str_radix(&string[index..index + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        megatec_hid_ups::MegatecHidUps, mock_transport::MockTransport,
+        stream_transport::StreamTransport, ups::Ups, voltronic_hid_ups::VoltronicHidUps,
+    };
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn voltronic_session_round_trips() {
+        let buffer = SharedBuffer::default();
+        let mock = MockTransport::new()
+            .expect_command("M", "V")
+            .expect_command("QS", "(215.0 195.0 230.0 014 49.0 27.5 30.0 00001001");
+        let recorded = VoltronicHidUps::new(RecordingTransport::new(mock, buffer.clone()).unwrap())
+            .unwrap()
+            .status()
+            .await
+            .unwrap();
+
+        let replay = Arc::new(ReplayTransport::from_reader(&buffer.contents()[..]).unwrap());
+        let replayed = VoltronicHidUps::new(replay.clone())
+            .unwrap()
+            .status()
+            .await
+            .unwrap();
+
+        assert!(replay.is_done());
+        assert_eq!(replayed.output_voltage, recorded.output_voltage);
+        assert_eq!(replayed.flags, recorded.flags);
+    }
+
+    #[tokio::test]
+    async fn replays_handwritten_megatec_capture() {
+        let capture = format!(
+            "{}\n0 STR 3 {}\n12 ERR STR - Device went away\n",
+            HEADER,
+            to_hex(b"(208.4 140.0 208.4 034 59.9 2.05 35.0 00110000\r")
+        );
+
+        let ups =
+            MegatecHidUps::new(ReplayTransport::from_reader(capture.as_bytes()).unwrap()).unwrap();
+
+        assert_eq!(ups.status().await.unwrap().output_load_level, 34);
+        let error = ups.status().await.unwrap_err();
+        assert_eq!(error.to_string(), "Device went away");
+    }
+
+    #[tokio::test]
+    async fn error_kinds_are_recorded() {
+        let buffer = SharedBuffer::default();
+        let (stream, _peer) = tokio::io::duplex(64);
+        let recording =
+            RecordingTransport::new(StreamTransport::new(stream), buffer.clone()).unwrap();
+        let error = recording.get_indexed_string(3).await.unwrap_err();
+
+        let capture = String::from_utf8(buffer.contents()).unwrap();
+        assert!(capture
+            .ends_with(" ERR STR Unsupported Indexed strings are not supported over streams\n"));
+
+        let replay = ReplayTransport::from_reader(capture.as_bytes()).unwrap();
+        let replayed = replay.get_indexed_string(3).await.unwrap_err();
+        assert_eq!(UpsError::of(&replayed), UpsError::of(&error));
+    }
+
+    #[tokio::test]
+    async fn error_kinds_round_trip() {
+        let errors = [
+            UpsError::Timeout("Receiving response".to_string()),
+            UpsError::Disconnected("hidraw device was disconnected".to_string()),
+            UpsError::Framing("Malformed response".to_string()),
+            UpsError::ChecksumMismatch {
+                expected: 0x1234,
+                actual: 0xabcd,
+            },
+            UpsError::InvalidField {
+                field: "output_voltage",
+                value: "2 0".to_string(),
+                reason: "invalid float literal".to_string(),
+            },
+            UpsError::InvalidField {
+                field: "phases",
+                value: "4/1".to_string(),
+                reason: "expected 1 to 3 input and output phases".to_string(),
+            },
+            UpsError::UnexpectedReportId {
+                expected: 0,
+                actual: 1,
+            },
+            UpsError::Unsupported("No indexed strings".to_string()),
+            UpsError::Nak("PEa".to_string()),
+            UpsError::DeviceNotFound("".to_string()),
+            UpsError::Ambiguous("2 devices match".to_string()),
+        ];
+
+        let mut capture = format!("{}\n", HEADER);
+        for error in &errors {
+            capture += &format!("0 ERR STR {}\n", format_error(&error.clone().into()));
+        }
+
+        let replay = ReplayTransport::from_reader(capture.as_bytes()).unwrap();
+        for error in errors {
+            let replayed = replay.get_indexed_string(3).await.unwrap_err();
+            assert_eq!(UpsError::of(&replayed), Some(&error));
+        }
+        assert!(replay.is_done());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_are_recorded_and_replayed() {
+        let buffer = SharedBuffer::default();
+        let mock = MockTransport::new().expect_command_unanswered("M");
+        let ups =
+            VoltronicHidUps::new(RecordingTransport::new(mock, buffer.clone()).unwrap()).unwrap();
+        assert!(ups.protocol().await.is_err());
+
+        let capture = String::from_utf8(buffer.contents()).unwrap();
+        assert!(capture.lines().last().unwrap().ends_with(" CANCEL IN"));
+
+        let replay = ReplayTransport::from_reader(capture.as_bytes()).unwrap();
+        assert!(VoltronicHidUps::new(replay)
+            .unwrap()
+            .protocol()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn feature_reports_round_trip() {
+        let descriptor = [0x05, 0x84, 0x09, 0x04, 0xA1, 0x01, 0xC0];
+        let buffer = SharedBuffer::default();
+        let mock = MockTransport::new()
+            .with_report_descriptor(&descriptor)
+            .expect_get_feature(3, &[0x02])
+            .expect_send_feature(3, &[0x01]);
+        let recording = RecordingTransport::new(mock, buffer.clone()).unwrap();
+        assert_eq!(recording.report_descriptor().await.unwrap(), descriptor);
+        assert_eq!(recording.get_feature_report(3).await.unwrap(), [0x02]);
+        recording.send_feature_report(3, &[0x01]).await.unwrap();
+
+        let capture = String::from_utf8(buffer.contents()).unwrap();
+        assert!(capture.contains(" DESC 05840904a101c0\n"));
+        assert!(capture.contains(" FGET 03 02\n"));
+        assert!(capture.contains(" FSET 03 01\n"));
+
+        let replay = ReplayTransport::from_reader(capture.as_bytes()).unwrap();
+        assert_eq!(replay.report_descriptor().await.unwrap(), descriptor);
+        assert_eq!(replay.get_feature_report(3).await.unwrap(), [0x02]);
+        assert!(replay.send_feature_report(3, &[0x02]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn divergence_is_an_error() {
+        let capture = format!("{}\n0 OUT 00 4d0d\n", HEADER);
+        let replay = ReplayTransport::from_reader(capture.as_bytes()).unwrap();
+
+        assert!(replay.send_output_report(0, b"QS\r").await.is_err());
+        assert!(replay.get_indexed_string(3).await.is_err());
+    }
+}
@@ -0,0 +1,1135 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    error::UpsError,
+    framing::{self, CrcPolicy},
+    transport::Transport,
+    ups::{
+        FieldParser, ParseMode, SelfTest, UpsInfo, UpsRatings, UpsStatus, UpsStatusFields,
+        UpsStatusFlags,
+    },
+    voltronic_hid_ups::UpsProtocol,
+};
+
+/// A command of the Megatec and Voltronic protocols.
+///
+/// Each command knows its wire form, which [`Display`](fmt::Display) writes
+/// and [`FromStr`] reads back, and how to parse its response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Command {
+    /// `M`: which Voltronic protocol the UPS speaks
+    Protocol,
+    /// `QS`: status, Voltronic V protocol
+    Status,
+    /// `Q1`: status, Megatec
+    MegatecStatus,
+    /// `Q`: toggle the beeper, Voltronic V protocol and Megatec
+    BeeperToggle,
+    /// `I`: manufacturer, model and firmware, Voltronic V protocol and Megatec
+    Info,
+    /// `F`: ratings, Voltronic V protocol and Megatec
+    Ratings,
+    /// `T`: quick battery test, of about 10 seconds
+    SelfTest,
+    /// `TL`: battery test until the battery runs low
+    SelfTestUntilBatteryLow,
+    /// `T<n>`: battery test for 1 to 99 minutes
+    TimedSelfTest(u8),
+    /// `CT`: cancel a running battery test
+    CancelSelfTest,
+    /// `S<n>R<m>`: turn the output off after `delay` tenths of a minute, and
+    /// back on `restore` minutes later if mains power is there. Without
+    /// `restore` this is `S<n>R0000`, and the output stays off. A `restore`
+    /// of 0 is the bare `S<n>`, which turns the output back on as soon as
+    /// mains power returns.
+    Shutdown { delay: u8, restore: Option<u16> },
+    /// `C`: cancel a pending shutdown
+    CancelShutdown,
+    /// `QPI`: protocol ID, Voltronic P and T protocols
+    ProtocolId,
+    /// `QGS`: general status, Voltronic P and T protocols
+    GeneralStatus,
+    /// `QMOD`: work mode, Voltronic P and T protocols
+    Mode,
+    /// `QWS`: warnings, Voltronic P and T protocols
+    Warnings,
+    /// `QMD`: model information, Voltronic P and T protocols
+    Model,
+    /// `QRI`: ratings, Voltronic P and T protocols
+    RatedInformation,
+    /// `QID`: serial number, Voltronic P and T protocols
+    SerialNumber,
+    /// `QVFW`: firmware version, Voltronic P and T protocols
+    FirmwareVersion,
+    /// `Q3PV`: per-phase input voltages, three-phase T protocol UPSes
+    PhaseInputVoltages,
+    /// `Q3OV`: per-phase output voltages, three-phase T protocol UPSes
+    PhaseOutputVoltages,
+    /// `Q3LD`: per-phase load, three-phase T protocol UPSes
+    PhaseLoads,
+    /// `PE<flag>`: set one of the lowercase setting flags
+    EnableFlag(char),
+    /// `PD<flag>`: clear one of the lowercase setting flags
+    DisableFlag(char),
+}
+
+/// A parsed response, of the kind the [`Command`] that got it calls for
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The command has no response worth parsing
+    None,
+    /// `(ACK`
+    Ack,
+    Protocol(UpsProtocol),
+    Status(UpsStatus),
+    /// The number in the `(PI<nn>` protocol ID
+    ProtocolId(u32),
+    Mode(VoltronicMode),
+    Warnings(VoltronicWarnings),
+    Model(ModelInfo),
+    Info(UpsInfo),
+    Ratings(UpsRatings),
+    /// A single string value, like the serial number
+    Text(String),
+    /// The three phase-to-neutral values of a `Q3..` command
+    PhaseValues([f32; 3]),
+}
+
+impl Command {
+    /// Every command without parameters, for listing what is supported.
+    /// Includes commands that change the state of the UPS.
+    pub const ALL: [Command; 21] = [
+        Command::Protocol,
+        Command::Status,
+        Command::MegatecStatus,
+        Command::BeeperToggle,
+        Command::Info,
+        Command::Ratings,
+        Command::SelfTest,
+        Command::SelfTestUntilBatteryLow,
+        Command::CancelSelfTest,
+        Command::CancelShutdown,
+        Command::ProtocolId,
+        Command::GeneralStatus,
+        Command::Mode,
+        Command::Warnings,
+        Command::Model,
+        Command::RatedInformation,
+        Command::SerialNumber,
+        Command::FirmwareVersion,
+        Command::PhaseInputVoltages,
+        Command::PhaseOutputVoltages,
+        Command::PhaseLoads,
+    ];
+
+    /// The indexed string Megatec HID UPSes map this command onto, if any
+    pub fn hid_string_index(&self) -> Option<u32> {
+        match self {
+            Command::MegatecStatus => Some(3),
+            Command::SelfTest => Some(4),
+            Command::SelfTestUntilBatteryLow => Some(5),
+            Command::BeeperToggle => Some(7),
+            // Both cancellations share a string
+            Command::CancelSelfTest | Command::CancelShutdown => Some(0x0b),
+            Command::Info => Some(0x0c),
+            Command::Ratings => Some(0x0d),
+            _ => None,
+        }
+    }
+
+    /// Parse the response to this command, without the terminator or CRC.
+    /// Statuses are parsed leniently, see
+    /// [`parse_response_with`](Self::parse_response_with).
+    ///
+    /// `(NAK` becomes [`UpsError::Nak`] whatever the command.
+    pub fn parse_response(&self, response: &[u8]) -> Result<Response> {
+        self.parse_response_with(response, ParseMode::Lenient)
+    }
+
+    /// Like [`parse_response`](Self::parse_response), dealing with status
+    /// fields that don't parse as `mode` says
+    pub fn parse_response_with(&self, response: &[u8], mode: ParseMode) -> Result<Response> {
+        let response = match std::str::from_utf8(response) {
+            Ok(response) => response,
+            Err(_) => bail!(UpsError::Framing(
+                "UPS response is not valid UTF-8".to_string()
+            )),
+        };
+        // Indexed strings keep the terminator
+        let response = response.strip_suffix('\r').unwrap_or(response);
+
+        if response == "(NAK" {
+            bail!(UpsError::Nak(self.to_string()));
+        }
+
+        Ok(match self {
+            Command::Protocol => Response::Protocol(match response {
+                "P" => UpsProtocol::P,
+                "T" => UpsProtocol::T,
+                "V" => UpsProtocol::V,
+                // Another protocol letter, as opposed to a garbled response
+                _ if response.len() == 1
+                    && response.bytes().all(|byte| byte.is_ascii_uppercase()) =>
+                {
+                    UpsProtocol::Unknown
+                }
+                _ => bail!(UpsError::Framing(format!(
+                    "Malformed M response: {:?}",
+                    response
+                ))),
+            }),
+            Command::Status | Command::MegatecStatus => {
+                // UpsStatus expects the terminator
+                Response::Status(UpsStatus::parse(&format!("{}\r", response), mode)?)
+            }
+            Command::BeeperToggle => Response::None,
+            Command::Info => Response::Info(parse_info(response)?),
+            Command::Ratings => Response::Ratings(parse_ratings(response, '#')?),
+            // Megatec UPSes don't answer these, Voltronic P and T ones ACK
+            // them
+            Command::SelfTest
+            | Command::SelfTestUntilBatteryLow
+            | Command::TimedSelfTest(_)
+            | Command::CancelSelfTest
+            | Command::Shutdown { .. }
+            | Command::CancelShutdown => match response {
+                "(ACK" => Response::Ack,
+                _ => Response::None,
+            },
+            Command::ProtocolId => Response::ProtocolId(parse_protocol_id(response)?),
+            Command::GeneralStatus => Response::Status(parse_general_status(response, mode)?),
+            Command::Mode => Response::Mode(parse_mode(response)?),
+            Command::Warnings => Response::Warnings(parse_warnings(response)?),
+            Command::Model => Response::Model(parse_model(response)?),
+            Command::RatedInformation => Response::Ratings(parse_ratings(response, '(')?),
+            Command::SerialNumber => Response::Text(strip_header(response)?.to_string()),
+            Command::FirmwareVersion => Response::Text(parse_firmware_version(response)?),
+            Command::PhaseInputVoltages | Command::PhaseOutputVoltages | Command::PhaseLoads => {
+                Response::PhaseValues(parse_phase_values(response)?)
+            }
+            Command::EnableFlag(_) | Command::DisableFlag(_) => match response {
+                "(ACK" => Response::Ack,
+                _ => bail!(UpsError::Framing(format!(
+                    "Unexpected response to {}: {:?}",
+                    self, response
+                ))),
+            },
+        })
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Protocol => write!(f, "M"),
+            Command::Status => write!(f, "QS"),
+            Command::MegatecStatus => write!(f, "Q1"),
+            Command::BeeperToggle => write!(f, "Q"),
+            Command::Info => write!(f, "I"),
+            Command::Ratings => write!(f, "F"),
+            Command::SelfTest => write!(f, "T"),
+            Command::SelfTestUntilBatteryLow => write!(f, "TL"),
+            Command::TimedSelfTest(minutes) => write!(f, "T{:02}", minutes),
+            Command::CancelSelfTest => write!(f, "CT"),
+            Command::Shutdown { delay, restore } => {
+                if *delay < 10 {
+                    write!(f, "S.{}", delay)?;
+                } else {
+                    write!(f, "S{:02}", delay / 10)?;
+                }
+                match restore {
+                    Some(0) => Ok(()),
+                    Some(restore) => write!(f, "R{:04}", restore),
+                    None => write!(f, "R0000"),
+                }
+            }
+            Command::CancelShutdown => write!(f, "C"),
+            Command::ProtocolId => write!(f, "QPI"),
+            Command::GeneralStatus => write!(f, "QGS"),
+            Command::Mode => write!(f, "QMOD"),
+            Command::Warnings => write!(f, "QWS"),
+            Command::Model => write!(f, "QMD"),
+            Command::RatedInformation => write!(f, "QRI"),
+            Command::SerialNumber => write!(f, "QID"),
+            Command::FirmwareVersion => write!(f, "QVFW"),
+            Command::PhaseInputVoltages => write!(f, "Q3PV"),
+            Command::PhaseOutputVoltages => write!(f, "Q3OV"),
+            Command::PhaseLoads => write!(f, "Q3LD"),
+            Command::EnableFlag(flag) => write!(f, "PE{}", flag),
+            Command::DisableFlag(flag) => write!(f, "PD{}", flag),
+        }
+    }
+}
+
+impl Command {
+    /// The shutdown command closest to `delay` and `restore_after`. The UPS
+    /// counts the delay in tenths of a minute up to a minute, and in whole
+    /// minutes up to 10 minutes. The restore delay is in whole minutes.
+    pub fn shutdown(delay: Duration, restore_after: Option<Duration>) -> Result<Self> {
+        let delay = match (delay.as_secs_f64() / 6.0).round() {
+            tenths if (2.0..10.0).contains(&tenths) => tenths as u8,
+            _ => match (delay.as_secs_f64() / 60.0).round() {
+                minutes if (1.0..=10.0).contains(&minutes) => minutes as u8 * 10,
+                _ => bail!(UpsError::Unsupported(
+                    "Shutdown delays can be 12 seconds to 10 minutes".to_string()
+                )),
+            },
+        };
+
+        let restore = match restore_after.map(|after| (after.as_secs_f64() / 60.0).round()) {
+            Some(minutes) if (1.0..=9999.0).contains(&minutes) => Some(minutes as u16),
+            Some(_) => bail!(UpsError::Unsupported(
+                "Restore delays can be 1 to 9999 minutes".to_string()
+            )),
+            None => None,
+        };
+
+        Ok(Command::Shutdown { delay, restore })
+    }
+}
+
+impl TryFrom<SelfTest> for Command {
+    type Error = anyhow::Error;
+
+    fn try_from(test: SelfTest) -> Result<Self> {
+        Ok(match test {
+            SelfTest::Quick => Command::SelfTest,
+            SelfTest::UntilBatteryLow => Command::SelfTestUntilBatteryLow,
+            SelfTest::Timed(minutes @ 1..=99) => Command::TimedSelfTest(minutes),
+            SelfTest::Timed(_) => bail!(UpsError::Unsupported(
+                "Battery tests can last 1 to 99 minutes".to_string()
+            )),
+        })
+    }
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> Result<Self> {
+        if let Some(command) = Self::ALL
+            .iter()
+            .find(|command| command.to_string() == string)
+        {
+            return Ok(*command);
+        }
+
+        let flag = |rest: &str| {
+            let mut chars = rest.chars();
+            match (chars.next(), chars.next()) {
+                (Some(flag), None) if flag.is_ascii_lowercase() => Some(flag),
+                _ => None,
+            }
+        };
+        if let Some(minutes) = string.strip_prefix('T') {
+            if minutes.len() == 2 && minutes.bytes().all(|digit| digit.is_ascii_digit()) {
+                match minutes.parse() {
+                    Ok(minutes @ 1..=99) => return Ok(Command::TimedSelfTest(minutes)),
+                    _ => bail!("Battery test duration must be 01 to 99 minutes"),
+                }
+            }
+        }
+
+        if let Some(command) = string.strip_prefix('S').and_then(parse_shutdown) {
+            return Ok(command);
+        }
+
+        if let Some(flag) = string.strip_prefix("PE").and_then(flag) {
+            return Ok(Command::EnableFlag(flag));
+        }
+        if let Some(flag) = string.strip_prefix("PD").and_then(flag) {
+            return Ok(Command::DisableFlag(flag));
+        }
+
+        bail!("Unknown command {:?}", string)
+    }
+}
+
+impl Response {
+    pub fn into_status(self) -> Result<UpsStatus> {
+        match self {
+            Response::Status(status) => Ok(status),
+            response => Err(mismatch("a status", response)),
+        }
+    }
+
+    pub fn into_protocol(self) -> Result<UpsProtocol> {
+        match self {
+            Response::Protocol(protocol) => Ok(protocol),
+            response => Err(mismatch("a protocol", response)),
+        }
+    }
+
+    pub fn into_mode(self) -> Result<VoltronicMode> {
+        match self {
+            Response::Mode(mode) => Ok(mode),
+            response => Err(mismatch("a mode", response)),
+        }
+    }
+
+    pub fn into_warnings(self) -> Result<VoltronicWarnings> {
+        match self {
+            Response::Warnings(warnings) => Ok(warnings),
+            response => Err(mismatch("warnings", response)),
+        }
+    }
+
+    pub fn into_model(self) -> Result<ModelInfo> {
+        match self {
+            Response::Model(model) => Ok(model),
+            response => Err(mismatch("model information", response)),
+        }
+    }
+
+    pub fn into_info(self) -> Result<UpsInfo> {
+        match self {
+            Response::Info(info) => Ok(info),
+            response => Err(mismatch("identification", response)),
+        }
+    }
+
+    pub fn into_ratings(self) -> Result<UpsRatings> {
+        match self {
+            Response::Ratings(ratings) => Ok(ratings),
+            response => Err(mismatch("ratings", response)),
+        }
+    }
+
+    pub fn into_text(self) -> Result<String> {
+        match self {
+            Response::Text(text) => Ok(text),
+            response => Err(mismatch("text", response)),
+        }
+    }
+
+    pub fn into_phase_values(self) -> Result<[f32; 3]> {
+        match self {
+            Response::PhaseValues(values) => Ok(values),
+            response => Err(mismatch("per-phase values", response)),
+        }
+    }
+}
+
+fn mismatch(expected: &str, response: Response) -> anyhow::Error {
+    anyhow::anyhow!("Expected {}, got {:?}", expected, response)
+}
+
+/// Send `command` as-is and parse the response, checking its CRC as `crc` says
+pub async fn transact<T: Transport + ?Sized>(
+    device: &T,
+    command: Command,
+    crc: CrcPolicy,
+) -> Result<Response> {
+    let response = framing::transact_command(device, &command.to_string(), crc).await?;
+    command.parse_response(&response)
+}
+
+/// Run `command` through its indexed string, the way Megatec HID UPSes want
+pub async fn transact_indexed<T: Transport + ?Sized>(
+    device: &T,
+    command: Command,
+) -> Result<Response> {
+    let index = match command.hid_string_index() {
+        Some(index) => index,
+        None => bail!(UpsError::Unsupported(format!(
+            "{} has no indexed string",
+            command
+        ))),
+    };
+
+    let response = device.get_indexed_string(index).await?;
+    command.parse_response(response.as_bytes())
+}
+
+/// The work mode reported by QMOD
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoltronicMode {
+    PowerOn,
+    Standby,
+    Bypass,
+    Line,
+    Battery,
+    BatteryTest,
+    Fault,
+    Eco,
+    Converter,
+    Shutdown,
+}
+
+impl VoltronicMode {
+    /// The status flags implied by the mode
+    pub fn flags(&self) -> UpsStatusFlags {
+        match self {
+            VoltronicMode::Battery => UpsStatusFlags::UTILITY_FAIL,
+            VoltronicMode::BatteryTest => UpsStatusFlags::SELF_TEST_IN_PROGRESS,
+            VoltronicMode::Fault => UpsStatusFlags::UPS_FAULT,
+            VoltronicMode::Shutdown => UpsStatusFlags::UPS_SHUTDOWN_ACTIVE,
+            _ => UpsStatusFlags::empty(),
+        }
+    }
+}
+
+/// The warning bits reported by QWS, starting from bit 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoltronicWarnings {
+    bits: Vec<bool>,
+}
+
+impl VoltronicWarnings {
+    const BATTERY_LOW: usize = 7;
+
+    pub fn is_set(&self, bit: usize) -> bool {
+        self.bits.get(bit).copied().unwrap_or(false)
+    }
+
+    /// The status flags implied by the warnings
+    pub fn flags(&self) -> UpsStatusFlags {
+        let mut flags = UpsStatusFlags::empty();
+        flags.set(UpsStatusFlags::BATTERY_LOW, self.is_set(Self::BATTERY_LOW));
+        flags
+    }
+}
+
+/// The response to QMD
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub model: String,
+    pub rated_va: u32,
+    /// Power factor, in percent
+    pub power_factor: u32,
+    pub input_phases: u8,
+    pub output_phases: u8,
+    pub nominal_input_voltage: f32,
+    pub nominal_output_voltage: f32,
+    pub battery_count: u32,
+    pub battery_voltage: f32,
+}
+
+/// The fields outside [`UpsStatusFields`] that [`UpsError::InvalidField`]
+/// can name
+pub(crate) const RESPONSE_FIELDS: &[&str] = &[
+    "status_bits",
+    "mode",
+    "phases",
+    "rated_va",
+    "power_factor",
+    "nominal_input_voltage",
+    "nominal_output_voltage",
+    "battery_count",
+    "battery_voltage",
+];
+
+/// Parse the arguments of `S<n>R<m>`, with `n` either `.2` to `.9` or `01` to
+/// `10`, and the optional `m` `0000` to `9999`. `R0000` keeps the output off,
+/// while leaving `R<m>` out turns it back on once mains power returns.
+fn parse_shutdown(arguments: &str) -> Option<Command> {
+    let digits = |string: &str| string.bytes().all(|digit| digit.is_ascii_digit());
+
+    let (delay, restore) = match arguments.split_once('R') {
+        Some((delay, restore)) => (delay, Some(restore)),
+        None => (arguments, None),
+    };
+
+    let delay = match delay.strip_prefix('.') {
+        Some(tenths) if tenths.len() == 1 && digits(tenths) => match tenths.parse() {
+            Ok(tenths @ 2..=9) => tenths,
+            _ => return None,
+        },
+        Some(_) => return None,
+        None if delay.len() == 2 && digits(delay) => match delay.parse::<u8>() {
+            Ok(minutes @ 1..=10) => minutes * 10,
+            _ => return None,
+        },
+        None => return None,
+    };
+
+    let restore = match restore {
+        Some(restore) if restore.len() == 4 && digits(restore) => match restore.parse() {
+            Ok(0) => None,
+            Ok(minutes) => Some(minutes),
+            Err(_) => return None,
+        },
+        Some(_) => return None,
+        None => Some(0),
+    };
+
+    Some(Command::Shutdown { delay, restore })
+}
+
+/// Strip the `(` that starts every P and T protocol response
+fn strip_header(response: &str) -> Result<&str> {
+    match response.strip_prefix('(') {
+        Some(response) => Ok(response),
+        None => bail!(UpsError::Framing(format!(
+            "Unexpected response header: {:?}",
+            response
+        ))),
+    }
+}
+
+/// Parse the response to I:
+///
+/// `#Company_Name___ UPS_Model_ Version___`
+///
+/// The fields are padded to 15, 10 and 10 characters, and may contain spaces.
+fn parse_info(response: &str) -> Result<UpsInfo> {
+    let response = match response.strip_prefix('#') {
+        Some(response) if response.is_ascii() && response.len() >= 27 => response,
+        _ => bail!(UpsError::Framing(format!(
+            "Malformed I response: {:?}",
+            response
+        ))),
+    };
+
+    let field = |range: std::ops::Range<usize>| {
+        let end = range.end.min(response.len());
+        Some(response[range.start.min(end)..end].trim())
+            .filter(|field| !field.is_empty())
+            .map(str::to_string)
+    };
+
+    Ok(UpsInfo {
+        manufacturer: field(0..15),
+        model: field(16..26),
+        firmware_version: field(27..37),
+        serial_number: None,
+    })
+}
+
+/// Parse the response to F or QRI, which differ only in their header:
+///
+/// `#MMM.M QQQ SS.SS RR.R`
+///
+/// That is output voltage, output current, battery voltage and frequency.
+fn parse_ratings(response: &str, header: char) -> Result<UpsRatings> {
+    let parts: Vec<_> = match response.strip_prefix(header) {
+        Some(response) => response.split_whitespace().collect(),
+        None => bail!(UpsError::Framing(format!(
+            "Unexpected response header: {:?}",
+            response
+        ))),
+    };
+    if parts.len() != 4 {
+        bail!(UpsError::Framing(format!(
+            "Unexpected number of ratings parts: {:?}",
+            response
+        )));
+    }
+
+    Ok(UpsRatings {
+        voltage: parts[0].parse().ok(),
+        current: parts[1].parse().ok(),
+        frequency: parts[3].parse().ok(),
+        apparent_power: None,
+        battery_voltage: parts[2].parse().ok(),
+        battery_count: None,
+    })
+}
+
+/// Parse the response to QVFW, like `(VERFW:00322.02`
+fn parse_firmware_version(response: &str) -> Result<String> {
+    match strip_header(response)?.strip_prefix("VERFW:") {
+        Some(version) => Ok(version.trim().to_string()),
+        None => bail!(UpsError::Framing(format!(
+            "Malformed QVFW response: {:?}",
+            response
+        ))),
+    }
+}
+
+/// Parse the response to QPI, like `(PI30`
+fn parse_protocol_id(response: &str) -> Result<u32> {
+    match strip_header(response)?
+        .strip_prefix("PI")
+        .and_then(|id| id.parse().ok())
+    {
+        Some(id) => Ok(id),
+        None => bail!(UpsError::Framing(format!(
+            "Malformed QPI response: {:?}",
+            response
+        ))),
+    }
+}
+
+/// Parse the response to QGS:
+///
+/// `(MMM.M HH.H LLL.L NN.N QQQ DDD KKK.K VVV.V SSS.S XXX.X TTT.T b11..b0`
+///
+/// That is input voltage and frequency, output voltage and frequency, output
+/// current, load percentage, positive and negative bus voltage, positive and
+/// negative battery voltage, maximum temperature and status bits. Values a
+/// model doesn't measure are dashed out.
+fn parse_general_status(response: &str, mode: ParseMode) -> Result<UpsStatus> {
+    let parts: Vec<_> = strip_header(response)?.split_whitespace().collect();
+    if parts.len() != 12 {
+        bail!(UpsError::Framing(format!(
+            "Unexpected number of QGS response parts: {:?}",
+            response
+        )));
+    }
+
+    let bits = parts[11].as_bytes();
+    if bits.len() != 12 || bits.iter().any(|bit| !matches!(bit, b'0' | b'1')) {
+        bail!(UpsError::InvalidField {
+            field: "status_bits",
+            value: parts[11].to_string(),
+            reason: "expected 12 bits".to_string(),
+        });
+    }
+    let bit = |n: usize| bits[11 - n] == b'1';
+
+    let mut flags = UpsStatusFlags::empty();
+    // b11 b10 are the UPS type: 00 standby, 01 line-interactive, 10 on-line
+    flags.set(UpsStatusFlags::UPS_LINE_INTERACTIVE, !bit(11) && bit(10));
+    flags.set(UpsStatusFlags::UTILITY_FAIL, bit(9));
+    flags.set(UpsStatusFlags::BATTERY_LOW, bit(8));
+    flags.set(UpsStatusFlags::BOOST_OR_BUCK_MODE, bit(7));
+    flags.set(UpsStatusFlags::UPS_FAULT, bit(6));
+    // b5 is EPO active, which has no flag of its own
+    flags.set(UpsStatusFlags::SELF_TEST_IN_PROGRESS, bit(4));
+    flags.set(UpsStatusFlags::UPS_SHUTDOWN_ACTIVE, bit(3));
+    flags.set(UpsStatusFlags::BEEPER_ACTIVE, bit(2));
+
+    let mut fields = FieldParser::new(mode);
+    Ok(UpsStatus {
+        input_voltage: fields.parse(UpsStatusFields::INPUT_VOLTAGE, parts[0], f32::NAN)?,
+        input_fault_voltage: f32::NAN,
+        output_voltage: fields.parse(UpsStatusFields::OUTPUT_VOLTAGE, parts[2], f32::NAN)?,
+        output_load_level: fields.parse(UpsStatusFields::OUTPUT_LOAD_LEVEL, parts[5], 0)?,
+        output_frequency: fields.parse(UpsStatusFields::OUTPUT_FREQUENCY, parts[3], f32::NAN)?,
+        battery_voltage: fields.parse(UpsStatusFields::BATTERY_VOLTAGE, parts[8], f32::NAN)?,
+        internal_temperature: fields.parse(
+            UpsStatusFields::INTERNAL_TEMPERATURE,
+            parts[10],
+            f32::NAN,
+        )?,
+        battery_capacity: None,
+        battery_run_time: None,
+        flags,
+        invalid_fields: fields.invalid_fields,
+    })
+}
+
+/// Parse the response to QMOD, a single letter for the work mode
+fn parse_mode(response: &str) -> Result<VoltronicMode> {
+    Ok(match strip_header(response)? {
+        "P" => VoltronicMode::PowerOn,
+        "S" => VoltronicMode::Standby,
+        "Y" => VoltronicMode::Bypass,
+        "L" => VoltronicMode::Line,
+        "B" => VoltronicMode::Battery,
+        "T" => VoltronicMode::BatteryTest,
+        "F" => VoltronicMode::Fault,
+        "E" => VoltronicMode::Eco,
+        "C" => VoltronicMode::Converter,
+        "D" => VoltronicMode::Shutdown,
+        mode => bail!(UpsError::InvalidField {
+            field: "mode",
+            value: mode.to_string(),
+            reason: "unknown mode".to_string(),
+        }),
+    })
+}
+
+/// Parse the response to QWS, one bit per warning starting from bit 0
+fn parse_warnings(response: &str) -> Result<VoltronicWarnings> {
+    let bits = strip_header(response)?.as_bytes();
+    if bits.len() <= VoltronicWarnings::BATTERY_LOW
+        || bits.iter().any(|bit| !matches!(bit, b'0' | b'1'))
+    {
+        bail!(UpsError::Framing(format!(
+            "Malformed QWS response: {:?}",
+            response
+        )));
+    }
+
+    Ok(VoltronicWarnings {
+        bits: bits.iter().map(|&bit| bit == b'1').collect(),
+    })
+}
+
+/// Parse the response to QMD:
+///
+/// `(TTTTTTTTTTTTTTT WWWWWWW KK P/Q MMM NNN RR BB.B`
+///
+/// That is the model name padded with `#`, VA rating, power factor, input and
+/// output phases, nominal input and output voltage, battery count and
+/// voltage per battery.
+fn parse_model(response: &str) -> Result<ModelInfo> {
+    let parts: Vec<_> = strip_header(response)?.split_whitespace().collect();
+    if parts.len() != 8 {
+        bail!(UpsError::Framing(format!(
+            "Unexpected number of QMD response parts: {:?}",
+            response
+        )));
+    }
+
+    let phases = parts[3]
+        .split_once('/')
+        .and_then(|(input, output)| Some((input.parse().ok()?, output.parse().ok()?)));
+    let (input_phases, output_phases) = match phases {
+        Some((input_phases @ 1..=3, output_phases @ 1..=3)) => (input_phases, output_phases),
+        _ => bail!(UpsError::InvalidField {
+            field: "phases",
+            value: parts[3].to_string(),
+            reason: "expected 1 to 3 input and output phases".to_string(),
+        }),
+    };
+
+    let field = |index: usize| parts[index].trim_start_matches('#');
+
+    Ok(ModelInfo {
+        model: field(0).to_string(),
+        rated_va: parse_model_field("rated_va", field(1))?,
+        power_factor: parse_model_field("power_factor", field(2))?,
+        input_phases,
+        output_phases,
+        nominal_input_voltage: parse_model_field("nominal_input_voltage", field(4))?,
+        nominal_output_voltage: parse_model_field("nominal_output_voltage", field(5))?,
+        battery_count: parse_model_field("battery_count", field(6))?,
+        battery_voltage: parse_model_field("battery_voltage", field(7))?,
+    })
+}
+
+/// Parse a number from a QMD response, naming the field if it doesn't parse
+fn parse_model_field<F: FromStr>(field: &'static str, value: &str) -> Result<F>
+where
+    F::Err: fmt::Display,
+{
+    value.parse().map_err(|error: F::Err| {
+        UpsError::InvalidField {
+            field,
+            value: value.to_string(),
+            reason: error.to_string(),
+        }
+        .into()
+    })
+}
+
+/// Parse the response to Q3PV, Q3OV or Q3LD, keeping the three
+/// phase-to-neutral values. Q3PV carries the line-to-line voltages after
+/// them, which are dropped.
+fn parse_phase_values(response: &str) -> Result<[f32; 3]> {
+    let parts: Vec<_> = strip_header(response)?.split_whitespace().collect();
+    if parts.len() < 3 {
+        bail!(UpsError::Framing(format!(
+            "Too few phases in response: {:?}",
+            response
+        )));
+    }
+
+    // Dashed out phases come out as NaN, which the min/max folds skip
+    Ok([0, 1, 2].map(|phase| parts[phase].parse().unwrap_or(f32::NAN)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_transport::MockTransport;
+
+    #[test]
+    fn wire_forms_round_trip() {
+        for command in Command::ALL.into_iter().chain([
+            Command::EnableFlag('a'),
+            Command::DisableFlag('x'),
+            Command::TimedSelfTest(5),
+            Command::Shutdown {
+                delay: 3,
+                restore: None,
+            },
+            Command::Shutdown {
+                delay: 100,
+                restore: Some(1),
+            },
+            Command::Shutdown {
+                delay: 50,
+                restore: Some(0),
+            },
+        ]) {
+            assert_eq!(command.to_string().parse::<Command>().unwrap(), command);
+        }
+
+        assert_eq!(Command::EnableFlag('a').to_string(), "PEa");
+        assert!("PEA".parse::<Command>().is_err());
+        assert!("PEab".parse::<Command>().is_err());
+        assert!("QX".parse::<Command>().is_err());
+
+        assert_eq!(Command::TimedSelfTest(5).to_string(), "T05");
+        assert!("T00".parse::<Command>().is_err());
+        assert!("T5".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn shutdown_durations() {
+        let shutdown = |delay, restore: Option<u64>| {
+            Command::shutdown(Duration::from_secs(delay), restore.map(Duration::from_secs))
+                .map(|command| command.to_string())
+        };
+
+        assert_eq!(shutdown(30, None).unwrap(), "S.5R0000");
+        assert_eq!(shutdown(12, Some(60)).unwrap(), "S.2R0001");
+        assert_eq!(shutdown(100, Some(600)).unwrap(), "S02R0010");
+        assert_eq!(shutdown(600, None).unwrap(), "S10R0000");
+        assert!(shutdown(5, None).is_err());
+        assert!(shutdown(660, None).is_err());
+        assert!(shutdown(60, Some(10)).is_err());
+
+        assert!("S.1".parse::<Command>().is_err());
+        assert!("S11".parse::<Command>().is_err());
+        assert_eq!(
+            "S05R0000".parse::<Command>().unwrap(),
+            Command::Shutdown {
+                delay: 50,
+                restore: None,
+            }
+        );
+        assert_eq!(
+            "S05".parse::<Command>().unwrap(),
+            Command::Shutdown {
+                delay: 50,
+                restore: Some(0),
+            }
+        );
+        assert!("S05R1".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn ack_and_nak() {
+        let command = Command::DisableFlag('a');
+
+        assert_eq!(command.parse_response(b"(ACK").unwrap(), Response::Ack);
+
+        let error = command.parse_response(b"(NAK").unwrap_err();
+        assert_eq!(
+            UpsError::of(&error),
+            Some(&UpsError::Nak("PDa".to_string()))
+        );
+
+        // Even commands that don't expect an ACK can be refused
+        let error = Command::PhaseLoads.parse_response(b"(NAK").unwrap_err();
+        assert_eq!(
+            UpsError::of(&error),
+            Some(&UpsError::Nak("Q3LD".to_string()))
+        );
+
+        assert!(command.parse_response(b"(ACKNOWLEDGED").is_err());
+    }
+
+    #[tokio::test]
+    async fn crc_is_checked_as_configured() {
+        let mut response = b"(PI30".to_vec();
+        response.extend(framing::crc16_xmodem(&response).to_be_bytes());
+
+        let mut command = b"QPI".to_vec();
+        command.extend(framing::crc16_xmodem(&command).to_be_bytes());
+
+        let mock = MockTransport::new()
+            .expect_command_bytes(b"QPI", &response)
+            .expect_command("QPI", "(PI30")
+            .expect_command_bytes(&command, &response)
+            .expect_command_bytes(&command, b"(PI30");
+
+        let result = transact(&mock, Command::ProtocolId, CrcPolicy::Optional).await;
+        assert_eq!(result.unwrap(), Response::ProtocolId(30));
+        let result = transact(&mock, Command::ProtocolId, CrcPolicy::Optional).await;
+        assert_eq!(result.unwrap(), Response::ProtocolId(30));
+        let result = transact(&mock, Command::ProtocolId, CrcPolicy::Required).await;
+        assert_eq!(result.unwrap(), Response::ProtocolId(30));
+        let error = transact(&mock, Command::ProtocolId, CrcPolicy::Required)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            UpsError::of(&error),
+            Some(UpsError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn parses_info() {
+        let info = Command::Info
+            .parse_response(b"#Power Corp      UPS 1000   VER 2.01  ")
+            .unwrap()
+            .into_info()
+            .unwrap();
+
+        assert_eq!(
+            info,
+            UpsInfo {
+                manufacturer: Some("Power Corp".to_string()),
+                model: Some("UPS 1000".to_string()),
+                firmware_version: Some("VER 2.01".to_string()),
+                serial_number: None,
+            }
+        );
+
+        assert!(Command::Info.parse_response(b"#Power Corp").is_err());
+    }
+
+    #[test]
+    fn parses_ratings() {
+        let expected = UpsRatings {
+            voltage: Some(220.0),
+            current: Some(4.0),
+            frequency: Some(50.0),
+            apparent_power: None,
+            battery_voltage: Some(24.0),
+            battery_count: None,
+        };
+
+        let ratings = Command::Ratings
+            .parse_response(b"#220.0 004 24.00 50.0")
+            .unwrap();
+        assert_eq!(ratings, Response::Ratings(expected));
+
+        let ratings = Command::RatedInformation
+            .parse_response(b"(220.0 004 24.00 50.0")
+            .unwrap();
+        assert_eq!(ratings, Response::Ratings(expected));
+
+        assert!(Command::RatedInformation
+            .parse_response(b"#220.0 004 24.00 50.0")
+            .is_err());
+        assert!(Command::Ratings.parse_response(b"#220.0 004").is_err());
+    }
+
+    #[test]
+    fn parses_firmware_version() {
+        let version = Command::FirmwareVersion
+            .parse_response(b"(VERFW:00322.02")
+            .unwrap();
+
+        assert_eq!(version, Response::Text("00322.02".to_string()));
+    }
+
+    #[test]
+    fn parses_model() {
+        let model = Command::Model
+            .parse_response(b"(#######OLHVT1K0 ###1000 80 1/1 230 230 02 12.0")
+            .unwrap()
+            .into_model()
+            .unwrap();
+
+        assert_eq!(
+            model,
+            ModelInfo {
+                model: "OLHVT1K0".to_string(),
+                rated_va: 1000,
+                power_factor: 80,
+                input_phases: 1,
+                output_phases: 1,
+                nominal_input_voltage: 230.0,
+                nominal_output_voltage: 230.0,
+                battery_count: 2,
+                battery_voltage: 12.0,
+            }
+        );
+    }
+
+    fn is_framing<R>(result: Result<R>) -> bool {
+        matches!(
+            UpsError::of(&result.err().unwrap()),
+            Some(UpsError::Framing(_))
+        )
+    }
+
+    fn is_invalid_field<R>(result: Result<R>) -> bool {
+        matches!(
+            UpsError::of(&result.err().unwrap()),
+            Some(UpsError::InvalidField { .. })
+        )
+    }
+
+    #[test]
+    fn malformed_p_responses_are_errors() {
+        assert!(is_framing(parse_general_status(
+            "234.9 50.0 229.8 50.0 000 012 369.1 ---.- 026.5 ---.- 018.8 011000000100",
+            ParseMode::Lenient
+        )));
+        assert!(is_framing(parse_general_status(
+            "(234.9 50.0 229.8",
+            ParseMode::Lenient
+        )));
+        assert!(is_invalid_field(parse_general_status(
+            "(234.9 50.0 229.8 50.0 000 012 369.1 ---.- 026.5 ---.- 018.8 0110",
+            ParseMode::Lenient
+        )));
+        assert!(is_invalid_field(parse_mode("(X")));
+        assert!(is_framing(parse_warnings("(0000002")));
+        assert!(is_framing(parse_protocol_id("(P30")));
+        assert!(is_framing(parse_firmware_version("(VER:00322.02")));
+        assert!(is_framing(parse_ratings("#230.0 004 12.00", '#')));
+    }
+
+    #[test]
+    fn general_status_follows_parse_mode() {
+        let response = b"(234.9 50.0 2#9.8 50.0 000 012 369.1 ---.- 026.5 ---.- 018.8 011000000100";
+
+        let error = Command::GeneralStatus
+            .parse_response_with(response, ParseMode::Strict)
+            .unwrap_err();
+        match UpsError::of(&error) {
+            Some(UpsError::InvalidField { field, value, .. }) => {
+                assert_eq!(*field, "output_voltage");
+                assert_eq!(value, "2#9.8");
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+
+        let status = Command::GeneralStatus
+            .parse_response(response)
+            .unwrap()
+            .into_status()
+            .unwrap();
+        assert_eq!(status.invalid_fields.len(), 1);
+        assert_eq!(
+            status.invalid_fields[0].field,
+            UpsStatusFields::OUTPUT_VOLTAGE
+        );
+        assert_eq!(status.invalid_fields[0].value, "2#9.8");
+        assert_eq!(status.input_voltage, 234.9);
+    }
+
+    #[test]
+    fn malformed_t_responses_are_errors() {
+        assert!(is_framing(parse_model(
+            "(#######OLHVT1K0 ###1000 80 1/1 230 230 02"
+        )));
+        assert!(is_invalid_field(parse_model(
+            "(#######OLHVT1K0 ###1000 80 4/1 230 230 02 12.0"
+        )));
+        assert!(is_invalid_field(parse_model(
+            "(#######OLHVT1K0 ###1000 80 1-1 230 230 02 12.0"
+        )));
+        assert!(is_invalid_field(parse_model(
+            "(#######OLHVT1K0 ###1K00 80 1/1 230 230 02 12.0"
+        )));
+        assert!(is_invalid_field(parse_model(
+            "(#######OLHVT1K0 ###1000 80 1/1 2#0 230 02 12.0"
+        )));
+        assert!(is_framing(parse_phase_values("(230.1 229.9")));
+        assert!(is_framing(parse_phase_values("230.1 229.9 230.3")));
+    }
+
+    #[tokio::test]
+    async fn indexed_strings_map_megatec_commands() {
+        let mock = MockTransport::new()
+            .expect_indexed_string(3, "(208.4 140.0 208.4 034 59.9 2.05 35.0 10000000\r");
+
+        let status = transact_indexed(&mock, Command::MegatecStatus)
+            .await
+            .unwrap()
+            .into_status()
+            .unwrap();
+        assert_eq!(status.output_load_level, 34);
+
+        let error = transact_indexed(&mock, Command::GeneralStatus)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            UpsError::of(&error),
+            Some(UpsError::Unsupported(_))
+        ));
+    }
+}
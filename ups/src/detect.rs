@@ -0,0 +1,482 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+
+use crate::{
+    command::{Command, Response},
+    error::UpsError,
+    framing::{self, CrcPolicy},
+    hid_power_device_ups::{self, HidPowerDeviceUps},
+    megatec_hid_ups::MegatecHidUps,
+    megatec_serial_ups::MegatecSerialUps,
+    report_descriptor::ReportDescriptor,
+    transport::Transport,
+    ups::Ups,
+    voltronic_hid_ups::{UpsProtocol, VoltronicHidUps},
+};
+
+/// The protocol an UPS was found to speak
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Megatec, with commands mapped onto indexed strings
+    MegatecHid,
+    /// Megatec, with commands sent as-is
+    MegatecSerial,
+    Voltronic(UpsProtocol),
+    /// The USB HID Power Device Class, as found in the report descriptor
+    HidPowerDevice,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::MegatecHid => write!(f, "Megatec (HID)"),
+            Protocol::MegatecSerial => write!(f, "Megatec (serial)"),
+            Protocol::Voltronic(protocol) => write!(f, "Voltronic {:?}", protocol),
+            Protocol::HidPowerDevice => write!(f, "HID Power Device"),
+        }
+    }
+}
+
+/// How sure the detection is of its verdict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    /// A single identification probe matched
+    Medium,
+    /// The UPS returned a well-formed status, or two probes agreed
+    High,
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Confidence::Medium => write!(f, "medium"),
+            Confidence::High => write!(f, "high"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeOutcome {
+    /// The UPS gave the expected kind of response
+    Matched(String),
+    /// The UPS responded, but not the way the protocol would
+    Unexpected(String),
+    /// The UPS gave a well-formed response naming a protocol this crate
+    /// doesn't speak
+    Unsupported(String),
+    /// The request itself failed, usually by timing out
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeResult {
+    /// The command sent, and how if not as-is
+    pub probe: String,
+    pub outcome: ProbeOutcome,
+}
+
+/// Everything that was tried during detection, in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DetectionReport {
+    pub probes: Vec<ProbeResult>,
+    pub confidence: Option<Confidence>,
+}
+
+impl fmt::Display for DetectionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, result) in self.probes.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            match &result.outcome {
+                ProbeOutcome::Matched(response) => {
+                    write!(f, "{}: matched {:?}", result.probe, response)?
+                }
+                ProbeOutcome::Unexpected(response) => {
+                    write!(f, "{}: unexpected {:?}", result.probe, response)?
+                }
+                ProbeOutcome::Unsupported(response) => {
+                    write!(f, "{}: unsupported {:?}", result.probe, response)?
+                }
+                ProbeOutcome::Failed(error) => write!(f, "{}: failed ({})", result.probe, error)?,
+            }
+        }
+        if let Some(confidence) = self.confidence {
+            write!(f, "; confidence {}", confidence)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Detection {
+    pub ups: Box<dyn Ups + Send + Sync>,
+    pub protocol: Protocol,
+    pub report: DetectionReport,
+}
+
+/// The name of the probe that looks at the report descriptor
+const DESCRIPTOR_PROBE: &str = "report descriptor";
+
+/// Find out which protocol the UPS behind `device` speaks, and wrap it in the
+/// matching driver.
+///
+/// Only queries are sent, never anything that changes the state of the UPS,
+/// so probing a device with the wrong protocol is harmless. Failing to detect
+/// anything is [`UpsError::Unsupported`] only if the UPS named a protocol
+/// this crate doesn't speak. Silence is [`UpsError::DeviceNotFound`], and
+/// responses that match nothing are [`UpsError::Framing`], as both may
+/// clear up on a retry.
+pub async fn detect<T: Transport + 'static>(device: T) -> Result<Detection> {
+    let mut report = DetectionReport::default();
+
+    let (protocol, confidence) = match identify(&device, &mut report.probes).await {
+        Some(result) => result,
+        None if report
+            .probes
+            .iter()
+            .any(|result| matches!(result.outcome, ProbeOutcome::Unsupported(_))) =>
+        {
+            bail!(UpsError::Unsupported(format!(
+                "Could not detect the UPS protocol: {}",
+                report
+            )))
+        }
+        // Nothing answered, so the UPS may just be off or still starting. The
+        // descriptor comes from the host, so it doesn't count.
+        None if report
+            .probes
+            .iter()
+            .filter(|result| result.probe != DESCRIPTOR_PROBE)
+            .all(|result| matches!(result.outcome, ProbeOutcome::Failed(_))) =>
+        {
+            bail!(UpsError::DeviceNotFound(format!(
+                "No UPS answered any protocol probe: {}",
+                report
+            )))
+        }
+        None => bail!(UpsError::Framing(format!(
+            "No UPS protocol matches the responses: {}",
+            report
+        ))),
+    };
+    report.confidence = Some(confidence);
+
+    let ups: Box<dyn Ups + Send + Sync> = match protocol {
+        Protocol::MegatecHid => Box::new(MegatecHidUps::new(device)?),
+        Protocol::MegatecSerial => Box::new(MegatecSerialUps::new(device)?),
+        Protocol::Voltronic(protocol) => {
+            Box::new(VoltronicHidUps::with_protocol(device, protocol)?)
+        }
+        Protocol::HidPowerDevice => Box::new(HidPowerDeviceUps::new(device).await?),
+    };
+
+    Ok(Detection {
+        ups,
+        protocol,
+        report,
+    })
+}
+
+async fn identify<T: Transport>(
+    device: &T,
+    probes: &mut Vec<ProbeResult>,
+) -> Option<(Protocol, Confidence)> {
+    // Power Device Class UPSes describe themselves, so nothing has to be sent
+    if probe_descriptor(device, probes).await {
+        return Some((Protocol::HidPowerDevice, Confidence::High));
+    }
+
+    // Megatec HID UPSes answer Q1 through string 3, without any reports
+    // changing hands, so try them first
+    let response = device.get_indexed_string(3).await.map(String::into_bytes);
+    let name = "Q1 (indexed string 3)";
+    if check(probes, name, Command::MegatecStatus, response).is_some() {
+        return Some((Protocol::MegatecHid, Confidence::High));
+    }
+
+    let protocol = match probe(device, probes, Command::Protocol).await {
+        Some(Response::Protocol(UpsProtocol::Unknown)) | None => None,
+        Some(response) => response.into_protocol().ok(),
+    };
+    match protocol {
+        Some(UpsProtocol::V) => {
+            let confidence = match probe(device, probes, Command::Status).await {
+                Some(_) => Confidence::High,
+                None => Confidence::Medium,
+            };
+            return Some((Protocol::Voltronic(UpsProtocol::V), confidence));
+        }
+        Some(UpsProtocol::T) => {
+            return Some((Protocol::Voltronic(UpsProtocol::T), Confidence::Medium))
+        }
+        _ => {}
+    }
+
+    // P protocol UPSes report their protocol ID, like "(PI30"
+    let confirmed = probe(device, probes, Command::ProtocolId).await.is_some();
+    match (protocol.is_some(), confirmed) {
+        (true, true) => return Some((Protocol::Voltronic(UpsProtocol::P), Confidence::High)),
+        (true, false) | (false, true) => {
+            return Some((Protocol::Voltronic(UpsProtocol::P), Confidence::Medium))
+        }
+        (false, false) => {}
+    }
+
+    if probe(device, probes, Command::MegatecStatus)
+        .await
+        .is_some()
+    {
+        return Some((Protocol::MegatecSerial, Confidence::High));
+    }
+
+    None
+}
+
+/// Look for a Power Device or Battery System application collection in the
+/// report descriptor, recording the outcome
+async fn probe_descriptor<T: Transport>(device: &T, probes: &mut Vec<ProbeResult>) -> bool {
+    let descriptor = device
+        .report_descriptor()
+        .await
+        .and_then(|descriptor| ReportDescriptor::parse(&descriptor));
+
+    let (outcome, matched) = match descriptor {
+        Ok(descriptor) => {
+            let applications = descriptor
+                .applications
+                .iter()
+                .map(|usage| format!("{:04X}:{:04X}", usage.page, usage.id))
+                .collect::<Vec<_>>()
+                .join(" ");
+            if hid_power_device_ups::is_power_device(&descriptor) {
+                (ProbeOutcome::Matched(applications), true)
+            } else {
+                (ProbeOutcome::Unexpected(applications), false)
+            }
+        }
+        Err(error) => (ProbeOutcome::Failed(error.to_string()), false),
+    };
+    probes.push(ProbeResult {
+        probe: DESCRIPTOR_PROBE.to_string(),
+        outcome,
+    });
+    matched
+}
+
+/// Send `command` as-is, recording the outcome
+async fn probe<T: Transport>(
+    device: &T,
+    probes: &mut Vec<ProbeResult>,
+    command: Command,
+) -> Option<Response> {
+    let response =
+        framing::transact_command(device, &command.to_string(), CrcPolicy::Optional).await;
+    check(probes, &command.to_string(), command, response)
+}
+
+/// Record the outcome of a probe, returning the response if it parsed
+fn check(
+    probes: &mut Vec<ProbeResult>,
+    probe: &str,
+    command: Command,
+    response: Result<Vec<u8>>,
+) -> Option<Response> {
+    let (outcome, result) = match response {
+        Ok(response) => {
+            let text = String::from_utf8_lossy(&response).into_owned();
+            match command.parse_response(&response) {
+                // An unrecognized protocol letter parses, but isn't a match
+                Ok(Response::Protocol(UpsProtocol::Unknown)) => {
+                    (ProbeOutcome::Unsupported(text), None)
+                }
+                Ok(parsed) => (ProbeOutcome::Matched(text), Some(parsed)),
+                Err(_) => (ProbeOutcome::Unexpected(text), None),
+            }
+        }
+        Err(error) => (ProbeOutcome::Failed(error.to_string()), None),
+    };
+    probes.push(ProbeResult {
+        probe: probe.to_string(),
+        outcome,
+    });
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_transport::MockTransport;
+
+    const STATUS: &str = "(215.0 195.0 230.0 014 49.0 27.5 30.0 00001001";
+
+    #[tokio::test]
+    async fn detects_megatec_hid() {
+        let mock = MockTransport::new().expect_indexed_string(3, &format!("{}\r", STATUS));
+
+        let detection = detect(mock).await.unwrap();
+
+        assert_eq!(detection.protocol, Protocol::MegatecHid);
+        assert_eq!(detection.report.confidence, Some(Confidence::High));
+        assert_eq!(detection.report.probes.len(), 2);
+    }
+
+    /// A UPS application collection with a single voltage feature
+    const POWER_DEVICE_DESCRIPTOR: &[u8] = &[
+        0x05, 0x84, // Usage Page (Power Device)
+        0x09, 0x04, // Usage (UPS)
+        0xA1, 0x01, // Collection (Application)
+        0x85, 0x01, //   Report ID (1)
+        0x09, 0x30, //   Usage (Voltage)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x26, 0xFF, 0x00, //   Logical Maximum (255)
+        0x75, 0x08, //   Report Size (8)
+        0x95, 0x01, //   Report Count (1)
+        0xB1, 0x02, //   Feature (Data, Variable, Absolute)
+        0xC0, // End Collection
+    ];
+
+    #[tokio::test]
+    async fn detects_hid_power_device_from_descriptor() {
+        let mock = MockTransport::new().with_report_descriptor(POWER_DEVICE_DESCRIPTOR);
+
+        let detection = detect(mock).await.unwrap();
+
+        assert_eq!(detection.protocol, Protocol::HidPowerDevice);
+        assert_eq!(detection.report.confidence, Some(Confidence::High));
+        assert_eq!(
+            detection.report.probes,
+            [ProbeResult {
+                probe: DESCRIPTOR_PROBE.to_string(),
+                outcome: ProbeOutcome::Matched("0084:0004".to_string()),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn vendor_descriptor_moves_on_to_commands() {
+        let mock = MockTransport::new()
+            .with_report_descriptor(&[
+                0x06, 0x00, 0xFF, // Usage Page (Vendor Defined 0xFF00)
+                0x09, 0x01, // Usage (0x01)
+                0xA1, 0x01, // Collection (Application)
+                0xC0, // End Collection
+            ])
+            .expect_indexed_string(3, &format!("{}\r", STATUS));
+
+        let detection = detect(mock).await.unwrap();
+
+        assert_eq!(detection.protocol, Protocol::MegatecHid);
+        assert_eq!(
+            detection.report.probes[0].outcome,
+            ProbeOutcome::Unexpected("FF00:0001".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn detects_voltronic_v() {
+        let mock = MockTransport::new()
+            .expect_indexed_string_failure(3)
+            .expect_command("M", "V")
+            .expect_command("QS", STATUS);
+
+        let detection = detect(mock).await.unwrap();
+
+        assert_eq!(detection.protocol, Protocol::Voltronic(UpsProtocol::V));
+        assert_eq!(detection.report.confidence, Some(Confidence::High));
+        assert!(matches!(
+            detection.report.probes[1].outcome,
+            ProbeOutcome::Failed(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn detects_voltronic_p() {
+        let mock = MockTransport::new()
+            .expect_indexed_string(3, "")
+            .expect_command("M", "P")
+            .expect_command("QPI", "(PI30");
+
+        let detection = detect(mock).await.unwrap();
+
+        assert_eq!(detection.protocol, Protocol::Voltronic(UpsProtocol::P));
+        assert_eq!(detection.report.confidence, Some(Confidence::High));
+        assert_eq!(
+            detection.report.probes[1].outcome,
+            ProbeOutcome::Unexpected("".to_string())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn qpi_alone_is_medium_confidence() {
+        let mock = MockTransport::new()
+            .expect_indexed_string_failure(3)
+            .expect_command_unanswered("M")
+            .expect_command("QPI", "(PI30");
+
+        let detection = detect(mock).await.unwrap();
+
+        assert_eq!(detection.protocol, Protocol::Voltronic(UpsProtocol::P));
+        assert_eq!(detection.report.confidence, Some(Confidence::Medium));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn detects_megatec_serial() {
+        let mock = MockTransport::new()
+            .expect_indexed_string_failure(3)
+            .expect_command_unanswered("M")
+            .expect_command_unanswered("QPI")
+            .expect_command("Q1", STATUS);
+
+        let detection = detect(mock).await.unwrap();
+
+        assert_eq!(detection.protocol, Protocol::MegatecSerial);
+        assert_eq!(detection.report.probes.len(), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_device_is_an_error() {
+        let mock = MockTransport::new()
+            .expect_indexed_string_failure(3)
+            .expect_command_unanswered("M")
+            .expect_command_unanswered("QPI")
+            .expect_command_unanswered("Q1");
+
+        let error = detect(mock).await.err().unwrap();
+
+        assert!(error.to_string().contains("Q1: failed"));
+        assert!(matches!(
+            UpsError::of(&error),
+            Some(UpsError::DeviceNotFound(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unknown_protocol_is_unsupported() {
+        let mock = MockTransport::new()
+            .expect_indexed_string_failure(3)
+            .expect_command("M", "X")
+            .expect_command("QPI", "(NAK")
+            .expect_command("Q1", "(NAK");
+
+        let error = detect(mock).await.err().unwrap();
+
+        assert!(matches!(
+            UpsError::of(&error),
+            Some(UpsError::Unsupported(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn garbled_responses_are_a_framing_error() {
+        let mock = MockTransport::new()
+            .expect_indexed_string_failure(3)
+            .expect_command("M", "(V#")
+            .expect_command("QPI", "(PX")
+            .expect_command("Q1", "(215.0 1");
+
+        let error = detect(mock).await.err().unwrap();
+
+        assert!(matches!(UpsError::of(&error), Some(UpsError::Framing(_))));
+    }
+}
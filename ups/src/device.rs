@@ -0,0 +1,229 @@
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::error::UpsError;
+
+/// A HID device that might be a UPS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Backend-specific path, which can be used to open the device
+    pub path: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub usage_page: u16,
+    pub usage_id: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04X}:{:04X} (usage {:04X}:{:04X})",
+            self.vendor_id, self.product_id, self.usage_page, self.usage_id
+        )?;
+
+        for value in [&self.manufacturer, &self.product].into_iter().flatten() {
+            write!(f, " {}", value)?;
+        }
+
+        if let Some(serial_number) = &self.serial_number {
+            write!(f, " S/N {}", serial_number)?;
+        }
+
+        write!(f, " at {}", self.path)
+    }
+}
+
+/// Narrows down an enumeration. Fields left as `None` match anything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceFilter {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub usage_page: Option<u16>,
+    pub usage_id: Option<u16>,
+}
+
+impl DeviceFilter {
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        self.vendor_id.is_none_or(|id| id == device.vendor_id)
+            && self.product_id.is_none_or(|id| id == device.product_id)
+            && self.usage_page.is_none_or(|page| page == device.usage_page)
+            && self.usage_id.is_none_or(|id| id == device.usage_id)
+    }
+}
+
+/// Picks one device out of several that match a [`DeviceFilter`], for hosts
+/// with more than one identical UPS attached
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// The only matching device. More than one is an error.
+    #[default]
+    Any,
+    SerialNumber(String),
+    Path(String),
+    /// Position in the list returned by [`enumerate`], counting from 0
+    Ordinal(usize),
+}
+
+impl DeviceSelector {
+    /// The index of the one device in `devices` this selects
+    pub fn select(&self, devices: &[DeviceInfo]) -> Result<usize> {
+        let total = devices.len();
+
+        let indices = 0..total;
+        let mut matching: Vec<_> = match self {
+            Self::Any => indices.collect(),
+            Self::SerialNumber(serial_number) => indices
+                .filter(|&index| devices[index].serial_number.as_ref() == Some(serial_number))
+                .collect(),
+            // Device interface paths are case-insensitive on Windows
+            Self::Path(path) => indices
+                .filter(|&index| devices[index].path.eq_ignore_ascii_case(path))
+                .collect(),
+            Self::Ordinal(ordinal) => indices.skip(*ordinal).take(1).collect(),
+        };
+
+        match matching.len() {
+            1 => Ok(matching.pop().unwrap()),
+            0 if total == 0 => {
+                Err(UpsError::DeviceNotFound("No matching device found".to_string()).into())
+            }
+            0 => Err(UpsError::DeviceNotFound(format!(
+                "None of the {} matching devices has {}",
+                total, self
+            ))
+            .into()),
+            count => Err(UpsError::Ambiguous(format!(
+                "{} devices match{}, select one by serial number, path or ordinal",
+                count,
+                match self {
+                    Self::Any => "".to_string(),
+                    _ => format!(" {}", self),
+                }
+            ))
+            .into()),
+        }
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "any device"),
+            Self::SerialNumber(serial_number) => write!(f, "serial number {}", serial_number),
+            Self::Path(path) => write!(f, "path {}", path),
+            Self::Ordinal(ordinal) => write!(f, "ordinal {}", ordinal),
+        }
+    }
+}
+
+/// List the HID devices matching `filter`, using the platform's backend
+pub async fn enumerate(filter: &DeviceFilter) -> Result<Vec<DeviceInfo>> {
+    #[cfg(windows)]
+    return crate::hid_device::HidDevice::enumerate(filter).await;
+
+    #[cfg(target_os = "linux")]
+    return crate::hidraw_device::HidrawDevice::enumerate(filter).await;
+
+    #[cfg(not(any(windows, target_os = "linux")))]
+    {
+        let _ = filter;
+        Err(UpsError::Unsupported("No HID backend for this platform".to_string()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(path: &str, serial_number: Option<&str>) -> DeviceInfo {
+        DeviceInfo {
+            path: path.to_string(),
+            vendor_id: 0x0665,
+            product_id: 0x5161,
+            usage_page: 0xFF00,
+            usage_id: 0x0001,
+            manufacturer: None,
+            product: None,
+            serial_number: serial_number.map(str::to_string),
+        }
+    }
+
+    fn two_identical_upses() -> Vec<DeviceInfo> {
+        vec![
+            device("/dev/hidraw1", Some("A1")),
+            device("/dev/hidraw2", Some("B2")),
+        ]
+    }
+
+    fn select(selector: DeviceSelector, devices: &[DeviceInfo]) -> Result<&DeviceInfo> {
+        Ok(&devices[selector.select(devices)?])
+    }
+
+    #[test]
+    fn any_needs_exactly_one_device() {
+        assert_eq!(
+            select(DeviceSelector::Any, &[device("/dev/hidraw1", None)])
+                .unwrap()
+                .path,
+            "/dev/hidraw1"
+        );
+
+        let error = select(DeviceSelector::Any, &two_identical_upses()).unwrap_err();
+        assert!(error.to_string().contains("2 devices match"));
+        assert!(matches!(UpsError::of(&error), Some(UpsError::Ambiguous(_))));
+
+        let error = select(DeviceSelector::Any, &Vec::new()).unwrap_err();
+        assert!(matches!(
+            UpsError::of(&error),
+            Some(UpsError::DeviceNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn selects_by_serial_number_path_and_ordinal() {
+        let devices = two_identical_upses();
+
+        let selected = select(DeviceSelector::SerialNumber("B2".to_string()), &devices).unwrap();
+        assert_eq!(selected.path, "/dev/hidraw2");
+
+        let selected = select(DeviceSelector::Path("/dev/hidraw2".to_string()), &devices).unwrap();
+        assert_eq!(selected.serial_number.as_deref(), Some("B2"));
+
+        let selected = select(DeviceSelector::Ordinal(0), &devices).unwrap();
+        assert_eq!(selected.serial_number.as_deref(), Some("A1"));
+    }
+
+    #[test]
+    fn unmatched_selector_is_an_error() {
+        let error = select(
+            DeviceSelector::SerialNumber("C3".to_string()),
+            &two_identical_upses(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("serial number C3"));
+        assert!(matches!(
+            UpsError::of(&error),
+            Some(UpsError::DeviceNotFound(_))
+        ));
+
+        assert!(select(DeviceSelector::Ordinal(2), &two_identical_upses()).is_err());
+    }
+
+    #[test]
+    fn duplicate_serial_numbers_are_ambiguous() {
+        let devices = vec![
+            device("/dev/hidraw1", Some("0")),
+            device("/dev/hidraw2", Some("0")),
+        ];
+
+        let error = select(DeviceSelector::SerialNumber("0".to_string()), &devices).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("2 devices match serial number 0"));
+    }
+}
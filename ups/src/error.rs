@@ -0,0 +1,104 @@
+use std::fmt;
+
+/// The failures callers may want to tell apart.
+///
+/// Drivers and transports still return [`anyhow::Result`], but raise these for
+/// anything policy code can act on. Get them back out with [`UpsError::of`],
+/// which also looks through any context added on the way up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpsError {
+    /// The UPS didn't respond in time. Holds what was being waited for.
+    Timeout(String),
+    /// The device went away, or a read or write to it was cut short
+    Disconnected(String),
+    /// A response was garbled, cut short or not properly delimited
+    Framing(String),
+    /// A response failed its CRC check, so its contents can't be trusted
+    ChecksumMismatch {
+        expected: u16,
+        actual: u16,
+    },
+    /// A field of a response didn't parse. Holds the field, its value and why.
+    InvalidField {
+        field: &'static str,
+        value: String,
+        reason: String,
+    },
+    UnexpectedReportId {
+        expected: u8,
+        actual: u8,
+    },
+    /// The device, transport or protocol can't do what was asked
+    Unsupported(String),
+    /// The UPS refused the command it holds
+    Nak(String),
+    /// No device matches. Holds what was looked for.
+    DeviceNotFound(String),
+    /// More than one device matches, so which one to use is unclear
+    Ambiguous(String),
+}
+
+impl UpsError {
+    /// Find the `UpsError` behind `error`, if any
+    pub fn of(error: &anyhow::Error) -> Option<&UpsError> {
+        error.chain().find_map(|cause| cause.downcast_ref())
+    }
+}
+
+impl fmt::Display for UpsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpsError::Timeout(operation) => write!(f, "{} timed-out", operation),
+            UpsError::Disconnected(message) => write!(f, "{}", message),
+            UpsError::Framing(message) => write!(f, "{}", message),
+            UpsError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Response CRC {:04X} does not match the computed {:04X}",
+                actual, expected
+            ),
+            UpsError::InvalidField {
+                field,
+                value,
+                reason,
+            } => write!(f, "Invalid {} {:?}: {}", field, value, reason),
+            UpsError::UnexpectedReportId { expected, actual } => write!(
+                f,
+                "Unexpected HID report ID {}, expected {}",
+                actual, expected
+            ),
+            UpsError::Unsupported(message) => write!(f, "{}", message),
+            UpsError::Nak(command) => write!(f, "UPS refused {}", command),
+            UpsError::DeviceNotFound(message) => write!(f, "{}", message),
+            UpsError::Ambiguous(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for UpsError {}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn found_through_context() {
+        let error = Err::<(), _>(UpsError::Nak("PEa".to_string()))
+            .context("Enabling the beeper")
+            .unwrap_err();
+
+        assert_eq!(
+            UpsError::of(&error),
+            Some(&UpsError::Nak("PEa".to_string()))
+        );
+        assert_eq!(error.root_cause().to_string(), "UPS refused PEa");
+    }
+
+    #[test]
+    fn other_errors_have_no_kind() {
+        let error = anyhow::anyhow!("Something else");
+
+        assert_eq!(UpsError::of(&error), None);
+    }
+}
@@ -0,0 +1,258 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::time::timeout;
+
+use crate::{error::UpsError, transport::Transport};
+
+const REPORT_ID: u8 = 0;
+
+const TERMINATOR: char = '\r';
+
+const SEND_TIMEOUT_MS: u64 = 1000;
+const RECEIVE_TIMEOUT_MS: u64 = 250;
+const RECEIVE_TOTAL_TIMEOUT_MS: u64 = 2400;
+
+/// What to do about the CRC-16/XMODEM some Voltronic firmware appends to its
+/// responses, just before the terminator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CrcPolicy {
+    /// Leave responses as they are
+    Off,
+    /// Check and strip a CRC when the response carries one. Responses without
+    /// one pass as they are.
+    #[default]
+    Optional,
+    /// Every response must carry a valid CRC. Commands are sent with one too.
+    Required,
+}
+
+/// Send a command and read back the response, without the terminator or CRC.
+///
+/// The response stays as bytes, as it may not be valid UTF-8.
+pub(crate) async fn transact_command<T: Transport + ?Sized>(
+    device: &T,
+    command: &str,
+    crc: CrcPolicy,
+) -> Result<Vec<u8>> {
+    send_command(device, command, crc).await?;
+    read_response(device, crc).await
+}
+
+pub(crate) async fn send_command<T: Transport + ?Sized>(
+    device: &T,
+    command: &str,
+    crc: CrcPolicy,
+) -> Result<()> {
+    assert!(TERMINATOR.is_ascii());
+
+    let mut command = command.as_bytes().to_vec();
+    if crc == CrcPolicy::Required {
+        command.extend(crc16_xmodem(&command).to_be_bytes());
+    }
+    command.push(TERMINATOR as u8);
+
+    let future = device.send_output_report(REPORT_ID, &command);
+    let future = timeout(Duration::from_millis(SEND_TIMEOUT_MS), future);
+    match future.await {
+        Ok(result) => result?,
+        Err(_) => return Err(UpsError::Timeout("Sending command".to_string()).into()),
+    };
+
+    Ok(())
+}
+
+/// Read a response, without the terminator, checking its CRC as `crc` says
+pub(crate) async fn read_response<T: Transport + ?Sized>(
+    device: &T,
+    crc: CrcPolicy,
+) -> Result<Vec<u8>> {
+    let future = read_all_response_packets(device);
+    let future = timeout(Duration::from_millis(RECEIVE_TOTAL_TIMEOUT_MS), future);
+    let mut response = match future.await {
+        Ok(result) => result?,
+        Err(_) => return Err(UpsError::Timeout("Receiving response".to_string()).into()),
+    };
+
+    let end = response
+        .iter()
+        .position(|&byte| byte == TERMINATOR as u8)
+        .unwrap();
+    response.truncate(end);
+
+    let length = check_crc(&response, crc)?.len();
+    response.truncate(length);
+
+    Ok(response)
+}
+
+/// CRC-16/XMODEM, as Voltronic UPSes append it to some responses.
+///
+/// The UPS bumps any CRC byte that would read as `(`, CR or LF by one, so that
+/// it can't be mistaken for framing. This does the same.
+pub(crate) fn crc16_xmodem(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    let [high, low] = crc.to_be_bytes();
+    let escape = |byte: u8| match byte {
+        b'(' | b'\r' | b'\n' => byte + 1,
+        byte => byte,
+    };
+    u16::from_be_bytes([escape(high), escape(low)])
+}
+
+/// Check the CRC at the end of `response` as `policy` says, returning the
+/// response without it
+pub(crate) fn check_crc(response: &[u8], policy: CrcPolicy) -> Result<&[u8]> {
+    if policy == CrcPolicy::Off {
+        return Ok(response);
+    }
+
+    if response.len() < 3 {
+        return match policy {
+            CrcPolicy::Required => Err(UpsError::Framing(format!(
+                "Response too short to carry a CRC: {:?}",
+                String::from_utf8_lossy(response)
+            ))
+            .into()),
+            _ => Ok(response),
+        };
+    }
+
+    let (data, crc) = response.split_at(response.len() - 2);
+    let expected = crc16_xmodem(data);
+    let actual = u16::from_be_bytes([crc[0], crc[1]]);
+    if actual == expected {
+        return Ok(data);
+    }
+
+    // Responses are printable ASCII, so anything else in the last two bytes
+    // can only be a CRC, and a wrong one
+    let has_crc = crc.iter().any(|byte| !(b' '..=b'~').contains(byte));
+    if policy == CrcPolicy::Required || has_crc {
+        return Err(UpsError::ChecksumMismatch { expected, actual }.into());
+    }
+
+    Ok(response)
+}
+
+async fn read_all_response_packets<T: Transport + ?Sized>(device: &T) -> Result<Vec<u8>> {
+    assert!(TERMINATOR.is_ascii());
+
+    let mut response: Vec<u8> = Vec::new();
+    loop {
+        let packet = read_single_response_packet(device).await?;
+
+        response.extend(&packet);
+
+        if packet
+            .iter()
+            .find(|&&elem| elem == TERMINATOR as u8)
+            .is_some()
+        {
+            break;
+        }
+    }
+
+    Ok(response)
+}
+
+async fn read_single_response_packet<T: Transport + ?Sized>(device: &T) -> Result<Vec<u8>> {
+    let future = device.read_input_report();
+    let future = timeout(Duration::from_millis(RECEIVE_TIMEOUT_MS), future);
+    let (report_id, report) = match future.await {
+        Ok(result) => result?,
+        Err(_) => return Err(UpsError::Timeout("Receiving response".to_string()).into()),
+    };
+
+    if report_id != REPORT_ID {
+        return Err(UpsError::UnexpectedReportId {
+            expected: REPORT_ID,
+            actual: report_id,
+        }
+        .into());
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_xmodem_check_value() {
+        assert_eq!(crc16_xmodem(b"123456789"), 0x31C3);
+    }
+
+    fn with_crc(data: &[u8]) -> Vec<u8> {
+        let mut response = data.to_vec();
+        response.extend(crc16_xmodem(data).to_be_bytes());
+        response
+    }
+
+    #[test]
+    fn crc_is_escaped() {
+        // "QPIGS" is the classic example, with a CRC of 0xB7A9
+        assert_eq!(crc16_xmodem(b"QPIGS"), 0xB7A9);
+
+        for data in 0..=u16::MAX {
+            let [high, low] = crc16_xmodem(&data.to_be_bytes()).to_be_bytes();
+            assert!(![b'(', b'\r', b'\n'].contains(&high));
+            assert!(![b'(', b'\r', b'\n'].contains(&low));
+        }
+    }
+
+    #[test]
+    fn valid_crc_is_stripped() {
+        for policy in [CrcPolicy::Optional, CrcPolicy::Required] {
+            assert_eq!(check_crc(&with_crc(b"(ACK"), policy).unwrap(), b"(ACK");
+        }
+        assert_eq!(
+            check_crc(&with_crc(b"(ACK"), CrcPolicy::Off).unwrap(),
+            with_crc(b"(ACK")
+        );
+    }
+
+    #[test]
+    fn missing_crc() {
+        assert_eq!(check_crc(b"(ACK", CrcPolicy::Optional).unwrap(), b"(ACK");
+        assert_eq!(check_crc(b"(ACK", CrcPolicy::Off).unwrap(), b"(ACK");
+
+        let error = check_crc(b"(ACK", CrcPolicy::Required).unwrap_err();
+        assert!(matches!(
+            UpsError::of(&error),
+            Some(UpsError::ChecksumMismatch { .. })
+        ));
+
+        let error = check_crc(b"(", CrcPolicy::Required).unwrap_err();
+        assert!(matches!(UpsError::of(&error), Some(UpsError::Framing(_))));
+    }
+
+    #[test]
+    fn corrupted_response_is_a_mismatch() {
+        let mut response = with_crc(b"(230.0 49.9");
+        response[3] = b'8';
+
+        for policy in [CrcPolicy::Optional, CrcPolicy::Required] {
+            let error = check_crc(&response, policy).unwrap_err();
+            assert_eq!(
+                UpsError::of(&error),
+                Some(&UpsError::ChecksumMismatch {
+                    expected: crc16_xmodem(b"(238.0 49.9"),
+                    actual: crc16_xmodem(b"(230.0 49.9"),
+                })
+            );
+        }
+    }
+}
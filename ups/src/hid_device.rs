@@ -1,6 +1,7 @@
 use std::convert::TryInto;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
 use windows::{
     core::Interface,
     Devices::{
@@ -12,13 +13,22 @@ use windows::{
 };
 
 use crate::util::slice_to_ibuffer;
-use crate::{hid_util::HidInfo, util::ioctl_number_to_class};
+use crate::{
+    device::{DeviceFilter, DeviceInfo, DeviceSelector},
+    error::UpsError,
+    hid_util::HidInfo,
+    report_descriptor::ReportDescriptor,
+    transport::Transport,
+    util::ioctl_number_to_class,
+};
 
 #[derive(Debug)]
 pub struct HidDevice {
     device: CustomDevice,
+    device_id: String,
     input_report_size: usize,
     output_report_size: usize,
+    feature_report_size: usize,
 }
 
 impl HidDevice {
@@ -28,52 +38,94 @@ impl HidDevice {
         vendor_id: u16,
         product_id: u16,
     ) -> Result<Self> {
-        let devices = Self::get_devices(usage_page, usage_id, vendor_id, product_id).await?;
-        assert_eq!(devices.Size()?, 1);
+        let filter = DeviceFilter {
+            vendor_id: Some(vendor_id),
+            product_id: Some(product_id),
+            usage_page,
+            usage_id,
+        };
+
+        Self::open(&filter, &DeviceSelector::Any).await
+    }
 
-        let device_id: String = devices.GetAt(0)?.Id()?.try_into().unwrap();
+    pub async fn open(filter: &DeviceFilter, selector: &DeviceSelector) -> Result<Self> {
+        let mut devices = Self::enumerate(filter).await?;
+        let device_id = devices.swap_remove(selector.select(&devices)?).path;
 
         let caps = HidInfo::new(&device_id)?.preparsed_data()?.caps()?;
         let input_report_size = caps.InputReportByteLength;
         let output_report_size = caps.OutputReportByteLength;
+        let feature_report_size = caps.FeatureReportByteLength;
 
         let device = Self::open_device(&device_id).await?;
 
-        return Ok(HidDevice {
+        Ok(HidDevice {
             device,
+            device_id,
             input_report_size: input_report_size.into(),
             output_report_size: output_report_size.into(),
-        });
+            feature_report_size: feature_report_size.into(),
+        })
     }
 
-    async fn get_devices(
-        usage_page: Option<u16>,
-        usage_id: Option<u16>,
-        vendor_id: u16,
-        product_id: u16,
-    ) -> Result<DeviceInformationCollection> {
-        let selector = format!(
-            concat!(
-                "System.Devices.InterfaceClassGuid:=\"{{4D1E55B2-F16F-11CF-88CB-001111000030}}\"",
-                " AND System.Devices.InterfaceEnabled:=System.StructuredQueryType.Boolean#True",
-                "{}",
-                "{}",
-                " AND System.DeviceInterface.Hid.VendorId:={}",
-                " AND System.DeviceInterface.Hid.ProductId:={}"
-            ),
-            if let Some(usage_page) = usage_page {
-                format!(" AND System.DeviceInterface.Hid.UsagePage:={}", usage_page)
-            } else {
-                "".to_string()
-            },
-            if let Some(usage_id) = usage_id {
-                format!(" AND System.DeviceInterface.Hid.UsageId:={}", usage_id)
-            } else {
-                "".to_string()
-            },
-            vendor_id,
-            product_id
-        );
+    pub async fn enumerate(filter: &DeviceFilter) -> Result<Vec<DeviceInfo>> {
+        let mut result = Vec::new();
+
+        for device in Self::get_devices(filter).await? {
+            let path: String = device.Id()?.try_into()?;
+
+            // Devices can disappear between the query and now, so skip any
+            // that can no longer be queried
+            let info = match HidInfo::new(&path) {
+                Ok(info) => info,
+                Err(_) => continue,
+            };
+            let attributes = match info.attributes() {
+                Ok(attributes) => attributes,
+                Err(_) => continue,
+            };
+            let caps = match info.preparsed_data().and_then(|data| data.caps()) {
+                Ok(caps) => caps,
+                Err(_) => continue,
+            };
+
+            result.push(DeviceInfo {
+                vendor_id: attributes.VendorID,
+                product_id: attributes.ProductID,
+                usage_page: caps.UsagePage,
+                usage_id: caps.Usage,
+                manufacturer: info.manufacturer_string().ok(),
+                product: info.product_string().ok(),
+                serial_number: info.serial_number_string().ok(),
+                path,
+            });
+        }
+
+        // Keep ordinals stable from one enumeration to the next
+        result.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(result)
+    }
+
+    async fn get_devices(filter: &DeviceFilter) -> Result<DeviceInformationCollection> {
+        let mut selector = concat!(
+            "System.Devices.InterfaceClassGuid:=\"{4D1E55B2-F16F-11CF-88CB-001111000030}\"",
+            " AND System.Devices.InterfaceEnabled:=System.StructuredQueryType.Boolean#True",
+        )
+        .to_string();
+
+        if let Some(usage_page) = filter.usage_page {
+            selector += &format!(" AND System.DeviceInterface.Hid.UsagePage:={}", usage_page);
+        }
+        if let Some(usage_id) = filter.usage_id {
+            selector += &format!(" AND System.DeviceInterface.Hid.UsageId:={}", usage_id);
+        }
+        if let Some(vendor_id) = filter.vendor_id {
+            selector += &format!(" AND System.DeviceInterface.Hid.VendorId:={}", vendor_id);
+        }
+        if let Some(product_id) = filter.product_id {
+            selector += &format!(" AND System.DeviceInterface.Hid.ProductId:={}", product_id);
+        }
 
         Ok(DeviceInformation::FindAllAsyncAqsFilter(&selector.into())?.await?)
     }
@@ -87,48 +139,27 @@ impl HidDevice {
         Ok(future.await?)
     }
 
-    pub async fn send_output_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
-        let report = self.create_output_report(report_id, data)?;
-
-        let future = {
-            let report_buffer = slice_to_ibuffer(&report)?;
-            self.device.OutputStream()?.WriteAsync(&report_buffer)?
-        };
-        let written = future.await?;
-        assert_eq!(written, self.output_report_size.try_into().unwrap());
-
-        Ok(())
+    fn create_output_report(&self, report_id: u8, data: &[u8]) -> Result<Vec<u8>> {
+        Self::create_report(self.output_report_size, report_id, data)
     }
 
-    fn create_output_report(&self, report_id: u8, data: &[u8]) -> Result<Vec<u8>> {
-        assert!(self.output_report_size >= 1);
-        if data.len() > self.output_report_size - 1 {
+    fn create_report(size: usize, report_id: u8, data: &[u8]) -> Result<Vec<u8>> {
+        if size < 1 {
+            bail!(UpsError::Unsupported(
+                "Device has no reports of this kind".to_string()
+            ));
+        }
+        if data.len() > size - 1 {
             return Err(anyhow!("Supplied data does not fit in report"));
         }
 
-        let mut report = vec![0u8; self.output_report_size];
+        let mut report = vec![0u8; size];
         report[0] = report_id;
         report[1..data.len() + 1].copy_from_slice(data);
 
         Ok(report)
     }
 
-    pub async fn read_input_report(&self) -> Result<(u8, Vec<u8>)> {
-        let reader = DataReader::CreateDataReader(&self.device.InputStream()?)?;
-
-        let future = reader.LoadAsync(self.input_report_size.try_into().unwrap())?;
-        future.await?;
-
-        assert!(self.input_report_size >= 1);
-
-        let report_id = reader.ReadByte()?;
-
-        let mut report = vec![0u8; self.input_report_size - 1];
-        reader.ReadBytes(&mut report)?;
-
-        Ok((report_id, report))
-    }
-
     pub async fn io_control(
         &self,
         control_code: u32,
@@ -136,7 +167,17 @@ impl HidDevice {
         output_buffer: Option<&mut [u8]>,
     ) -> Result<u32> {
         let output_ibuffer = if let Some(output_buffer) = &output_buffer {
-            Some(Buffer::Create(output_buffer.len().try_into().unwrap())?)
+            let length = output_buffer.len().try_into()?;
+            let buffer = Buffer::Create(length)?;
+
+            // Some IOCTLs take arguments in the output buffer, like the
+            // report ID of IOCTL_HID_GET_FEATURE
+            Self::with_buffer_bytes(&buffer.cast()?, |bytes| {
+                bytes.copy_from_slice(output_buffer)
+            })?;
+            buffer.SetLength(length)?;
+
+            Some(buffer)
         } else {
             None
         };
@@ -144,10 +185,7 @@ impl HidDevice {
         let result = {
             let future = self.device.SendIOControlAsync(
                 &ioctl_number_to_class(control_code)?,
-                input_buffer
-                    .map(|input| slice_to_ibuffer(input))
-                    .transpose()?
-                    .as_ref(),
+                input_buffer.map(slice_to_ibuffer).transpose()?.as_ref(),
                 output_ibuffer
                     .as_ref()
                     .map(|buffer| buffer.cast::<IBuffer>())
@@ -159,26 +197,76 @@ impl HidDevice {
 
         if let Some(output_buffer) = output_buffer {
             let output_ibuffer = output_ibuffer.unwrap().cast::<IBuffer>()?;
+            Self::with_buffer_bytes(&output_ibuffer, |bytes| {
+                output_buffer.copy_from_slice(bytes)
+            })?;
+        }
 
-            let byte_access = Buffer::CreateMemoryBufferOverIBuffer(&output_ibuffer)?
-                .CreateReference()?
-                .cast::<IMemoryBufferByteAccess>()?;
+        Ok(result)
+    }
 
-            unsafe {
-                let mut data = std::ptr::null_mut();
-                let mut len = 0;
-                byte_access.GetBuffer(&mut data, &mut len)?;
+    /// Gives `f` the whole capacity of `buffer` as a slice
+    fn with_buffer_bytes<R>(buffer: &IBuffer, f: impl FnOnce(&mut [u8]) -> R) -> Result<R> {
+        let reference = Buffer::CreateMemoryBufferOverIBuffer(buffer)?.CreateReference()?;
+        let byte_access = reference.cast::<IMemoryBufferByteAccess>()?;
 
-                let bytes = std::slice::from_raw_parts(data, len.try_into().unwrap());
+        unsafe {
+            let mut data = std::ptr::null_mut();
+            let mut len = 0;
+            byte_access.GetBuffer(&mut data, &mut len)?;
 
-                output_buffer.copy_from_slice(bytes);
-            };
+            let bytes = std::slice::from_raw_parts_mut(data, len.try_into()?);
+
+            Ok(f(bytes))
         }
+    }
+}
 
-        Ok(result)
+#[async_trait]
+impl Transport for HidDevice {
+    async fn send_output_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        let report = self.create_output_report(report_id, data)?;
+
+        let future = {
+            let report_buffer = slice_to_ibuffer(&report)?;
+            self.device.OutputStream()?.WriteAsync(&report_buffer)?
+        };
+        let written: usize = future.await?.try_into()?;
+        if written != report.len() {
+            bail!(UpsError::Disconnected(
+                "Short write to HID device".to_string()
+            ));
+        }
+
+        Ok(())
     }
 
-    pub async fn get_indexed_string(&self, index: u32) -> Result<String> {
+    async fn read_input_report(&self) -> Result<(u8, Vec<u8>)> {
+        if self.input_report_size < 1 {
+            bail!(UpsError::Unsupported(
+                "Device has no input reports".to_string()
+            ));
+        }
+
+        let reader = DataReader::CreateDataReader(&self.device.InputStream()?)?;
+
+        let future = reader.LoadAsync(self.input_report_size.try_into()?)?;
+        let loaded: usize = future.await?.try_into()?;
+        if loaded != self.input_report_size {
+            bail!(UpsError::Disconnected(
+                "Short read from HID device".to_string()
+            ));
+        }
+
+        let report_id = reader.ReadByte()?;
+
+        let mut report = vec![0u8; self.input_report_size - 1];
+        reader.ReadBytes(&mut report)?;
+
+        Ok((report_id, report))
+    }
+
+    async fn get_indexed_string(&self, index: u32) -> Result<String> {
         // https://docs.microsoft.com/en-us/windows-hardware/drivers/ddi/hidclass/ni-hidclass-ioctl_hid_get_indexed_string
 
         const IOCTL_HID_GET_INDEXED_STRING: u32 = 0x000B01E2;
@@ -193,14 +281,61 @@ impl HidDevice {
             )
             .await?;
 
-        let output: Vec<_> = output[..returned.try_into().unwrap()]
+        let output: Vec<_> = output[..returned.try_into()?]
             .chunks_exact(std::mem::size_of::<u16>())
             .map(|chunk| u16::from_le_bytes(chunk.try_into().unwrap()))
             .collect();
 
         // Output must contain at least a null-terminator
-        assert_eq!(output.last().unwrap(), &0);
+        match output.split_last() {
+            Some((0, string)) => Ok(String::from_utf16_lossy(string)),
+            _ => Err(UpsError::Framing("Indexed string is not null-terminated".to_string()).into()),
+        }
+    }
+
+    async fn get_feature_report(&self, report_id: u8) -> Result<Vec<u8>> {
+        // https://docs.microsoft.com/en-us/windows-hardware/drivers/ddi/hidclass/ni-hidclass-ioctl_hid_get_feature
+
+        const IOCTL_HID_GET_FEATURE: u32 = 0x000B0192;
+
+        let mut report = Self::create_report(self.feature_report_size, report_id, &[])?;
+        let returned: usize = self
+            .io_control(IOCTL_HID_GET_FEATURE, None, Some(&mut report))
+            .await?
+            .try_into()?;
+        if returned < 1 || returned > report.len() {
+            bail!(UpsError::Framing(format!(
+                "Unexpected feature report length {}",
+                returned
+            )));
+        }
+
+        report.truncate(returned);
+        report.remove(0);
+
+        Ok(report)
+    }
+
+    async fn send_feature_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        // https://docs.microsoft.com/en-us/windows-hardware/drivers/ddi/hidclass/ni-hidclass-ioctl_hid_set_feature
+
+        const IOCTL_HID_SET_FEATURE: u32 = 0x000B0191;
+
+        let report = Self::create_report(self.feature_report_size, report_id, data)?;
+        self.io_control(IOCTL_HID_SET_FEATURE, Some(&report), None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn report_descriptor(&self) -> Result<Vec<u8>> {
+        // User mode only gets the preparsed form of the descriptor, so this
+        // is rebuilt from it. It describes the same data fields, but not
+        // padding or array buttons.
+        let fields = HidInfo::new(&self.device_id)?
+            .preparsed_data()?
+            .report_fields()?;
 
-        Ok(String::from_utf16_lossy(&output[..output.len() - 1]))
+        Ok(ReportDescriptor::encode(&fields))
     }
 }
@@ -0,0 +1,830 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+
+use crate::{
+    error::UpsError,
+    report_descriptor::{ReportDescriptor, ReportKind, Usage},
+    transport::Transport,
+    ups::{
+        wait_for_beeper, SelfTest, SelfTestHandle, Ups, UpsCapabilities, UpsInfo, UpsRatings,
+        UpsStatus, UpsStatusFlags,
+    },
+};
+
+// https://www.usb.org/sites/default/files/pdcv11.pdf, sections 4.1 and 4.2
+const POWER_DEVICE: u16 = 0x84;
+const BATTERY_SYSTEM: u16 = 0x85;
+
+const PRESENT_STATUS: Usage = Usage::new(POWER_DEVICE, 0x02);
+const BATTERY: Usage = Usage::new(POWER_DEVICE, 0x12);
+const INPUT: Usage = Usage::new(POWER_DEVICE, 0x1A);
+const OUTPUT: Usage = Usage::new(POWER_DEVICE, 0x1C);
+const POWER_SUMMARY: Usage = Usage::new(POWER_DEVICE, 0x24);
+const VOLTAGE: Usage = Usage::new(POWER_DEVICE, 0x30);
+const FREQUENCY: Usage = Usage::new(POWER_DEVICE, 0x32);
+const PERCENT_LOAD: Usage = Usage::new(POWER_DEVICE, 0x35);
+const TEMPERATURE: Usage = Usage::new(POWER_DEVICE, 0x36);
+const CONFIG_VOLTAGE: Usage = Usage::new(POWER_DEVICE, 0x40);
+const CONFIG_CURRENT: Usage = Usage::new(POWER_DEVICE, 0x41);
+const CONFIG_FREQUENCY: Usage = Usage::new(POWER_DEVICE, 0x42);
+const CONFIG_APPARENT_POWER: Usage = Usage::new(POWER_DEVICE, 0x43);
+const DELAY_BEFORE_STARTUP: Usage = Usage::new(POWER_DEVICE, 0x56);
+const DELAY_BEFORE_SHUTDOWN: Usage = Usage::new(POWER_DEVICE, 0x57);
+const TEST: Usage = Usage::new(POWER_DEVICE, 0x58);
+const INTERNAL_FAILURE: Usage = Usage::new(POWER_DEVICE, 0x62);
+const SHUTDOWN_IMMINENT: Usage = Usage::new(POWER_DEVICE, 0x69);
+const BOOST: Usage = Usage::new(POWER_DEVICE, 0x6E);
+const BUCK: Usage = Usage::new(POWER_DEVICE, 0x6F);
+const I_MANUFACTURER: Usage = Usage::new(POWER_DEVICE, 0xFD);
+const I_PRODUCT: Usage = Usage::new(POWER_DEVICE, 0xFE);
+const I_SERIAL_NUMBER: Usage = Usage::new(POWER_DEVICE, 0xFF);
+
+const BELOW_REMAINING_CAPACITY_LIMIT: Usage = Usage::new(BATTERY_SYSTEM, 0x42);
+const DISCHARGING: Usage = Usage::new(BATTERY_SYSTEM, 0x45);
+const AUDIBLE_ALARM_CONTROL: Usage = Usage::new(BATTERY_SYSTEM, 0x5A);
+const REMAINING_CAPACITY: Usage = Usage::new(BATTERY_SYSTEM, 0x66);
+const RUN_TIME_TO_EMPTY: Usage = Usage::new(BATTERY_SYSTEM, 0x68);
+const AC_PRESENT: Usage = Usage::new(BATTERY_SYSTEM, 0xD0);
+
+const ALARM_DISABLED: i32 = 1;
+const ALARM_ENABLED: i32 = 2;
+
+// Test is written with these, and reads back the outcome
+const TEST_QUICK: i32 = 1;
+const TEST_DEEP: i32 = 2;
+const TEST_ABORT: i32 = 3;
+const TEST_IN_PROGRESS: i32 = 5;
+
+const KELVIN_OFFSET: f64 = 273.15;
+
+/// A UPS that follows the USB HID Power Device Class, as most APC, Eaton and
+/// CyberPower units do.
+///
+/// Everything is read from and written to feature reports, located through
+/// the device's report descriptor. Values the device doesn't report come back
+/// as NaN or `None`, like unparseable fields of the string protocols.
+#[derive(Debug)]
+pub struct HidPowerDeviceUps<T: Transport> {
+    device: T,
+    descriptor: ReportDescriptor,
+}
+
+/// Feature reports fetched so far during one status query, by report ID
+type ReportCache = HashMap<u8, Vec<u8>>;
+
+impl<T: Transport> HidPowerDeviceUps<T> {
+    pub async fn new(device: T) -> Result<Self> {
+        let descriptor = ReportDescriptor::parse(&device.report_descriptor().await?)?;
+
+        if !is_power_device(&descriptor) {
+            bail!(UpsError::Unsupported("Not a HID Power Device".to_string()));
+        }
+
+        Ok(Self { device, descriptor })
+    }
+
+    /// Read the raw logical value at `path`, or `None` if the device doesn't
+    /// have it
+    async fn read_raw(&self, reports: &mut ReportCache, path: &[Usage]) -> Result<Option<i32>> {
+        let field = match self.descriptor.find(ReportKind::Feature, path) {
+            Some(field) => field,
+            None => return Ok(None),
+        };
+
+        let report = match reports.entry(field.report_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(self.device.get_feature_report(field.report_id).await?)
+            }
+        };
+
+        Ok(Some(field.extract(report, 0)?))
+    }
+
+    /// Read the value at `path` in physical units
+    async fn read(&self, reports: &mut ReportCache, path: &[Usage]) -> Result<Option<f64>> {
+        let field = match self.descriptor.find(ReportKind::Feature, path) {
+            Some(field) => field,
+            None => return Ok(None),
+        };
+
+        Ok(self
+            .read_raw(reports, path)
+            .await?
+            .map(|value| field.to_physical(value)))
+    }
+
+    /// Read the string whose index is stored at `usage`, if the device has
+    /// one there and the transport can fetch it
+    async fn read_string(&self, reports: &mut ReportCache, usage: Usage) -> Result<Option<String>> {
+        let index = match self.read_raw(reports, &[usage]).await? {
+            // Index 0 means no string
+            Some(index) if index > 0 => index.try_into()?,
+            _ => return Ok(None),
+        };
+
+        match self.device.get_indexed_string(index).await {
+            Ok(string) if string.is_empty() => Ok(None),
+            Ok(string) => Ok(Some(string)),
+            // hidraw has no way to fetch strings by index
+            Err(error) if matches!(UpsError::of(&error), Some(UpsError::Unsupported(_))) => {
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Write `value` to the feature field at `path`, keeping the rest of its
+    /// report as the device has it
+    async fn write(&self, path: &[Usage], value: i32) -> Result<()> {
+        let field = self
+            .descriptor
+            .find(ReportKind::Feature, path)
+            .ok_or_else(|| UpsError::Unsupported(format!("UPS has no {:?}", path)))?;
+
+        let mut report = self.device.get_feature_report(field.report_id).await?;
+        field.insert(&mut report, 0, value)?;
+
+        self.device
+            .send_feature_report(field.report_id, &report)
+            .await
+    }
+
+    async fn read_flag(&self, reports: &mut ReportCache, usage: Usage) -> Result<Option<bool>> {
+        Ok(self
+            .read_raw(reports, &[PRESENT_STATUS, usage])
+            .await?
+            .map(|value| value != 0))
+    }
+}
+
+#[async_trait]
+impl<T: Transport> Ups for HidPowerDeviceUps<T> {
+    async fn status(&self) -> Result<UpsStatus> {
+        let mut reports = ReportCache::new();
+
+        let to_f32 = |value: Option<f64>| value.map_or(f32::NAN, |value| value as f32);
+
+        let input_voltage = self.read(&mut reports, &[INPUT, VOLTAGE]).await?;
+        let output_voltage = self.read(&mut reports, &[OUTPUT, VOLTAGE]).await?;
+        let output_frequency = self.read(&mut reports, &[OUTPUT, FREQUENCY]).await?;
+        let output_load_level = self.read(&mut reports, &[PERCENT_LOAD]).await?;
+
+        let battery_voltage = match self.read(&mut reports, &[BATTERY, VOLTAGE]).await? {
+            Some(voltage) => Some(voltage),
+            None => self.read(&mut reports, &[POWER_SUMMARY, VOLTAGE]).await?,
+        };
+
+        // Power devices report temperatures in kelvin
+        let internal_temperature = self
+            .read(&mut reports, &[TEMPERATURE])
+            .await?
+            .map(|kelvin| kelvin - KELVIN_OFFSET);
+
+        let battery_capacity = self.read(&mut reports, &[REMAINING_CAPACITY]).await?;
+        let battery_run_time = self.read(&mut reports, &[RUN_TIME_TO_EMPTY]).await?;
+
+        let mut flags = UpsStatusFlags::empty();
+
+        let utility_fail = match self.read_flag(&mut reports, AC_PRESENT).await? {
+            Some(ac_present) => !ac_present,
+            None => self
+                .read_flag(&mut reports, DISCHARGING)
+                .await?
+                .unwrap_or(false),
+        };
+        flags.set(UpsStatusFlags::UTILITY_FAIL, utility_fail);
+
+        for (usage, flag) in [
+            (BELOW_REMAINING_CAPACITY_LIMIT, UpsStatusFlags::BATTERY_LOW),
+            (SHUTDOWN_IMMINENT, UpsStatusFlags::BATTERY_LOW),
+            (BOOST, UpsStatusFlags::BOOST_OR_BUCK_MODE),
+            (BUCK, UpsStatusFlags::BOOST_OR_BUCK_MODE),
+            (INTERNAL_FAILURE, UpsStatusFlags::UPS_FAULT),
+        ] {
+            if self.read_flag(&mut reports, usage).await? == Some(true) {
+                flags.insert(flag);
+            }
+        }
+
+        if self.read_raw(&mut reports, &[TEST]).await? == Some(TEST_IN_PROGRESS) {
+            flags.insert(UpsStatusFlags::SELF_TEST_IN_PROGRESS);
+        }
+
+        if self
+            .read_raw(&mut reports, &[AUDIBLE_ALARM_CONTROL])
+            .await?
+            == Some(ALARM_ENABLED)
+        {
+            flags.insert(UpsStatusFlags::BEEPER_ACTIVE);
+        }
+
+        // -1 means no shutdown is counting down
+        if let Some(delay) = self
+            .read_raw(&mut reports, &[DELAY_BEFORE_SHUTDOWN])
+            .await?
+        {
+            flags.set(UpsStatusFlags::UPS_SHUTDOWN_ACTIVE, delay >= 0);
+        }
+
+        Ok(UpsStatus {
+            input_voltage: to_f32(input_voltage),
+            input_fault_voltage: f32::NAN,
+            output_voltage: to_f32(output_voltage),
+            output_load_level: output_load_level.map_or(0, |load| load.round() as u32),
+            output_frequency: to_f32(output_frequency),
+            battery_voltage: to_f32(battery_voltage),
+            internal_temperature: to_f32(internal_temperature),
+            battery_capacity: battery_capacity.map(|capacity| capacity.round() as u32),
+            battery_run_time: battery_run_time
+                .filter(|seconds| *seconds >= 0.0)
+                .map(Duration::from_secs_f64),
+            flags,
+            invalid_fields: Vec::new(),
+        })
+    }
+
+    async fn beeper_toggle(&self) -> Result<()> {
+        let field = self
+            .descriptor
+            .find(ReportKind::Feature, &[AUDIBLE_ALARM_CONTROL])
+            .ok_or_else(|| UpsError::Unsupported("UPS has no audible alarm control".to_string()))?;
+
+        let mut report = self.device.get_feature_report(field.report_id).await?;
+        let enabled = field.extract(&report, 0)? == ALARM_ENABLED;
+        field.insert(
+            &mut report,
+            0,
+            if enabled {
+                ALARM_DISABLED
+            } else {
+                ALARM_ENABLED
+            },
+        )?;
+
+        self.device
+            .send_feature_report(field.report_id, &report)
+            .await
+    }
+
+    async fn set_beeper(&self, enabled: bool) -> Result<()> {
+        let value = if enabled {
+            ALARM_ENABLED
+        } else {
+            ALARM_DISABLED
+        };
+        self.write(&[AUDIBLE_ALARM_CONTROL], value).await?;
+
+        wait_for_beeper(
+            || async {
+                let mut reports = ReportCache::new();
+                let value = self
+                    .read_raw(&mut reports, &[AUDIBLE_ALARM_CONTROL])
+                    .await?;
+                Ok(value == Some(ALARM_ENABLED))
+            },
+            enabled,
+        )
+        .await
+    }
+
+    async fn capabilities(&self) -> Result<UpsCapabilities> {
+        let mut capabilities = UpsCapabilities::empty();
+        for (path, capability) in [
+            (&[AUDIBLE_ALARM_CONTROL][..], UpsCapabilities::BEEPER),
+            (&[TEMPERATURE][..], UpsCapabilities::TEMPERATURE),
+            (&[TEST][..], UpsCapabilities::SELF_TEST),
+            (&[DELAY_BEFORE_SHUTDOWN][..], UpsCapabilities::SHUTDOWN),
+            (&[DELAY_BEFORE_STARTUP][..], UpsCapabilities::OUTLET_CONTROL),
+            (&[OUTPUT, CONFIG_VOLTAGE][..], UpsCapabilities::RATINGS),
+            (&[CONFIG_APPARENT_POWER][..], UpsCapabilities::RATINGS),
+        ] {
+            if self.descriptor.find(ReportKind::Feature, path).is_some() {
+                capabilities.insert(capability);
+            }
+        }
+
+        Ok(capabilities)
+    }
+
+    async fn info(&self) -> Result<UpsInfo> {
+        let mut reports = ReportCache::new();
+
+        Ok(UpsInfo {
+            manufacturer: self.read_string(&mut reports, I_MANUFACTURER).await?,
+            model: self.read_string(&mut reports, I_PRODUCT).await?,
+            firmware_version: None,
+            serial_number: self.read_string(&mut reports, I_SERIAL_NUMBER).await?,
+        })
+    }
+
+    async fn ratings(&self) -> Result<UpsRatings> {
+        let mut reports = ReportCache::new();
+
+        let to_f32 = |value: Option<f64>| value.map(|value| value as f32);
+
+        let battery_voltage = match self.read(&mut reports, &[BATTERY, CONFIG_VOLTAGE]).await? {
+            Some(voltage) => Some(voltage),
+            None => {
+                self.read(&mut reports, &[POWER_SUMMARY, CONFIG_VOLTAGE])
+                    .await?
+            }
+        };
+
+        Ok(UpsRatings {
+            voltage: to_f32(self.read(&mut reports, &[OUTPUT, CONFIG_VOLTAGE]).await?),
+            current: to_f32(self.read(&mut reports, &[OUTPUT, CONFIG_CURRENT]).await?),
+            frequency: to_f32(self.read(&mut reports, &[OUTPUT, CONFIG_FREQUENCY]).await?),
+            apparent_power: self
+                .read(&mut reports, &[CONFIG_APPARENT_POWER])
+                .await?
+                .map(|power| power.round() as u32),
+            battery_voltage: to_f32(battery_voltage),
+            battery_count: None,
+        })
+    }
+
+    async fn start_self_test(&self, test: SelfTest) -> Result<SelfTestHandle> {
+        let value = match test {
+            SelfTest::Quick => TEST_QUICK,
+            // The deep test is the one that drains the battery
+            SelfTest::UntilBatteryLow => TEST_DEEP,
+            SelfTest::Timed(_) => bail!(UpsError::Unsupported(
+                "HID Power Devices have no timed battery test".to_string()
+            )),
+        };
+        self.write(&[TEST], value).await?;
+
+        Ok(SelfTestHandle::new(test))
+    }
+
+    async fn cancel_self_test(&self) -> Result<()> {
+        self.write(&[TEST], TEST_ABORT).await
+    }
+
+    async fn schedule_shutdown(
+        &self,
+        delay: Duration,
+        restore_after: Option<Duration>,
+    ) -> Result<()> {
+        let seconds = |duration: Duration| -> Result<i32> { Ok(duration.as_secs().try_into()?) };
+
+        let has_startup_delay = self
+            .descriptor
+            .find(ReportKind::Feature, &[DELAY_BEFORE_STARTUP])
+            .is_some();
+        match restore_after {
+            // The startup delay counts from now, not from the shutdown
+            Some(restore_after) if has_startup_delay => {
+                self.write(&[DELAY_BEFORE_STARTUP], seconds(delay + restore_after)?)
+                    .await?
+            }
+            // -1 keeps the output off until told otherwise
+            None if has_startup_delay => self.write(&[DELAY_BEFORE_STARTUP], -1).await?,
+            // Without a startup delay, the output comes back with the mains
+            Some(restore_after) if restore_after.is_zero() => {}
+            Some(_) => bail!(UpsError::Unsupported(
+                "UPS can't delay turning its output back on".to_string()
+            )),
+            None => bail!(UpsError::Unsupported(
+                "UPS can't keep its output off".to_string()
+            )),
+        }
+
+        self.write(&[DELAY_BEFORE_SHUTDOWN], seconds(delay)?).await
+    }
+
+    async fn cancel_shutdown(&self) -> Result<()> {
+        // -1 stops the countdowns
+        self.write(&[DELAY_BEFORE_SHUTDOWN], -1).await?;
+        if self
+            .descriptor
+            .find(ReportKind::Feature, &[DELAY_BEFORE_STARTUP])
+            .is_some()
+        {
+            self.write(&[DELAY_BEFORE_STARTUP], -1).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `descriptor` has a Power Device or Battery System application
+/// collection
+pub(crate) fn is_power_device(descriptor: &ReportDescriptor) -> bool {
+    descriptor
+        .applications
+        .iter()
+        .any(|usage| matches!(usage.page, POWER_DEVICE | BATTERY_SYSTEM))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock_transport::MockTransport, ups::UpsWorkMode};
+
+    /// Hand-assembled after the PDC spec's example UPS, with one feature
+    /// report per collection
+    const DESCRIPTOR: &[u8] = &[
+        0x05, 0x84, // Usage Page (Power Device)
+        0x09, 0x04, // Usage (UPS)
+        0xA1, 0x01, // Collection (Application)
+        0x09, 0x24, //   Usage (PowerSummary)
+        0xA1, 0x02, //   Collection (Logical)
+        0x85, 0x01, //     Report ID (1)
+        0x05, 0x85, //     Usage Page (Battery System)
+        0x09, 0x66, //     Usage (RemainingCapacity)
+        0x15, 0x00, //     Logical Minimum (0)
+        0x25, 0x64, //     Logical Maximum (100)
+        0x75, 0x08, //     Report Size (8)
+        0x95, 0x01, //     Report Count (1)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0x09, 0x68, //     Usage (RunTimeToEmpty)
+        0x27, 0xFF, 0xFF, 0x00, 0x00, //     Logical Maximum (65535)
+        0x75, 0x10, //     Report Size (16)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0x05, 0x84, //     Usage Page (Power Device)
+        0x09, 0x02, //     Usage (PresentStatus)
+        0xA1, 0x02, //     Collection (Logical)
+        0x85, 0x02, //       Report ID (2)
+        0x05, 0x85, //       Usage Page (Battery System)
+        0x09, 0xD0, //       Usage (ACPresent)
+        0x09, 0x44, //       Usage (Charging)
+        0x09, 0x45, //       Usage (Discharging)
+        0x09, 0x42, //       Usage (BelowRemainingCapacityLimit)
+        0x05, 0x84, //       Usage Page (Power Device)
+        0x09, 0x69, //       Usage (ShutdownImminent)
+        0x09, 0x6E, //       Usage (Boost)
+        0x09, 0x6F, //       Usage (Buck)
+        0x09, 0x62, //       Usage (InternalFailure)
+        0x25, 0x01, //       Logical Maximum (1)
+        0x75, 0x01, //       Report Size (1)
+        0x95, 0x08, //       Report Count (8)
+        0xB1, 0x02, //       Feature (Data,Var,Abs)
+        0xC0, //     End Collection
+        0x85, 0x03, //     Report ID (3)
+        0x05, 0x85, //     Usage Page (Battery System)
+        0x09, 0x5A, //     Usage (AudibleAlarmControl)
+        0x15, 0x01, //     Logical Minimum (1)
+        0x25, 0x03, //     Logical Maximum (3)
+        0x75, 0x08, //     Report Size (8)
+        0x95, 0x01, //     Report Count (1)
+        0xB1, 0x22, //     Feature (Data,Var,Abs,NoPref)
+        0xC0, //   End Collection
+        0x05, 0x84, //   Usage Page (Power Device)
+        0x09, 0x1A, //   Usage (Input)
+        0xA1, 0x02, //   Collection (Logical)
+        0x85, 0x04, //     Report ID (4)
+        0x09, 0x30, //     Usage (Voltage)
+        0x15, 0x00, //     Logical Minimum (0)
+        0x26, 0xFF, 0x7F, //     Logical Maximum (32767)
+        0x75, 0x10, //     Report Size (16)
+        0x67, 0x21, 0xD1, 0xF0, 0x00, //     Unit (SI Linear: Volt)
+        0x55, 0x0F, //     Unit Exponent (-1)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0xC0, //   End Collection
+        0x09, 0x1C, //   Usage (Output)
+        0xA1, 0x02, //   Collection (Logical)
+        0x85, 0x05, //     Report ID (5)
+        0x09, 0x30, //     Usage (Voltage)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0x09, 0x32, //     Usage (Frequency)
+        0x66, 0x01, 0xF0, //     Unit (SI Linear: Hertz)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0x09, 0x35, //     Usage (PercentLoad)
+        0x65, 0x00, //     Unit (None)
+        0x55, 0x00, //     Unit Exponent (0)
+        0x25, 0x64, //     Logical Maximum (100)
+        0x75, 0x08, //     Report Size (8)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0xC0, //   End Collection
+        0x09, 0x12, //   Usage (Battery)
+        0xA1, 0x02, //   Collection (Logical)
+        0x85, 0x06, //     Report ID (6)
+        0x09, 0x30, //     Usage (Voltage)
+        0x67, 0x21, 0xD1, 0xF0, 0x00, //     Unit (SI Linear: Volt)
+        0x55, 0x0E, //     Unit Exponent (-2)
+        0x26, 0xFF, 0x7F, //     Logical Maximum (32767)
+        0x75, 0x10, //     Report Size (16)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0x09, 0x36, //     Usage (Temperature)
+        0x67, 0x01, 0x00, 0x01, 0x00, //     Unit (SI Linear: Kelvin)
+        0x55, 0x0F, //     Unit Exponent (-1)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0x09, 0x58, //     Usage (Test)
+        0x65, 0x00, //     Unit (None)
+        0x55, 0x00, //     Unit Exponent (0)
+        0x25, 0x06, //     Logical Maximum (6)
+        0x75, 0x08, //     Report Size (8)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0xC0, //   End Collection
+        0x85, 0x07, //   Report ID (7)
+        0x09, 0x57, //   Usage (DelayBeforeShutdown)
+        0x15, 0xFF, //   Logical Minimum (-1)
+        0x26, 0xFF, 0x7F, //   Logical Maximum (32767)
+        0x75, 0x10, //   Report Size (16)
+        0x66, 0x01, 0x10, //   Unit (SI Linear: Second)
+        0xB1, 0x22, //   Feature (Data,Var,Abs,NoPref)
+        0xC0, // End Collection
+    ];
+
+    /// Only the PresentStatus collection, with Discharging but no ACPresent
+    const MINIMAL_DESCRIPTOR: &[u8] = &[
+        0x05, 0x84, // Usage Page (Power Device)
+        0x09, 0x04, // Usage (UPS)
+        0xA1, 0x01, // Collection (Application)
+        0x09, 0x02, //   Usage (PresentStatus)
+        0xA1, 0x02, //   Collection (Logical)
+        0x85, 0x01, //     Report ID (1)
+        0x05, 0x85, //     Usage Page (Battery System)
+        0x09, 0x44, //     Usage (Charging)
+        0x09, 0x45, //     Usage (Discharging)
+        0x15, 0x00, //     Logical Minimum (0)
+        0x25, 0x01, //     Logical Maximum (1)
+        0x75, 0x01, //     Report Size (1)
+        0x95, 0x02, //     Report Count (2)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0x95, 0x06, //     Report Count (6)
+        0xB1, 0x03, //     Feature (Cnst,Var,Abs)
+        0xC0, //   End Collection
+        0xC0, // End Collection
+    ];
+
+    #[tokio::test]
+    async fn status_maps_power_device_usages() {
+        let mock = MockTransport::new()
+            .with_report_descriptor(DESCRIPTOR)
+            .expect_get_feature(4, &[0xFC, 0x08])
+            .expect_get_feature(5, &[0xF2, 0x08, 0xF4, 0x01, 0x22])
+            .expect_get_feature(6, &[0xAA, 0x0A, 0xD7, 0x0B, 0x06])
+            .expect_get_feature(1, &[0x57, 0xB0, 0x04])
+            .expect_get_feature(2, &[0b0010_0100])
+            .expect_get_feature(3, &[0x02])
+            .expect_get_feature(7, &[0xFF, 0xFF]);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+
+        let status = ups.status().await.unwrap();
+
+        assert_eq!(status.input_voltage, 230.0);
+        assert!(status.input_fault_voltage.is_nan());
+        assert_eq!(status.output_voltage, 229.0);
+        assert_eq!(status.output_frequency, 50.0);
+        assert_eq!(status.output_load_level, 34);
+        assert_eq!(status.battery_voltage, 27.3);
+        assert!((status.internal_temperature - 29.95).abs() < 1e-3);
+        assert_eq!(status.battery_capacity, Some(87));
+        assert_eq!(status.battery_run_time, Some(Duration::from_secs(1200)));
+        assert_eq!(
+            status.flags,
+            UpsStatusFlags::UTILITY_FAIL
+                | UpsStatusFlags::BOOST_OR_BUCK_MODE
+                | UpsStatusFlags::BEEPER_ACTIVE
+        );
+        assert_eq!(status.work_mode(), UpsWorkMode::Battery);
+    }
+
+    #[tokio::test]
+    async fn status_flags_on_line() {
+        let mock = MockTransport::new()
+            .with_report_descriptor(DESCRIPTOR)
+            .expect_get_feature(4, &[0xFC, 0x08])
+            .expect_get_feature(5, &[0xF2, 0x08, 0xF4, 0x01, 0x22])
+            .expect_get_feature(6, &[0xAA, 0x0A, 0xD7, 0x0B, 0x05])
+            .expect_get_feature(1, &[0x64, 0xFF, 0xFF])
+            .expect_get_feature(2, &[0b1001_0011])
+            .expect_get_feature(3, &[0x01])
+            .expect_get_feature(7, &[0x1E, 0x00]);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+
+        let status = ups.status().await.unwrap();
+
+        assert_eq!(
+            status.flags,
+            UpsStatusFlags::BATTERY_LOW
+                | UpsStatusFlags::UPS_FAULT
+                | UpsStatusFlags::SELF_TEST_IN_PROGRESS
+                | UpsStatusFlags::UPS_SHUTDOWN_ACTIVE
+        );
+        assert_eq!(status.work_mode(), UpsWorkMode::Fault);
+    }
+
+    #[tokio::test]
+    async fn missing_usages_are_not_errors() {
+        let mock = MockTransport::new()
+            .with_report_descriptor(MINIMAL_DESCRIPTOR)
+            .expect_get_feature(1, &[0b10]);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+
+        let status = ups.status().await.unwrap();
+
+        assert!(status.output_voltage.is_nan());
+        assert_eq!(status.battery_capacity, None);
+        assert_eq!(status.flags, UpsStatusFlags::UTILITY_FAIL);
+        assert!(ups.beeper_toggle().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn capabilities_follow_the_descriptor() {
+        let mock = MockTransport::new().with_report_descriptor(DESCRIPTOR);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+        assert_eq!(
+            ups.capabilities().await.unwrap(),
+            UpsCapabilities::BEEPER
+                | UpsCapabilities::TEMPERATURE
+                | UpsCapabilities::SELF_TEST
+                | UpsCapabilities::SHUTDOWN
+        );
+
+        let mock = MockTransport::new().with_report_descriptor(MINIMAL_DESCRIPTOR);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+        assert_eq!(ups.capabilities().await.unwrap(), UpsCapabilities::empty());
+    }
+
+    #[tokio::test]
+    async fn missing_info_and_ratings_are_none() {
+        let mock = MockTransport::new().with_report_descriptor(MINIMAL_DESCRIPTOR);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+
+        assert_eq!(ups.info().await.unwrap(), UpsInfo::default());
+        assert_eq!(ups.ratings().await.unwrap(), UpsRatings::default());
+    }
+
+    #[tokio::test]
+    async fn info_reads_indexed_strings() {
+        const STRINGS_DESCRIPTOR: &[u8] = &[
+            0x05, 0x84, // Usage Page (Power Device)
+            0x09, 0x04, // Usage (UPS)
+            0xA1, 0x01, // Collection (Application)
+            0x85, 0x01, //   Report ID (1)
+            0x09, 0xFD, //   Usage (iManufacturer)
+            0x09, 0xFE, //   Usage (iProduct)
+            0x09, 0xFF, //   Usage (iSerialNumber)
+            0x15, 0x00, //   Logical Minimum (0)
+            0x26, 0xFF, 0x00, //   Logical Maximum (255)
+            0x75, 0x08, //   Report Size (8)
+            0x95, 0x03, //   Report Count (3)
+            0xB1, 0x03, //   Feature (Cnst,Var,Abs)
+            0xC0, // End Collection
+        ];
+
+        // An empty string is as good as none
+        let mock = MockTransport::new()
+            .with_report_descriptor(STRINGS_DESCRIPTOR)
+            .expect_get_feature(1, &[0x01, 0x02, 0x00])
+            .expect_indexed_string(1, "APC")
+            .expect_indexed_string(2, "");
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+        assert_eq!(
+            ups.info().await.unwrap(),
+            UpsInfo {
+                manufacturer: Some("APC".to_string()),
+                ..UpsInfo::default()
+            }
+        );
+
+        let mock = MockTransport::new()
+            .with_report_descriptor(STRINGS_DESCRIPTOR)
+            .without_indexed_strings()
+            .expect_get_feature(1, &[0x01, 0x02, 0x03]);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+        assert_eq!(ups.info().await.unwrap(), UpsInfo::default());
+
+        let mock = MockTransport::new()
+            .with_report_descriptor(STRINGS_DESCRIPTOR)
+            .expect_get_feature(1, &[0x01, 0x00, 0x00])
+            .expect_indexed_string_failure(1);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+        assert!(ups.info().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn self_test_writes_test() {
+        let mock = MockTransport::new()
+            .with_report_descriptor(DESCRIPTOR)
+            .expect_get_feature(6, &[0xAA, 0x0A, 0xD7, 0x0B, 0x06])
+            .expect_send_feature(6, &[0xAA, 0x0A, 0xD7, 0x0B, 0x01])
+            .expect_get_feature(6, &[0xAA, 0x0A, 0xD7, 0x0B, 0x05])
+            .expect_send_feature(6, &[0xAA, 0x0A, 0xD7, 0x0B, 0x03]);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+
+        ups.start_self_test(SelfTest::Quick).await.unwrap();
+        ups.cancel_self_test().await.unwrap();
+        assert!(ups.start_self_test(SelfTest::Timed(1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shutdown_writes_delay_before_shutdown() {
+        let mock = MockTransport::new()
+            .with_report_descriptor(DESCRIPTOR)
+            .expect_get_feature(7, &[0xFF, 0xFF])
+            .expect_send_feature(7, &[0x3C, 0x00])
+            .expect_get_feature(7, &[0x3B, 0x00])
+            .expect_send_feature(7, &[0xFF, 0xFF]);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+
+        // The example has no DelayBeforeStartup, so the output comes back
+        // with the mains, and can neither stay off nor wait
+        ups.schedule_shutdown(Duration::from_secs(60), Some(Duration::ZERO))
+            .await
+            .unwrap();
+        ups.cancel_shutdown().await.unwrap();
+
+        for restore_after in [None, Some(Duration::from_secs(60))] {
+            let error = ups
+                .schedule_shutdown(Duration::from_secs(60), restore_after)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                UpsError::of(&error),
+                Some(UpsError::Unsupported(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_writes_delay_before_startup() {
+        let mock = MockTransport::new()
+            .with_report_descriptor(&[
+                0x05, 0x84, // Usage Page (Power Device)
+                0x09, 0x04, // Usage (UPS)
+                0xA1, 0x01, // Collection (Application)
+                0x85, 0x01, //   Report ID (1)
+                0x09, 0x57, //   Usage (DelayBeforeShutdown)
+                0x09, 0x56, //   Usage (DelayBeforeStartup)
+                0x15, 0xFF, //   Logical Minimum (-1)
+                0x26, 0xFF, 0x7F, //   Logical Maximum (32767)
+                0x75, 0x10, //   Report Size (16)
+                0x95, 0x02, //   Report Count (2)
+                0x66, 0x01, 0x10, //   Unit (SI Linear: Second)
+                0xB1, 0x22, //   Feature (Data,Var,Abs,NoPref)
+                0xC0, // End Collection
+            ])
+            // Stay off: DelayBeforeStartup -1, then DelayBeforeShutdown 60
+            .expect_get_feature(1, &[0xFF, 0xFF, 0xFF, 0xFF])
+            .expect_send_feature(1, &[0xFF, 0xFF, 0xFF, 0xFF])
+            .expect_get_feature(1, &[0xFF, 0xFF, 0xFF, 0xFF])
+            .expect_send_feature(1, &[0x3C, 0x00, 0xFF, 0xFF])
+            // Back on 2 minutes after the shutdown, 180 seconds from now
+            .expect_get_feature(1, &[0xFF, 0xFF, 0xFF, 0xFF])
+            .expect_send_feature(1, &[0xFF, 0xFF, 0xB4, 0x00])
+            .expect_get_feature(1, &[0xFF, 0xFF, 0xB4, 0x00])
+            .expect_send_feature(1, &[0x3C, 0x00, 0xB4, 0x00]);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+
+        assert!(ups
+            .capabilities()
+            .await
+            .unwrap()
+            .contains(UpsCapabilities::SHUTDOWN | UpsCapabilities::OUTLET_CONTROL));
+        ups.schedule_shutdown(Duration::from_secs(60), None)
+            .await
+            .unwrap();
+        ups.schedule_shutdown(Duration::from_secs(60), Some(Duration::from_secs(120)))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn beeper_toggle_flips_audible_alarm_control() {
+        let mock = MockTransport::new()
+            .with_report_descriptor(DESCRIPTOR)
+            .expect_get_feature(3, &[0x02])
+            .expect_send_feature(3, &[0x01])
+            .expect_get_feature(3, &[0x01])
+            .expect_send_feature(3, &[0x02]);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+
+        ups.beeper_toggle().await.unwrap();
+        ups.beeper_toggle().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_beeper_writes_audible_alarm_control_and_verifies() {
+        let mock = MockTransport::new()
+            .with_report_descriptor(DESCRIPTOR)
+            .expect_get_feature(3, &[0x02])
+            .expect_send_feature(3, &[0x01])
+            .expect_get_feature(3, &[0x02])
+            .expect_get_feature(3, &[0x01]);
+        let ups = HidPowerDeviceUps::new(mock).await.unwrap();
+
+        ups.set_beeper(false).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_devices_are_rejected() {
+        let mock = MockTransport::new().with_report_descriptor(&[
+            0x06, 0x00, 0xFF, // Usage Page (Vendor Defined 0xFF00)
+            0x09, 0x01, // Usage (0x01)
+            0xA1, 0x01, // Collection (Application)
+            0xC0, // End Collection
+        ]);
+
+        assert!(HidPowerDeviceUps::new(mock).await.is_err());
+    }
+}
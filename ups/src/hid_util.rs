@@ -1,20 +1,27 @@
-use std::{cell::UnsafeCell, marker::PhantomData};
+use std::{cell::UnsafeCell, ffi::c_void, marker::PhantomData};
 
 use static_assertions::{assert_impl_all, assert_not_impl_all};
 use windows::{
     core::Result,
     Win32::{
         Devices::HumanInterfaceDevice::{
-            HidD_FreePreparsedData, HidD_GetPreparsedData, HidP_GetCaps, HIDP_CAPS,
+            HidD_FreePreparsedData, HidD_GetAttributes, HidD_GetManufacturerString,
+            HidD_GetPreparsedData, HidD_GetProductString, HidD_GetSerialNumberString, HidP_Feature,
+            HidP_GetButtonCaps, HidP_GetCaps, HidP_GetLinkCollectionNodes, HidP_GetValueCaps,
+            HidP_InitializeReportForID, HidP_Input, HidP_Output, HidP_SetUsageValue,
+            HidP_SetUsageValueArray, HidP_SetUsages, HIDD_ATTRIBUTES, HIDP_BUTTON_CAPS, HIDP_CAPS,
+            HIDP_LINK_COLLECTION_NODE, HIDP_REPORT_TYPE, HIDP_VALUE_CAPS,
         },
-        Foundation::{CloseHandle, BOOL, HANDLE},
+        Foundation::{CloseHandle, BOOL, BOOLEAN, HANDLE},
         Storage::FileSystem::{
-            CreateFileW, FILE_FLAGS_AND_ATTRIBUTES, FILE_GENERIC_READ, FILE_SHARE_READ,
+            CreateFileW, FILE_ACCESS_FLAGS, FILE_FLAGS_AND_ATTRIBUTES, FILE_SHARE_READ,
             FILE_SHARE_WRITE, OPEN_EXISTING,
         },
     },
 };
 
+use crate::report_descriptor::{ItemFlags, ReportField, ReportKind, Usage};
+
 #[derive(Debug)]
 pub(crate) struct HidInfo {
     handle: HANDLE,
@@ -26,10 +33,12 @@ assert_not_impl_all!(HidInfo: Sync);
 
 impl HidInfo {
     pub fn new(device_id: &str) -> Result<Self> {
+        // No access rights are needed to query a device, and asking for none
+        // works even on devices other processes hold open exclusively.
         let handle = unsafe {
             CreateFileW(
                 &device_id.into(),
-                FILE_GENERIC_READ,
+                FILE_ACCESS_FLAGS(0),
                 FILE_SHARE_READ | FILE_SHARE_WRITE,
                 None,
                 OPEN_EXISTING,
@@ -53,6 +62,43 @@ impl HidInfo {
             })
         }
     }
+
+    pub fn attributes(&self) -> Result<HIDD_ATTRIBUTES> {
+        let mut attributes = HIDD_ATTRIBUTES {
+            Size: std::mem::size_of::<HIDD_ATTRIBUTES>() as u32,
+            ..Default::default()
+        };
+        unsafe { HidD_GetAttributes(self.handle, &mut attributes).ok()? };
+        Ok(attributes)
+    }
+
+    pub fn manufacturer_string(&self) -> Result<String> {
+        self.string(HidD_GetManufacturerString::<HANDLE>)
+    }
+
+    pub fn product_string(&self) -> Result<String> {
+        self.string(HidD_GetProductString::<HANDLE>)
+    }
+
+    pub fn serial_number_string(&self) -> Result<String> {
+        self.string(HidD_GetSerialNumberString::<HANDLE>)
+    }
+
+    fn string(&self, getter: unsafe fn(HANDLE, *mut c_void, u32) -> BOOLEAN) -> Result<String> {
+        // USB string descriptors top out at 126 UTF-16 code units
+        let mut buffer = [0u16; 128];
+        unsafe {
+            getter(
+                self.handle,
+                buffer.as_mut_ptr().cast(),
+                std::mem::size_of_val(&buffer) as u32,
+            )
+            .ok()?
+        };
+
+        let length = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+        Ok(String::from_utf16_lossy(&buffer[..length]))
+    }
 }
 
 impl Drop for HidInfo {
@@ -78,6 +124,300 @@ impl HidPreparsedData {
         unsafe { HidP_GetCaps(self.data, &mut caps)? };
         Ok(caps)
     }
+
+    /// Rebuild the fields of the device's report descriptor, which Windows
+    /// doesn't hand out.
+    ///
+    /// Preparsed data keeps usages, ranges, units and collections, but not
+    /// where each field sits in its report, so that is found by writing the
+    /// field into an empty report and seeing which bits change. Constant
+    /// fields and array buttons leave no such trace, and are left out.
+    pub fn report_fields(&self) -> Result<Vec<ReportField>> {
+        let caps = self.caps()?;
+        let collections = self.collections(caps.NumberLinkCollectionNodes)?;
+        let collection = |index: u16| {
+            collections
+                .get(usize::from(index))
+                .cloned()
+                .unwrap_or_default()
+        };
+
+        let mut fields = Vec::new();
+        for (kind, report_type, value_caps, button_caps, report_length) in [
+            (
+                ReportKind::Input,
+                HidP_Input,
+                caps.NumberInputValueCaps,
+                caps.NumberInputButtonCaps,
+                caps.InputReportByteLength,
+            ),
+            (
+                ReportKind::Output,
+                HidP_Output,
+                caps.NumberOutputValueCaps,
+                caps.NumberOutputButtonCaps,
+                caps.OutputReportByteLength,
+            ),
+            (
+                ReportKind::Feature,
+                HidP_Feature,
+                caps.NumberFeatureValueCaps,
+                caps.NumberFeatureButtonCaps,
+                caps.FeatureReportByteLength,
+            ),
+        ] {
+            let probe = Probe {
+                data: self.data,
+                report_type,
+                report_length: report_length.into(),
+            };
+            let mut report_fields = Vec::new();
+
+            for value in self.value_caps(report_type, value_caps)? {
+                // An alias shares its bits with the next usage
+                if value.IsAlias.0 != 0 {
+                    continue;
+                }
+
+                let (usages, count): (Vec<u16>, u16) = unsafe {
+                    if value.IsRange.0 != 0 {
+                        let range = value.Anonymous.Range;
+                        ((range.UsageMin..=range.UsageMax).collect(), 1)
+                    } else {
+                        (vec![value.Anonymous.NotRange.Usage], value.ReportCount)
+                    }
+                };
+
+                // The spec stores the exponent in a nibble, but plenty of
+                // devices put a whole signed byte there.
+                let exponent = value.UnitsExp as i8;
+                let unit_exponent = if value.UnitsExp <= 0xF {
+                    (exponent << 4) >> 4
+                } else {
+                    exponent
+                };
+
+                for usage in usages {
+                    let bit_offset = if count > 1 {
+                        probe.value_array(&value, usage)
+                    } else {
+                        probe.value(&value, usage)
+                    };
+                    let bit_offset = match bit_offset {
+                        Some(bit_offset) => bit_offset,
+                        None => continue,
+                    };
+
+                    report_fields.push(ReportField {
+                        kind,
+                        report_id: value.ReportID,
+                        flags: ItemFlags::from_bits_truncate(value.BitField.into()),
+                        bit_offset,
+                        bit_size: value.BitSize.into(),
+                        count: count.into(),
+                        usages: vec![Usage::new(value.UsagePage, usage)],
+                        collections: collection(value.LinkCollection),
+                        logical_minimum: value.LogicalMin,
+                        logical_maximum: value.LogicalMax,
+                        physical_minimum: value.PhysicalMin,
+                        physical_maximum: value.PhysicalMax,
+                        unit: value.Units,
+                        unit_exponent,
+                    });
+                }
+            }
+
+            for button in self.button_caps(report_type, button_caps)? {
+                let flags = ItemFlags::from_bits_truncate(button.BitField.into());
+                if button.IsAlias.0 != 0 || !flags.contains(ItemFlags::VARIABLE) {
+                    continue;
+                }
+
+                let usages: Vec<u16> = unsafe {
+                    if button.IsRange.0 != 0 {
+                        let range = button.Anonymous.Range;
+                        (range.UsageMin..=range.UsageMax).collect()
+                    } else {
+                        vec![button.Anonymous.NotRange.Usage]
+                    }
+                };
+
+                for usage in usages {
+                    let bit_offset = match probe.button(&button, usage) {
+                        Some(bit_offset) => bit_offset,
+                        None => continue,
+                    };
+
+                    report_fields.push(ReportField {
+                        kind,
+                        report_id: button.ReportID,
+                        flags,
+                        bit_offset,
+                        bit_size: 1,
+                        count: 1,
+                        usages: vec![Usage::new(button.UsagePage, usage)],
+                        collections: collection(button.LinkCollection),
+                        logical_minimum: 0,
+                        logical_maximum: 1,
+                        physical_minimum: 0,
+                        physical_maximum: 0,
+                        unit: 0,
+                        unit_exponent: 0,
+                    });
+                }
+            }
+
+            report_fields.sort_by_key(|field| (field.report_id, field.bit_offset));
+            fields.extend(report_fields);
+        }
+
+        Ok(fields)
+    }
+
+    /// The usages of every link collection and its parents, outermost first
+    fn collections(&self, count: u16) -> Result<Vec<Vec<Usage>>> {
+        let mut nodes = vec![HIDP_LINK_COLLECTION_NODE::default(); count.into()];
+        let mut length = u32::from(count);
+        if count > 0 {
+            unsafe { HidP_GetLinkCollectionNodes(nodes.as_mut_ptr(), &mut length, self.data)? };
+        }
+        nodes.truncate(length as usize);
+
+        // The first node is the top-level collection
+        Ok((0..nodes.len())
+            .map(|mut index| {
+                let mut path = Vec::new();
+                // Bounded, in case the parents loop
+                for _ in 0..nodes.len() {
+                    let node = &nodes[index];
+                    path.push(Usage::new(node.LinkUsagePage, node.LinkUsage));
+                    if index == 0 || usize::from(node.Parent) >= nodes.len() {
+                        break;
+                    }
+                    index = node.Parent.into();
+                }
+                path.reverse();
+                path
+            })
+            .collect())
+    }
+
+    fn value_caps(
+        &self,
+        report_type: HIDP_REPORT_TYPE,
+        count: u16,
+    ) -> Result<Vec<HIDP_VALUE_CAPS>> {
+        let mut caps = vec![HIDP_VALUE_CAPS::default(); count.into()];
+        let mut length = count;
+        if count > 0 {
+            unsafe { HidP_GetValueCaps(report_type, caps.as_mut_ptr(), &mut length, self.data)? };
+        }
+        caps.truncate(length.into());
+        Ok(caps)
+    }
+
+    fn button_caps(
+        &self,
+        report_type: HIDP_REPORT_TYPE,
+        count: u16,
+    ) -> Result<Vec<HIDP_BUTTON_CAPS>> {
+        let mut caps = vec![HIDP_BUTTON_CAPS::default(); count.into()];
+        let mut length = count;
+        if count > 0 {
+            unsafe { HidP_GetButtonCaps(report_type, caps.as_mut_ptr(), &mut length, self.data)? };
+        }
+        caps.truncate(length.into());
+        Ok(caps)
+    }
+}
+
+/// Locates fields in the reports of one type by writing to them
+struct Probe {
+    data: isize,
+    report_type: HIDP_REPORT_TYPE,
+    report_length: usize,
+}
+
+impl Probe {
+    fn value(&self, caps: &HIDP_VALUE_CAPS, usage: u16) -> Option<usize> {
+        let all_ones = match caps.BitSize {
+            32.. => u32::MAX,
+            bits => (1 << bits) - 1,
+        };
+        self.bit_offset(caps.ReportID, |report, set| unsafe {
+            HidP_SetUsageValue(
+                self.report_type,
+                caps.UsagePage,
+                caps.LinkCollection,
+                usage,
+                if set { all_ones } else { 0 },
+                self.data,
+                report,
+            )
+        })
+    }
+
+    fn value_array(&self, caps: &HIDP_VALUE_CAPS, usage: u16) -> Option<usize> {
+        let length = (usize::from(caps.BitSize) * usize::from(caps.ReportCount)).div_ceil(8);
+        self.bit_offset(caps.ReportID, |report, set| unsafe {
+            HidP_SetUsageValueArray(
+                self.report_type,
+                caps.UsagePage,
+                caps.LinkCollection,
+                usage,
+                &vec![if set { 0xFF } else { 0 }; length],
+                self.data,
+                report,
+            )
+        })
+    }
+
+    fn button(&self, caps: &HIDP_BUTTON_CAPS, usage: u16) -> Option<usize> {
+        self.bit_offset(caps.ReportID, |report, set| {
+            if !set {
+                return Ok(());
+            }
+            let mut usage = usage;
+            let mut length = 1;
+            unsafe {
+                HidP_SetUsages(
+                    self.report_type,
+                    caps.UsagePage,
+                    caps.LinkCollection,
+                    &mut usage,
+                    &mut length,
+                    self.data,
+                    report,
+                )
+            }
+        })
+    }
+
+    /// The first payload bit that differs between a report where `write`
+    /// cleared a field and one where it set it
+    fn bit_offset(
+        &self,
+        report_id: u8,
+        write: impl Fn(&mut [u8], bool) -> Result<()>,
+    ) -> Option<usize> {
+        let mut cleared = vec![0u8; self.report_length];
+        unsafe {
+            HidP_InitializeReportForID(self.report_type, report_id, self.data, &mut cleared).ok()?
+        };
+        let mut set = cleared.clone();
+
+        write(&mut cleared, false).ok()?;
+        write(&mut set, true).ok()?;
+
+        // Skip the report ID byte, which is there even without report IDs
+        let (index, (a, b)) = cleared
+            .iter()
+            .zip(&set)
+            .enumerate()
+            .skip(1)
+            .find(|(_, (a, b))| a != b)?;
+        Some((index - 1) * 8 + (a ^ b).trailing_zeros() as usize)
+    }
 }
 
 impl Drop for HidPreparsedData {
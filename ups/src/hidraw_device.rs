@@ -0,0 +1,637 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use tokio::io::unix::AsyncFd;
+
+use crate::{
+    device::{DeviceFilter, DeviceInfo, DeviceSelector},
+    error::UpsError,
+    report_descriptor::{ReportDescriptor, ReportKind},
+    transport::Transport,
+};
+
+const SYSFS_ROOT: &str = "/sys";
+const DEV_ROOT: &str = "/dev";
+
+/// A HID device accessed through the Linux hidraw driver
+#[derive(Debug)]
+pub struct HidrawDevice {
+    file: AsyncFd<File>,
+    raw_descriptor: Vec<u8>,
+    descriptor: ReportDescriptor,
+}
+
+/// A hidraw node found in sysfs
+#[derive(Debug)]
+struct Node {
+    info: DeviceInfo,
+    raw_descriptor: Vec<u8>,
+    descriptor: ReportDescriptor,
+}
+
+impl HidrawDevice {
+    pub async fn new(
+        usage_page: Option<u16>,
+        usage_id: Option<u16>,
+        vendor_id: u16,
+        product_id: u16,
+    ) -> Result<Self> {
+        let filter = DeviceFilter {
+            vendor_id: Some(vendor_id),
+            product_id: Some(product_id),
+            usage_page,
+            usage_id,
+        };
+
+        Self::open(&filter, &DeviceSelector::Any).await
+    }
+
+    pub async fn open(filter: &DeviceFilter, selector: &DeviceSelector) -> Result<Self> {
+        Self::open_with_sysfs_root(Path::new(SYSFS_ROOT), filter, selector).await
+    }
+
+    pub async fn open_with_sysfs_root(
+        sysfs_root: &Path,
+        filter: &DeviceFilter,
+        selector: &DeviceSelector,
+    ) -> Result<Self> {
+        let mut nodes = Self::get_devices(sysfs_root, filter)?;
+        let infos: Vec<_> = nodes.iter().map(|node| node.info.clone()).collect();
+        let index = selector.select(&infos)?;
+        let node = nodes.swap_remove(index);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&node.info.path)?;
+
+        Ok(Self {
+            file: AsyncFd::new(file)?,
+            raw_descriptor: node.raw_descriptor,
+            descriptor: node.descriptor,
+        })
+    }
+
+    pub async fn enumerate(filter: &DeviceFilter) -> Result<Vec<DeviceInfo>> {
+        Self::enumerate_with_sysfs_root(Path::new(SYSFS_ROOT), filter).await
+    }
+
+    pub async fn enumerate_with_sysfs_root(
+        sysfs_root: &Path,
+        filter: &DeviceFilter,
+    ) -> Result<Vec<DeviceInfo>> {
+        Ok(Self::get_devices(sysfs_root, filter)?
+            .into_iter()
+            .map(|node| node.info)
+            .collect())
+    }
+
+    /// Finds the hidraw nodes matching `filter`, together with their parsed
+    /// report descriptors. Nodes that can't be read are skipped, as they
+    /// shouldn't keep the UPS from being found.
+    fn get_devices(sysfs_root: &Path, filter: &DeviceFilter) -> Result<Vec<Node>> {
+        let entries = match fs::read_dir(sysfs_root.join("class/hidraw")) {
+            Ok(entries) => entries,
+            // No hidraw driver loaded, so no devices
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut devices: Vec<_> = entries
+            .filter_map(|entry| Self::read_node(&entry.ok()?.path(), filter).ok()?)
+            .collect();
+
+        devices.sort_by(|a, b| a.info.path.cmp(&b.info.path));
+
+        Ok(devices)
+    }
+
+    /// Reads the hidraw node at `node_dir`, if it matches `filter`
+    fn read_node(node_dir: &Path, filter: &DeviceFilter) -> Result<Option<Node>> {
+        let device_dir: PathBuf = node_dir.join("device");
+
+        let uevent = fs::read_to_string(device_dir.join("uevent"))?;
+        let (vendor_id, product_id) = Self::parse_hid_id(&uevent)?;
+
+        let mut device = DeviceInfo {
+            path: String::new(),
+            vendor_id,
+            product_id,
+            usage_page: 0,
+            usage_id: 0,
+            manufacturer: None,
+            product: None,
+            serial_number: None,
+        };
+
+        // Only look at the descriptor of devices that may match
+        let ids_filter = DeviceFilter {
+            usage_page: None,
+            usage_id: None,
+            ..*filter
+        };
+        if !ids_filter.matches(&device) {
+            return Ok(None);
+        }
+
+        let raw_descriptor = fs::read(device_dir.join("report_descriptor"))?;
+        let descriptor = ReportDescriptor::parse(&raw_descriptor)?;
+
+        let node = node_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("hidraw node name is not valid UTF-8"))?;
+
+        // The HID device sits under a USB interface, which sits under the
+        // USB device that has the descriptor strings.
+        let usb_device_dir = fs::canonicalize(&device_dir)
+            .ok()
+            .and_then(|dir| Some(dir.parent()?.parent()?.to_path_buf()));
+        let usb_string = |name: &str| {
+            usb_device_dir
+                .as_ref()
+                .and_then(|dir| Self::read_string(&dir.join(name)))
+        };
+
+        device.path = Path::new(DEV_ROOT)
+            .join(node)
+            .to_string_lossy()
+            .into_owned();
+        device.manufacturer = usb_string("manufacturer");
+        device.product = usb_string("product").or_else(|| Self::uevent_value(&uevent, "HID_NAME"));
+        device.serial_number =
+            usb_string("serial").or_else(|| Self::uevent_value(&uevent, "HID_UNIQ"));
+
+        // A hidraw node covers every top-level collection of the device,
+        // so it matches if any of them does.
+        let matching_application = descriptor.applications.iter().find(|usage| {
+            filter.matches(&DeviceInfo {
+                usage_page: usage.page,
+                usage_id: usage.id,
+                ..device.clone()
+            })
+        });
+        match matching_application {
+            Some(usage) => {
+                device.usage_page = usage.page;
+                device.usage_id = usage.id;
+            }
+            None if descriptor.applications.is_empty() && filter.matches(&device) => {}
+            None => return Ok(None),
+        }
+
+        Ok(Some(Node {
+            info: device,
+            raw_descriptor,
+            descriptor,
+        }))
+    }
+
+    fn read_string(path: &Path) -> Option<String> {
+        let value = fs::read_to_string(path).ok()?;
+        let value = value.trim();
+        (!value.is_empty()).then(|| value.to_string())
+    }
+
+    fn uevent_value(uevent: &str, key: &str) -> Option<String> {
+        uevent
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    }
+
+    fn parse_hid_id(uevent: &str) -> Result<(u16, u16)> {
+        // HID_ID=<bus>:<vendor>:<product>, each a zero-padded hex number
+        let hid_id = uevent
+            .lines()
+            .find_map(|line| line.strip_prefix("HID_ID="))
+            .ok_or_else(|| anyhow!("uevent has no HID_ID"))?;
+
+        let malformed = || UpsError::Framing(format!("Malformed HID_ID {:?}", hid_id));
+
+        let parts: Vec<_> = hid_id.split(':').collect();
+        if parts.len() != 3 {
+            bail!(malformed());
+        }
+
+        let id = |part: &str| {
+            u32::from_str_radix(part, 16)
+                .ok()
+                .and_then(|id| id.try_into().ok())
+                .ok_or_else(malformed)
+        };
+
+        Ok((id(parts[1])?, id(parts[2])?))
+    }
+
+    fn create_output_report(&self, report_id: u8, data: &[u8]) -> Result<Vec<u8>> {
+        Self::create_report(self.descriptor.output_report_size, report_id, data)
+    }
+
+    fn create_report(size: usize, report_id: u8, data: &[u8]) -> Result<Vec<u8>> {
+        if size < 1 {
+            bail!(UpsError::Unsupported(
+                "Device has no reports of this kind".to_string()
+            ));
+        }
+        if data.len() > size - 1 {
+            return Err(anyhow!("Supplied data does not fit in report"));
+        }
+
+        let mut report = vec![0u8; size];
+        report[0] = report_id;
+        report[1..data.len() + 1].copy_from_slice(data);
+
+        Ok(report)
+    }
+
+    fn feature_report_size(&self, report_id: u8) -> Result<usize> {
+        self.descriptor
+            .report_size(ReportKind::Feature, report_id)
+            .ok_or_else(|| {
+                UpsError::Unsupported(format!("Device has no feature report {}", report_id)).into()
+            })
+    }
+
+    /// Runs a feature report ioctl on `report`, which starts with the report
+    /// ID byte. Feature reports go over the control pipe and the kernel
+    /// waits for them synchronously, so this blocks, but only for as long
+    /// as one control transfer takes.
+    fn feature_ioctl(&self, number: u8, report: &mut [u8]) -> Result<usize> {
+        // _IOC(_IOC_WRITE | _IOC_READ, 'H', number, len), from linux/hidraw.h
+        const IOC_READ_WRITE: libc::c_ulong = 3;
+        let request = (IOC_READ_WRITE << 30)
+            | ((report.len() as libc::c_ulong) << 16)
+            | ((b'H' as libc::c_ulong) << 8)
+            | libc::c_ulong::from(number);
+
+        let result = unsafe {
+            libc::ioctl(
+                self.file.get_ref().as_raw_fd(),
+                request as _,
+                report.as_mut_ptr(),
+            )
+        };
+        if result < 0 {
+            return Err(Self::io_error(io::Error::last_os_error()));
+        }
+
+        Ok(result as usize)
+    }
+
+    /// I/O on the node fails with ENODEV once the device is unplugged
+    fn io_error(error: io::Error) -> anyhow::Error {
+        if error.raw_os_error() == Some(libc::ENODEV) {
+            UpsError::Disconnected(format!("hidraw device was disconnected: {}", error)).into()
+        } else {
+            error.into()
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for HidrawDevice {
+    async fn send_output_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        // hidraw always expects the report ID as the first byte, using 0 when
+        // the device doesn't number its reports.
+        let report = self.create_output_report(report_id, data)?;
+
+        let written = loop {
+            let mut guard = self.file.writable().await?;
+            if let Ok(result) = guard.try_io(|file| file.get_ref().write(&report)) {
+                break result.map_err(Self::io_error)?;
+            }
+        };
+        if written != report.len() {
+            bail!(UpsError::Disconnected(
+                "Short write to hidraw device".to_string()
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn read_input_report(&self) -> Result<(u8, Vec<u8>)> {
+        if self.descriptor.input_report_size < 1 {
+            bail!(UpsError::Unsupported(
+                "Device has no input reports".to_string()
+            ));
+        }
+
+        // Unnumbered reports come back without the ID byte, so leave room
+        // for it and report them as ID 0.
+        let mut report = vec![0u8; self.descriptor.input_report_size];
+        let buffer = if self.descriptor.uses_report_ids {
+            &mut report[..]
+        } else {
+            &mut report[1..]
+        };
+
+        let read = loop {
+            let mut guard = self.file.readable().await?;
+            if let Ok(result) = guard.try_io(|file| file.get_ref().read(buffer)) {
+                break result.map_err(Self::io_error)?;
+            }
+        };
+        if read == 0 {
+            bail!(UpsError::Disconnected(
+                "hidraw device was disconnected".to_string()
+            ));
+        }
+
+        let report_id = report[0];
+        report.remove(0);
+
+        Ok((report_id, report))
+    }
+
+    async fn get_indexed_string(&self, _index: u32) -> Result<String> {
+        Err(UpsError::Unsupported(
+            "Indexed strings are not supported by the hidraw backend".to_string(),
+        )
+        .into())
+    }
+
+    async fn get_feature_report(&self, report_id: u8) -> Result<Vec<u8>> {
+        const HIDIOCGFEATURE: u8 = 0x07;
+
+        let mut report = vec![0u8; self.feature_report_size(report_id)?];
+        report[0] = report_id;
+
+        // The ID byte is in the buffer even for unnumbered reports
+        let read = self.feature_ioctl(HIDIOCGFEATURE, &mut report)?;
+        if read < 1 {
+            bail!(UpsError::Framing("Empty feature report".to_string()));
+        }
+        report.truncate(read);
+        report.remove(0);
+
+        Ok(report)
+    }
+
+    async fn send_feature_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        const HIDIOCSFEATURE: u8 = 0x06;
+
+        let mut report =
+            Self::create_report(self.feature_report_size(report_id)?, report_id, data)?;
+
+        let written = self.feature_ioctl(HIDIOCSFEATURE, &mut report)?;
+        if written != report.len() {
+            bail!(UpsError::Disconnected(
+                "Short feature report write to hidraw device".to_string()
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn report_descriptor(&self) -> Result<Vec<u8>> {
+        Ok(self.raw_descriptor.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Vendor-defined collection with 8-byte input and output reports
+    const VENDOR_DESCRIPTOR: &[u8] = &[
+        0x06, 0x00, 0xFF, // Usage Page (Vendor Defined 0xFF00)
+        0x09, 0x01, //       Usage (0x01)
+        0xA1, 0x01, //       Collection (Application)
+        0x15, 0x00, //         Logical Minimum (0)
+        0x26, 0xFF, 0x00, //   Logical Maximum (255)
+        0x75, 0x08, //         Report Size (8)
+        0x95, 0x08, //         Report Count (8)
+        0x09, 0x01, //         Usage (0x01)
+        0x81, 0x02, //         Input (Data,Var,Abs)
+        0x09, 0x01, //         Usage (0x01)
+        0x91, 0x02, //         Output (Data,Var,Abs)
+        0xC0, //             End Collection
+    ];
+
+    // Keyboard-ish descriptor with a numbered 3-byte input report
+    const NUMBERED_DESCRIPTOR: &[u8] = &[
+        0x05, 0x01, //       Usage Page (Generic Desktop)
+        0x09, 0x06, //       Usage (Keyboard)
+        0xA1, 0x01, //       Collection (Application)
+        0x85, 0x02, //         Report ID (2)
+        0x75, 0x08, //         Report Size (8)
+        0x95, 0x03, //         Report Count (3)
+        0x81, 0x00, //         Input (Data,Array,Abs)
+        0xC0, //             End Collection
+    ];
+
+    /// Lays out a node the way sysfs does for a USB HID device
+    fn add_node(root: &Path, node: &str, hid_id: &str, descriptor: &[u8], serial: Option<&str>) {
+        let usb_device_dir = root.join("devices/usb1").join(node);
+        let device_dir = usb_device_dir.join("1-1:1.0/hid");
+        fs::create_dir_all(&device_dir).unwrap();
+        fs::write(
+            device_dir.join("uevent"),
+            format!(
+                "DRIVER=hid-generic\nHID_ID={}\nHID_NAME=Generic UPS\nHID_UNIQ=\n",
+                hid_id
+            ),
+        )
+        .unwrap();
+        fs::write(device_dir.join("report_descriptor"), descriptor).unwrap();
+
+        if let Some(serial) = serial {
+            fs::write(usb_device_dir.join("manufacturer"), "ACME\n").unwrap();
+            fs::write(usb_device_dir.join("product"), "UPS 1500\n").unwrap();
+            fs::write(usb_device_dir.join("serial"), format!("{}\n", serial)).unwrap();
+        }
+
+        let node_dir = root.join("class/hidraw").join(node);
+        fs::create_dir_all(&node_dir).unwrap();
+        std::os::unix::fs::symlink(&device_dir, node_dir.join("device")).unwrap();
+    }
+
+    fn fake_sysfs() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        add_node(
+            root.path(),
+            "hidraw0",
+            "0003:0000046D:0000C52B",
+            NUMBERED_DESCRIPTOR,
+            None,
+        );
+        add_node(
+            root.path(),
+            "hidraw1",
+            "0003:00000665:00005161",
+            VENDOR_DESCRIPTOR,
+            Some("A1B2C3"),
+        );
+        root
+    }
+
+    fn filter(
+        usage_page: Option<u16>,
+        usage_id: Option<u16>,
+        vendor_id: u16,
+        product_id: u16,
+    ) -> DeviceFilter {
+        DeviceFilter {
+            vendor_id: Some(vendor_id),
+            product_id: Some(product_id),
+            usage_page,
+            usage_id,
+        }
+    }
+
+    #[test]
+    fn finds_device_by_ids() {
+        let root = fake_sysfs();
+
+        let devices = HidrawDevice::get_devices(
+            root.path(),
+            &filter(Some(0xFF00), Some(0x0001), 0x0665, 0x5161),
+        )
+        .unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].info.path, "/dev/hidraw1");
+        assert_eq!(devices[0].descriptor.input_report_size, 9);
+        assert_eq!(devices[0].descriptor.output_report_size, 9);
+        assert!(!devices[0].descriptor.uses_report_ids);
+    }
+
+    #[test]
+    fn usage_is_optional() {
+        let root = fake_sysfs();
+
+        let devices =
+            HidrawDevice::get_devices(root.path(), &filter(None, None, 0x046D, 0xC52B)).unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].info.path, "/dev/hidraw0");
+        assert_eq!(devices[0].descriptor.input_report_size, 4);
+        assert_eq!(devices[0].descriptor.output_report_size, 0);
+        assert!(devices[0].descriptor.uses_report_ids);
+    }
+
+    #[test]
+    fn usage_mismatch_is_skipped() {
+        let root = fake_sysfs();
+
+        let devices = HidrawDevice::get_devices(
+            root.path(),
+            &filter(Some(0x0084), Some(0x0004), 0x0665, 0x5161),
+        )
+        .unwrap();
+
+        assert!(devices.is_empty());
+    }
+
+    #[tokio::test]
+    async fn enumerates_everything() {
+        let root = fake_sysfs();
+
+        let devices =
+            HidrawDevice::enumerate_with_sysfs_root(root.path(), &DeviceFilter::default())
+                .await
+                .unwrap();
+
+        assert_eq!(
+            devices,
+            [
+                DeviceInfo {
+                    path: "/dev/hidraw0".to_string(),
+                    vendor_id: 0x046D,
+                    product_id: 0xC52B,
+                    usage_page: 0x0001,
+                    usage_id: 0x0006,
+                    manufacturer: None,
+                    product: Some("Generic UPS".to_string()),
+                    serial_number: None,
+                },
+                DeviceInfo {
+                    path: "/dev/hidraw1".to_string(),
+                    vendor_id: 0x0665,
+                    product_id: 0x5161,
+                    usage_page: 0xFF00,
+                    usage_id: 0x0001,
+                    manufacturer: Some("ACME".to_string()),
+                    product: Some("UPS 1500".to_string()),
+                    serial_number: Some("A1B2C3".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_nodes_are_skipped() {
+        let root = fake_sysfs();
+        // An unrelated device with a descriptor that doesn't parse
+        add_node(
+            root.path(),
+            "hidraw2",
+            "0003:0000046D:0000C52C",
+            &[0xA1, 0x01],
+            None,
+        );
+        // A node without its device
+        fs::create_dir_all(root.path().join("class/hidraw/hidraw3")).unwrap();
+
+        let devices = HidrawDevice::get_devices(root.path(), &DeviceFilter::default()).unwrap();
+        assert_eq!(devices.len(), 2);
+
+        let devices =
+            HidrawDevice::get_devices(root.path(), &filter(None, None, 0x0665, 0x5161)).unwrap();
+        assert_eq!(devices.len(), 1);
+    }
+
+    #[test]
+    fn no_hidraw_class_means_no_devices() {
+        let root = tempfile::tempdir().unwrap();
+
+        let devices = HidrawDevice::get_devices(root.path(), &DeviceFilter::default()).unwrap();
+
+        assert!(devices.is_empty());
+    }
+
+    #[tokio::test]
+    async fn no_match_is_an_error() {
+        let root = fake_sysfs();
+
+        let result = HidrawDevice::open_with_sysfs_root(
+            root.path(),
+            &filter(None, None, 0x1234, 0x5678),
+            &DeviceSelector::Any,
+        )
+        .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn identical_devices_are_ambiguous() {
+        let root = fake_sysfs();
+        add_node(
+            root.path(),
+            "hidraw2",
+            "0003:00000665:00005161",
+            VENDOR_DESCRIPTOR,
+            Some("D4E5F6"),
+        );
+
+        let result = HidrawDevice::open_with_sysfs_root(
+            root.path(),
+            &filter(None, None, 0x0665, 0x5161),
+            &DeviceSelector::Any,
+        )
+        .await;
+
+        assert!(result.unwrap_err().to_string().contains("2 devices match"));
+    }
+}
@@ -1,7 +1,28 @@
+#[cfg(windows)]
 mod hid_util;
+#[cfg(windows)]
 mod util;
 
+pub mod battery;
+pub mod capture;
+pub mod command;
+pub mod detect;
+pub mod device;
+pub mod error;
+pub mod framing;
+#[cfg(windows)]
 pub mod hid_device;
+pub mod hid_power_device_ups;
+#[cfg(target_os = "linux")]
+pub mod hidraw_device;
 pub mod megatec_hid_ups;
+pub mod megatec_serial_ups;
+pub mod mock_transport;
+pub mod report_descriptor;
+#[cfg(unix)]
+pub mod serial_transport;
+pub mod stream_transport;
+pub mod tcp_transport;
+pub mod transport;
 pub mod ups;
 pub mod voltronic_hid_ups;
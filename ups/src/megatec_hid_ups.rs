@@ -1,32 +1,158 @@
+use std::time::Duration;
+
 use anyhow::Result;
 use async_trait::async_trait;
 
 use crate::{
-    hid_device::HidDevice,
-    ups::{Ups, UpsStatus},
+    command::{transact_indexed, Command},
+    transport::Transport,
+    ups::{
+        set_beeper_by_toggle, SelfTest, SelfTestHandle, Ups, UpsCapabilities, UpsInfo, UpsRatings,
+        UpsStatus,
+    },
 };
 
 #[derive(Debug)]
-pub struct MegatecHidUps {
-    device: HidDevice,
+pub struct MegatecHidUps<T: Transport> {
+    device: T,
 }
 
-impl MegatecHidUps {
-    pub fn new(device: HidDevice) -> Result<Self> {
+impl<T: Transport> MegatecHidUps<T> {
+    pub fn new(device: T) -> Result<Self> {
         Ok(Self { device })
     }
 }
 
 #[async_trait]
-impl Ups for MegatecHidUps {
+impl<T: Transport> Ups for MegatecHidUps<T> {
     async fn status(&self) -> Result<UpsStatus> {
-        let status_string = self.device.get_indexed_string(3).await?;
-
-        Ok(status_string.parse()?)
+        transact_indexed(&self.device, Command::MegatecStatus)
+            .await?
+            .into_status()
     }
 
     async fn beeper_toggle(&self) -> Result<()> {
-        self.device.get_indexed_string(7).await?;
+        transact_indexed(&self.device, Command::BeeperToggle).await?;
+        Ok(())
+    }
+
+    async fn set_beeper(&self, enabled: bool) -> Result<()> {
+        set_beeper_by_toggle(self, enabled).await
+    }
+
+    async fn capabilities(&self) -> Result<UpsCapabilities> {
+        Ok(UpsCapabilities::BEEPER
+            | UpsCapabilities::SELF_TEST
+            | UpsCapabilities::RATINGS
+            | UpsCapabilities::TEMPERATURE)
+    }
+
+    async fn info(&self) -> Result<UpsInfo> {
+        transact_indexed(&self.device, Command::Info)
+            .await?
+            .into_info()
+    }
+
+    async fn ratings(&self) -> Result<UpsRatings> {
+        transact_indexed(&self.device, Command::Ratings)
+            .await?
+            .into_ratings()
+    }
+
+    async fn start_self_test(&self, test: SelfTest) -> Result<SelfTestHandle> {
+        // Timed tests have no indexed string, so they are refused here
+        transact_indexed(&self.device, test.try_into()?).await?;
+        Ok(SelfTestHandle::new(test))
+    }
+
+    async fn cancel_self_test(&self) -> Result<()> {
+        transact_indexed(&self.device, Command::CancelSelfTest).await?;
+        Ok(())
+    }
+
+    async fn schedule_shutdown(
+        &self,
+        delay: Duration,
+        restore_after: Option<Duration>,
+    ) -> Result<()> {
+        // There is no indexed string for this, so it is always refused
+        transact_indexed(&self.device, Command::shutdown(delay, restore_after)?).await?;
+        Ok(())
+    }
+
+    async fn cancel_shutdown(&self) -> Result<()> {
+        transact_indexed(&self.device, Command::CancelShutdown).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::UpsError, mock_transport::MockTransport, ups::UpsWorkMode};
+
+    #[tokio::test]
+    async fn status_reads_string_3() {
+        let mock = MockTransport::new()
+            .expect_indexed_string(3, "(208.4 140.0 208.4 034 59.9 2.05 35.0 10000000\r");
+        let ups = MegatecHidUps::new(mock).unwrap();
+
+        let status = ups.status().await.unwrap();
+
+        assert_eq!(status.output_load_level, 34);
+        assert_eq!(status.work_mode(), UpsWorkMode::Battery);
+    }
+
+    #[tokio::test]
+    async fn beeper_toggle_reads_string_7() {
+        let mock = MockTransport::new().expect_indexed_string(7, "");
+        let ups = MegatecHidUps::new(mock).unwrap();
+
+        ups.beeper_toggle().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn info_and_ratings_read_strings_12_and_13() {
+        let mock = MockTransport::new()
+            .expect_indexed_string(0x0c, "#UPS Inc.        Line 650   V1.0      \r")
+            .expect_indexed_string(0x0d, "#230.0 002 12.00 50.0\r");
+        let ups = MegatecHidUps::new(mock).unwrap();
+
+        let info = ups.info().await.unwrap();
+        assert_eq!(info.manufacturer.as_deref(), Some("UPS Inc."));
+        assert_eq!(info.model.as_deref(), Some("Line 650"));
+
+        let ratings = ups.ratings().await.unwrap();
+        assert_eq!(ratings.voltage, Some(230.0));
+        assert_eq!(ratings.battery_voltage, Some(12.0));
+    }
+
+    #[tokio::test]
+    async fn self_tests_read_strings_4_5_and_11() {
+        let mock = MockTransport::new()
+            .expect_indexed_string(4, "")
+            .expect_indexed_string(5, "")
+            .expect_indexed_string(0x0b, "");
+        let ups = MegatecHidUps::new(mock).unwrap();
+
+        ups.start_self_test(SelfTest::Quick).await.unwrap();
+        ups.start_self_test(SelfTest::UntilBatteryLow)
+            .await
+            .unwrap();
+        ups.cancel_self_test().await.unwrap();
+
+        let error = ups.start_self_test(SelfTest::Timed(5)).await.unwrap_err();
+        assert!(matches!(
+            UpsError::of(&error),
+            Some(UpsError::Unsupported(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_request_is_an_error() {
+        let mock = MockTransport::new().expect_indexed_string_failure(3);
+        let ups = MegatecHidUps::new(mock).unwrap();
+
+        assert!(ups.status().await.is_err());
+    }
+}
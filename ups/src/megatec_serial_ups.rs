@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use crate::{
+    command::{self, Command},
+    framing::{self, CrcPolicy},
+    transport::Transport,
+    ups::{
+        set_beeper_by_toggle, SelfTest, SelfTestHandle, Ups, UpsCapabilities, UpsInfo, UpsRatings,
+        UpsStatus,
+    },
+};
+
+/// A UPS speaking the Megatec command protocol directly, as opposed to the
+/// indexed-string flavour used by [`MegatecHidUps`](crate::megatec_hid_ups::MegatecHidUps)
+#[derive(Debug)]
+pub struct MegatecSerialUps<T: Transport> {
+    device: Mutex<T>,
+}
+
+impl<T: Transport> MegatecSerialUps<T> {
+    pub fn new(device: T) -> Result<Self> {
+        Ok(Self {
+            device: Mutex::new(device),
+        })
+    }
+
+    async fn send(&self, command: Command) -> Result<command::Response> {
+        let device = self.device.lock().await;
+        // Megatec predates the CRC
+        command::transact(&*device, command, CrcPolicy::Off).await
+    }
+
+    /// Send one of the commands that have no response
+    async fn send_unanswered(&self, command: Command) -> Result<()> {
+        let device = self.device.lock().await;
+        framing::send_command(&*device, &command.to_string(), CrcPolicy::Off).await
+    }
+}
+
+#[async_trait]
+impl<T: Transport> Ups for MegatecSerialUps<T> {
+    async fn status(&self) -> Result<UpsStatus> {
+        self.send(Command::MegatecStatus).await?.into_status()
+    }
+
+    async fn beeper_toggle(&self) -> Result<()> {
+        self.send_unanswered(Command::BeeperToggle).await
+    }
+
+    async fn set_beeper(&self, enabled: bool) -> Result<()> {
+        set_beeper_by_toggle(self, enabled).await
+    }
+
+    async fn capabilities(&self) -> Result<UpsCapabilities> {
+        Ok(UpsCapabilities::BEEPER
+            | UpsCapabilities::SELF_TEST
+            | UpsCapabilities::SHUTDOWN
+            | UpsCapabilities::OUTLET_CONTROL
+            | UpsCapabilities::RATINGS
+            | UpsCapabilities::TEMPERATURE)
+    }
+
+    async fn info(&self) -> Result<UpsInfo> {
+        self.send(Command::Info).await?.into_info()
+    }
+
+    async fn ratings(&self) -> Result<UpsRatings> {
+        self.send(Command::Ratings).await?.into_ratings()
+    }
+
+    async fn start_self_test(&self, test: SelfTest) -> Result<SelfTestHandle> {
+        self.send_unanswered(test.try_into()?).await?;
+        Ok(SelfTestHandle::new(test))
+    }
+
+    async fn cancel_self_test(&self) -> Result<()> {
+        self.send_unanswered(Command::CancelSelfTest).await
+    }
+
+    async fn schedule_shutdown(
+        &self,
+        delay: Duration,
+        restore_after: Option<Duration>,
+    ) -> Result<()> {
+        self.send_unanswered(Command::shutdown(delay, restore_after)?)
+            .await
+    }
+
+    async fn cancel_shutdown(&self) -> Result<()> {
+        self.send_unanswered(Command::CancelShutdown).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::UpsError, mock_transport::MockTransport, ups::UpsWorkMode};
+
+    #[tokio::test]
+    async fn status_sends_q1() {
+        let mock = MockTransport::new()
+            .expect_command("Q1", "(208.4 140.0 208.4 034 59.9 2.05 35.0 10000000");
+        let ups = MegatecSerialUps::new(mock).unwrap();
+
+        let status = ups.status().await.unwrap();
+
+        assert_eq!(status.output_load_level, 34);
+        assert_eq!(status.battery_voltage, 2.05);
+        assert_eq!(status.work_mode(), UpsWorkMode::Battery);
+    }
+
+    #[tokio::test]
+    async fn commands_without_a_response_are_not_waited_on() {
+        let mock = MockTransport::new()
+            .expect_output(0, b"Q\r")
+            .expect_output(0, b"T\r")
+            .expect_output(0, b"TL\r")
+            .expect_output(0, b"T05\r")
+            .expect_output(0, b"CT\r")
+            .expect_output(0, b"S.5R0002\r")
+            .expect_output(0, b"S03R0000\r")
+            .expect_output(0, b"C\r");
+        let ups = MegatecSerialUps::new(mock).unwrap();
+
+        ups.beeper_toggle().await.unwrap();
+        ups.start_self_test(SelfTest::Quick).await.unwrap();
+        ups.start_self_test(SelfTest::UntilBatteryLow)
+            .await
+            .unwrap();
+        ups.start_self_test(SelfTest::Timed(5)).await.unwrap();
+        ups.cancel_self_test().await.unwrap();
+        ups.schedule_shutdown(Duration::from_secs(30), Some(Duration::from_secs(120)))
+            .await
+            .unwrap();
+        ups.schedule_shutdown(Duration::from_secs(180), None)
+            .await
+            .unwrap();
+        ups.cancel_shutdown().await.unwrap();
+
+        assert!(ups.start_self_test(SelfTest::Timed(100)).await.is_err());
+        assert!(ups
+            .schedule_shutdown(Duration::from_secs(1), None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn info_and_ratings() {
+        let mock = MockTransport::new()
+            .expect_command("I", "#UPS Inc.        Line 650   V1.0      ")
+            .expect_command("F", "#230.0 002 12.00 50.0");
+        let ups = MegatecSerialUps::new(mock).unwrap();
+
+        let info = ups.info().await.unwrap();
+        assert_eq!(info.manufacturer.as_deref(), Some("UPS Inc."));
+        assert_eq!(info.model.as_deref(), Some("Line 650"));
+        assert_eq!(info.firmware_version.as_deref(), Some("V1.0"));
+
+        let ratings = ups.ratings().await.unwrap();
+        assert_eq!(ratings.voltage, Some(230.0));
+        assert_eq!(ratings.current, Some(2.0));
+        assert_eq!(ratings.battery_voltage, Some(12.0));
+        assert_eq!(ratings.frequency, Some(50.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_status_times_out() {
+        let mock = MockTransport::new().expect_command_unanswered("Q1");
+        let ups = MegatecSerialUps::new(mock).unwrap();
+
+        let error = ups.status().await.unwrap_err();
+        assert!(matches!(UpsError::of(&error), Some(UpsError::Timeout(_))));
+    }
+}
@@ -0,0 +1,332 @@
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+
+use crate::{error::UpsError, transport::Transport};
+
+const TERMINATOR: char = '\r';
+
+const DEFAULT_PACKET_SIZE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Output {
+        report_id: u8,
+        data: Vec<u8>,
+    },
+    Input {
+        report_id: u8,
+        data: Vec<u8>,
+        delay: Duration,
+    },
+    Silence,
+    IndexedString {
+        index: u32,
+        response: Option<String>,
+    },
+    GetFeature {
+        report_id: u8,
+        data: Vec<u8>,
+    },
+    SendFeature {
+        report_id: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// A [`Transport`] that plays back a script of expected requests and canned
+/// responses, for testing protocol drivers without hardware.
+///
+/// Any request that doesn't match the next step of the script panics with a
+/// description of what was expected. Dropping the mock with steps left over
+/// panics as well, so tests notice when a driver stops short.
+#[derive(Debug)]
+pub struct MockTransport {
+    script: Mutex<VecDeque<Step>>,
+    packet_size: usize,
+    report_descriptor: Option<Vec<u8>>,
+    indexed_strings: bool,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self {
+            script: Mutex::new(VecDeque::new()),
+            packet_size: DEFAULT_PACKET_SIZE,
+            report_descriptor: None,
+            indexed_strings: true,
+        }
+    }
+
+    /// Set the input report payload size used to split command responses.
+    /// Applies to responses scripted after this call.
+    pub fn with_packet_size(mut self, packet_size: usize) -> Self {
+        assert!(packet_size >= 1);
+        self.packet_size = packet_size;
+        self
+    }
+
+    /// Hand out this report descriptor whenever one is asked for
+    pub fn with_report_descriptor(mut self, descriptor: &[u8]) -> Self {
+        self.report_descriptor = Some(descriptor.to_vec());
+        self
+    }
+
+    /// Fail every indexed string request as unsupported, like the hidraw
+    /// backend does
+    pub fn without_indexed_strings(mut self) -> Self {
+        self.indexed_strings = false;
+        self
+    }
+
+    /// Expect the given `\r`-terminated command and reply with `response`,
+    /// split across as many report-ID-0 input reports as it takes
+    pub fn expect_command(self, command: &str, response: &str) -> Self {
+        self.expect_command_bytes(command.as_bytes(), response.as_bytes())
+    }
+
+    /// Like [`expect_command`](Self::expect_command), for commands and
+    /// responses that aren't text, like those carrying a CRC
+    pub fn expect_command_bytes(self, command: &[u8], response: &[u8]) -> Self {
+        let mut output = command.to_vec();
+        output.push(TERMINATOR as u8);
+
+        let mut response = response.to_vec();
+        response.push(TERMINATOR as u8);
+
+        let packet_size = self.packet_size;
+        let mut this = self.expect_output(0, &output);
+        for chunk in response.chunks(packet_size) {
+            let mut packet = chunk.to_vec();
+            packet.resize(packet_size, 0);
+            this = this.input(0, &packet);
+        }
+        this
+    }
+
+    /// Expect the given command and never answer it
+    pub fn expect_command_unanswered(self, command: &str) -> Self {
+        self.expect_command_sent(command).silence()
+    }
+
+    /// Expect the given command without any read following it, for commands
+    /// the driver doesn't wait on
+    pub fn expect_command_sent(self, command: &str) -> Self {
+        let mut output = command.to_string();
+        output.push(TERMINATOR);
+
+        self.expect_output(0, output.as_bytes())
+    }
+
+    /// Expect an output report with exactly this ID and payload
+    pub fn expect_output(self, report_id: u8, data: &[u8]) -> Self {
+        self.push(Step::Output {
+            report_id,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Deliver an input report on the next read
+    pub fn input(self, report_id: u8, data: &[u8]) -> Self {
+        self.input_after(Duration::ZERO, report_id, data)
+    }
+
+    /// Deliver an input report on the next read, once `delay` has passed
+    pub fn input_after(self, delay: Duration, report_id: u8, data: &[u8]) -> Self {
+        self.push(Step::Input {
+            report_id,
+            data: data.to_vec(),
+            delay,
+        })
+    }
+
+    /// Make the next read wait forever, to exercise timeouts
+    pub fn silence(self) -> Self {
+        self.push(Step::Silence)
+    }
+
+    /// Expect a request for the string with the given index
+    pub fn expect_indexed_string(self, index: u32, response: &str) -> Self {
+        self.push(Step::IndexedString {
+            index,
+            response: Some(response.to_string()),
+        })
+    }
+
+    /// Expect a request for the string with the given index, and fail it
+    pub fn expect_indexed_string_failure(self, index: u32) -> Self {
+        self.push(Step::IndexedString {
+            index,
+            response: None,
+        })
+    }
+
+    /// Expect a read of the feature report with the given ID, and answer it
+    /// with `data`
+    pub fn expect_get_feature(self, report_id: u8, data: &[u8]) -> Self {
+        self.push(Step::GetFeature {
+            report_id,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Expect a feature report with exactly this ID and payload
+    pub fn expect_send_feature(self, report_id: u8, data: &[u8]) -> Self {
+        self.push(Step::SendFeature {
+            report_id,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Whether every scripted step has been consumed
+    pub fn is_done(&self) -> bool {
+        self.script.lock().unwrap().is_empty()
+    }
+
+    fn push(self, step: Step) -> Self {
+        self.script.lock().unwrap().push_back(step);
+        self
+    }
+
+    fn next_step(&self, request: &str) -> Step {
+        match self.script.lock().unwrap().pop_front() {
+            Some(step) => step,
+            None => panic!("MockTransport: unexpected {} after end of script", request),
+        }
+    }
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for MockTransport {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+
+        let script = self.script.get_mut().unwrap();
+        if !script.is_empty() {
+            panic!(
+                "MockTransport: script not finished, next step {:?}",
+                script[0]
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for MockTransport {
+    async fn send_output_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        let request = format!(
+            "output report {} {:?}",
+            report_id,
+            String::from_utf8_lossy(data)
+        );
+        match self.next_step(&request) {
+            Step::Output {
+                report_id: expected_report_id,
+                data: expected_data,
+            } if expected_report_id == report_id && expected_data == data => Ok(()),
+            step => panic!("MockTransport: unexpected {}, expected {:?}", request, step),
+        }
+    }
+
+    async fn read_input_report(&self) -> Result<(u8, Vec<u8>)> {
+        match self.next_step("input report read") {
+            Step::Input {
+                report_id,
+                data,
+                delay,
+            } => {
+                tokio::time::sleep(delay).await;
+                Ok((report_id, data))
+            }
+            Step::Silence => std::future::pending().await,
+            step => panic!(
+                "MockTransport: unexpected input report read, expected {:?}",
+                step
+            ),
+        }
+    }
+
+    async fn get_indexed_string(&self, index: u32) -> Result<String> {
+        if !self.indexed_strings {
+            return Err(
+                UpsError::Unsupported("Indexed strings are not scripted".to_string()).into(),
+            );
+        }
+
+        let request = format!("indexed string {} request", index);
+        match self.next_step(&request) {
+            Step::IndexedString {
+                index: expected_index,
+                response,
+            } if expected_index == index => {
+                response.ok_or_else(|| anyhow!("Scripted failure of indexed string {}", index))
+            }
+            step => panic!("MockTransport: unexpected {}, expected {:?}", request, step),
+        }
+    }
+
+    async fn get_feature_report(&self, report_id: u8) -> Result<Vec<u8>> {
+        let request = format!("feature report {} read", report_id);
+        match self.next_step(&request) {
+            Step::GetFeature {
+                report_id: expected_report_id,
+                data,
+            } if expected_report_id == report_id => Ok(data),
+            step => panic!("MockTransport: unexpected {}, expected {:?}", request, step),
+        }
+    }
+
+    async fn send_feature_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        let request = format!("feature report {} {:02x?}", report_id, data);
+        match self.next_step(&request) {
+            Step::SendFeature {
+                report_id: expected_report_id,
+                data: expected_data,
+            } if expected_report_id == report_id && expected_data == data => Ok(()),
+            step => panic!("MockTransport: unexpected {}, expected {:?}", request, step),
+        }
+    }
+
+    async fn report_descriptor(&self) -> Result<Vec<u8>> {
+        self.report_descriptor.clone().ok_or_else(|| {
+            UpsError::Unsupported("No report descriptor scripted".to_string()).into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn command_response_is_split_into_packets() {
+        let mock = MockTransport::new().expect_command("QS", "(123456789");
+
+        mock.send_output_report(0, b"QS\r").await.unwrap();
+        assert_eq!(mock.read_input_report().await.unwrap().1, b"(1234567");
+        assert_eq!(mock.read_input_report().await.unwrap().1, b"89\r\0\0\0\0\0");
+        assert!(mock.is_done());
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "unexpected output report")]
+    async fn unexpected_command_panics() {
+        let mock = MockTransport::new().expect_command("M", "V");
+
+        let _ = mock.send_output_report(0, b"QS\r").await;
+    }
+
+    #[test]
+    #[should_panic(expected = "script not finished")]
+    fn unfinished_script_panics() {
+        let _mock = MockTransport::new().expect_indexed_string(3, "(");
+    }
+}
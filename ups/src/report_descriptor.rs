@@ -0,0 +1,1073 @@
+use std::{collections::HashMap, fmt};
+
+use anyhow::{anyhow, bail, Result};
+use bitflags::bitflags;
+
+use crate::error::UpsError;
+
+// The limits Linux's HID parser enforces, far beyond anything a UPS declares.
+// Anything larger is a garbled or hostile descriptor.
+const MAX_REPORT_SIZE: usize = 256;
+const MAX_REPORT_COUNT: usize = 12288;
+const MAX_USAGES: usize = 12288;
+const MAX_REPORT_BITS: usize = 16384 * 8;
+
+/// A usage page and usage ID pair
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Usage {
+    pub page: u16,
+    pub id: u16,
+}
+
+impl Usage {
+    pub const fn new(page: u16, id: u16) -> Self {
+        Self { page, id }
+    }
+
+    /// Split an extended usage, which carries the page in its high word
+    pub const fn from_extended(usage: u32) -> Self {
+        Self {
+            page: (usage >> 16) as u16,
+            id: usage as u16,
+        }
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04X}:{:04X}", self.page, self.id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReportKind {
+    Input,
+    Output,
+    Feature,
+}
+
+bitflags! {
+    /// Data flags of an Input, Output or Feature item
+    #[derive(Default)]
+    pub struct ItemFlags: u32 {
+        const CONSTANT       = 1 << 0;
+        const VARIABLE       = 1 << 1;
+        const RELATIVE       = 1 << 2;
+        const WRAP           = 1 << 3;
+        const NON_LINEAR     = 1 << 4;
+        const NO_PREFERRED   = 1 << 5;
+        const NULL_STATE     = 1 << 6;
+        const VOLATILE       = 1 << 7;
+        const BUFFERED_BYTES = 1 << 8;
+    }
+}
+
+/// A run of equally-sized values in a report.
+///
+/// Variable items are split into one field per value, so each such field has
+/// a `count` of 1 and exactly one usage. Array fields keep their count, and
+/// list every usage a value can select.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportField {
+    pub kind: ReportKind,
+    /// 0 if the device doesn't use report IDs
+    pub report_id: u8,
+    pub flags: ItemFlags,
+    /// Offset from the start of the report payload, not counting the report
+    /// ID byte
+    pub bit_offset: usize,
+    pub bit_size: usize,
+    pub count: usize,
+    pub usages: Vec<Usage>,
+    /// Usages of the enclosing collections, outermost first
+    pub collections: Vec<Usage>,
+    pub logical_minimum: i32,
+    pub logical_maximum: i32,
+    pub physical_minimum: i32,
+    pub physical_maximum: i32,
+    /// Encoded as in the HID spec, section 6.2.2.7
+    pub unit: u32,
+    pub unit_exponent: i8,
+}
+
+impl ReportField {
+    pub fn is_constant(&self) -> bool {
+        self.flags.contains(ItemFlags::CONSTANT)
+    }
+
+    pub fn is_variable(&self) -> bool {
+        self.flags.contains(ItemFlags::VARIABLE)
+    }
+
+    /// Read the `index`th value from a report payload. Values are sign
+    /// extended if the logical range is signed.
+    pub fn extract(&self, payload: &[u8], index: usize) -> Result<i32> {
+        let start = self.value_offset(index)?;
+        if start + self.bit_size > payload.len() * 8 {
+            bail!(
+                "Report payload of {} bytes is too short for field at bit {}",
+                payload.len(),
+                start
+            );
+        }
+
+        let mut raw = 0u32;
+        for bit in 0..self.bit_size {
+            let position = start + bit;
+            if payload[position / 8] & (1 << (position % 8)) != 0 {
+                raw |= 1 << bit;
+            }
+        }
+
+        Ok(if self.logical_minimum < 0 {
+            sign_extend(raw, self.bit_size)
+        } else {
+            raw as i32
+        })
+    }
+
+    /// Write the `index`th value into a report payload
+    pub fn insert(&self, payload: &mut [u8], index: usize, value: i32) -> Result<()> {
+        let start = self.value_offset(index)?;
+        if start + self.bit_size > payload.len() * 8 {
+            bail!(
+                "Report payload of {} bytes is too short for field at bit {}",
+                payload.len(),
+                start
+            );
+        }
+
+        let raw = value as u32;
+        for bit in 0..self.bit_size {
+            let position = start + bit;
+            let mask = 1 << (position % 8);
+            if raw & (1 << bit) != 0 {
+                payload[position / 8] |= mask;
+            } else {
+                payload[position / 8] &= !mask;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Convert a logical value to physical units, applying the physical
+    /// range and the unit exponent
+    pub fn to_physical(&self, value: i32) -> f64 {
+        let (physical_minimum, physical_maximum) =
+            if self.physical_minimum == 0 && self.physical_maximum == 0 {
+                // No physical range means it equals the logical one
+                (self.logical_minimum, self.logical_maximum)
+            } else {
+                (self.physical_minimum, self.physical_maximum)
+            };
+
+        let logical_range = f64::from(self.logical_maximum) - f64::from(self.logical_minimum);
+        let physical = if logical_range == 0.0 {
+            f64::from(value)
+        } else {
+            (f64::from(value) - f64::from(self.logical_minimum))
+                * (f64::from(physical_maximum) - f64::from(physical_minimum))
+                / logical_range
+                + f64::from(physical_minimum)
+        };
+
+        physical * 10f64.powi(self.unit_exponent.into())
+    }
+
+    fn value_offset(&self, index: usize) -> Result<usize> {
+        if index >= self.count {
+            bail!(
+                "Value index {} out of range for field of {}",
+                index,
+                self.count
+            );
+        }
+        if self.bit_size > 32 {
+            bail!("Fields wider than 32 bits are not supported");
+        }
+        Ok(self.bit_offset + index * self.bit_size)
+    }
+
+    /// Whether this is a variable field whose usage, preceded by the usages
+    /// of its collections, ends with `path`
+    fn matches_path(&self, path: &[Usage]) -> bool {
+        if !self.is_variable() || self.usages.len() != 1 {
+            return false;
+        }
+
+        let full_path = self.collections.iter().chain(&self.usages);
+        let skip = (self.collections.len() + 1).checked_sub(path.len());
+        match skip {
+            Some(skip) => full_path.skip(skip).eq(path.iter()),
+            None => false,
+        }
+    }
+}
+
+/// A parsed HID report descriptor
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReportDescriptor {
+    /// Usages of the top-level application collections
+    pub applications: Vec<Usage>,
+
+    /// Whether reports are prefixed with a report ID
+    pub uses_report_ids: bool,
+
+    /// Length of the longest input report, including the report ID byte
+    pub input_report_size: usize,
+
+    /// Length of the longest output report, including the report ID byte
+    pub output_report_size: usize,
+
+    /// Length of the longest feature report, including the report ID byte
+    pub feature_report_size: usize,
+
+    /// Every field of every report, in descriptor order
+    pub fields: Vec<ReportField>,
+}
+
+impl ReportDescriptor {
+    pub fn parse(descriptor: &[u8]) -> Result<Self> {
+        // https://www.usb.org/sites/default/files/hid1_11.pdf, section 6.2.2
+
+        const MAIN_ITEM: u8 = 0;
+        const GLOBAL_ITEM: u8 = 1;
+        const LOCAL_ITEM: u8 = 2;
+
+        const INPUT: u8 = 0x8;
+        const OUTPUT: u8 = 0x9;
+        const FEATURE: u8 = 0xB;
+        const COLLECTION: u8 = 0xA;
+        const END_COLLECTION: u8 = 0xC;
+
+        const USAGE_PAGE: u8 = 0x0;
+        const LOGICAL_MINIMUM: u8 = 0x1;
+        const LOGICAL_MAXIMUM: u8 = 0x2;
+        const PHYSICAL_MINIMUM: u8 = 0x3;
+        const PHYSICAL_MAXIMUM: u8 = 0x4;
+        const UNIT_EXPONENT: u8 = 0x5;
+        const UNIT: u8 = 0x6;
+        const REPORT_SIZE: u8 = 0x7;
+        const REPORT_ID: u8 = 0x8;
+        const REPORT_COUNT: u8 = 0x9;
+        const PUSH: u8 = 0xA;
+        const POP: u8 = 0xB;
+
+        const USAGE: u8 = 0x0;
+        const USAGE_MINIMUM: u8 = 0x1;
+        const USAGE_MAXIMUM: u8 = 0x2;
+
+        const APPLICATION_COLLECTION: u32 = 1;
+        const LONG_ITEM_PREFIX: u8 = 0xFE;
+
+        let mut result = Self::default();
+
+        let mut globals = Globals::default();
+        let mut global_stack: Vec<Globals> = Vec::new();
+        let mut locals = Locals::default();
+        let mut collections: Vec<Usage> = Vec::new();
+
+        let mut bit_offsets: HashMap<(ReportKind, u8), usize> = HashMap::new();
+
+        let mut offset = 0;
+        while offset < descriptor.len() {
+            let prefix = descriptor[offset];
+
+            if prefix == LONG_ITEM_PREFIX {
+                let size = *descriptor
+                    .get(offset + 1)
+                    .ok_or_else(|| anyhow!("Truncated long item"))?;
+                offset += 3 + usize::from(size);
+                continue;
+            }
+
+            let size = match prefix & 0b11 {
+                3 => 4,
+                size => usize::from(size),
+            };
+            let item_type = (prefix >> 2) & 0b11;
+            let tag = prefix >> 4;
+
+            let data = descriptor
+                .get(offset + 1..offset + 1 + size)
+                .ok_or_else(|| anyhow!("Truncated short item"))?;
+            let value = data
+                .iter()
+                .rev()
+                .fold(0u32, |value, &byte| (value << 8) | u32::from(byte));
+            let signed_value = sign_extend(value, size * 8);
+
+            offset += 1 + size;
+
+            match (item_type, tag) {
+                (MAIN_ITEM, INPUT) | (MAIN_ITEM, OUTPUT) | (MAIN_ITEM, FEATURE) => {
+                    let kind = match tag {
+                        INPUT => ReportKind::Input,
+                        OUTPUT => ReportKind::Output,
+                        _ => ReportKind::Feature,
+                    };
+                    let bit_offset = bit_offsets.entry((kind, globals.report_id)).or_default();
+                    let end = globals
+                        .report_size
+                        .checked_mul(globals.report_count)
+                        .and_then(|bits| bit_offset.checked_add(bits))
+                        .filter(|&end| end <= MAX_REPORT_BITS)
+                        .ok_or_else(|| {
+                            UpsError::Framing(format!(
+                                "Report {} is longer than {} bits",
+                                globals.report_id, MAX_REPORT_BITS
+                            ))
+                        })?;
+
+                    let flags = ItemFlags::from_bits_truncate(value);
+                    let usages = std::mem::take(&mut locals.usages);
+
+                    let field = ReportField {
+                        kind,
+                        report_id: globals.report_id,
+                        flags,
+                        bit_offset: *bit_offset,
+                        bit_size: globals.report_size,
+                        count: globals.report_count,
+                        usages: Vec::new(),
+                        collections: collections.clone(),
+                        logical_minimum: globals.logical_minimum,
+                        logical_maximum: globals.logical_maximum(),
+                        physical_minimum: globals.physical_minimum,
+                        physical_maximum: globals.physical_maximum,
+                        unit: globals.unit,
+                        unit_exponent: globals.unit_exponent,
+                    };
+
+                    if flags.contains(ItemFlags::VARIABLE) && !usages.is_empty() {
+                        // The last usage applies to any remaining values
+                        for index in 0..globals.report_count {
+                            let usage = usages[index.min(usages.len() - 1)];
+                            result.fields.push(ReportField {
+                                bit_offset: *bit_offset + index * globals.report_size,
+                                count: 1,
+                                usages: vec![usage],
+                                ..field.clone()
+                            });
+                        }
+                    } else {
+                        result.fields.push(ReportField { usages, ..field });
+                    }
+
+                    *bit_offset = end;
+                    locals = Locals::default();
+                }
+                (MAIN_ITEM, COLLECTION) => {
+                    let usage = locals
+                        .usages
+                        .first()
+                        .copied()
+                        .unwrap_or(Usage::new(globals.usage_page, 0));
+                    if collections.is_empty() && value == APPLICATION_COLLECTION {
+                        result.applications.push(usage);
+                    }
+                    collections.push(usage);
+                    locals = Locals::default();
+                }
+                (MAIN_ITEM, END_COLLECTION) => {
+                    if collections.pop().is_none() {
+                        bail!("Unbalanced End Collection item");
+                    }
+                    locals = Locals::default();
+                }
+                (MAIN_ITEM, _) => locals = Locals::default(),
+                (GLOBAL_ITEM, USAGE_PAGE) => globals.usage_page = value as u16,
+                (GLOBAL_ITEM, LOGICAL_MINIMUM) => globals.logical_minimum = signed_value,
+                (GLOBAL_ITEM, LOGICAL_MAXIMUM) => globals.logical_maximum = (value, size),
+                (GLOBAL_ITEM, PHYSICAL_MINIMUM) => globals.physical_minimum = signed_value,
+                (GLOBAL_ITEM, PHYSICAL_MAXIMUM) => globals.physical_maximum = signed_value,
+                (GLOBAL_ITEM, UNIT_EXPONENT) => {
+                    // The spec stores the exponent in a nibble, but plenty of
+                    // devices put a whole signed byte there.
+                    globals.unit_exponent = if value <= 0xF {
+                        sign_extend(value, 4) as i8
+                    } else {
+                        signed_value as i8
+                    };
+                }
+                (GLOBAL_ITEM, UNIT) => globals.unit = value,
+                (GLOBAL_ITEM, REPORT_SIZE) => {
+                    if value as usize > MAX_REPORT_SIZE {
+                        bail!(UpsError::Framing(format!("Invalid report size {}", value)));
+                    }
+                    globals.report_size = value as usize;
+                }
+                (GLOBAL_ITEM, REPORT_COUNT) => {
+                    if value as usize > MAX_REPORT_COUNT {
+                        bail!(UpsError::Framing(format!("Invalid report count {}", value)));
+                    }
+                    globals.report_count = value as usize;
+                }
+                (GLOBAL_ITEM, REPORT_ID) => {
+                    if value == 0 || value > 0xFF {
+                        bail!("Invalid report ID {}", value);
+                    }
+                    globals.report_id = value as u8;
+                    result.uses_report_ids = true;
+                }
+                (GLOBAL_ITEM, PUSH) => global_stack.push(globals),
+                (GLOBAL_ITEM, POP) => {
+                    globals = global_stack
+                        .pop()
+                        .ok_or_else(|| anyhow!("Pop item without a matching Push"))?;
+                }
+                (LOCAL_ITEM, USAGE) => {
+                    locals.add_usages(&[Locals::usage(globals.usage_page, value, size)])?;
+                }
+                (LOCAL_ITEM, USAGE_MINIMUM) => {
+                    locals.usage_minimum = Some(Locals::usage(globals.usage_page, value, size));
+                    locals.add_range()?;
+                }
+                (LOCAL_ITEM, USAGE_MAXIMUM) => {
+                    locals.usage_maximum = Some(Locals::usage(globals.usage_page, value, size));
+                    locals.add_range()?;
+                }
+                _ => {}
+            }
+        }
+
+        if !collections.is_empty() {
+            bail!("Unterminated collection");
+        }
+
+        let size = |kind| {
+            bit_offsets
+                .iter()
+                .filter(|((report_kind, _), _)| *report_kind == kind)
+                .map(|(_, &bits)| bits)
+                .max()
+                .map_or(0, Self::bytes_with_report_id)
+        };
+        result.input_report_size = size(ReportKind::Input);
+        result.output_report_size = size(ReportKind::Output);
+        result.feature_report_size = size(ReportKind::Feature);
+
+        Ok(result)
+    }
+
+    /// Length of a specific report, including the report ID byte, or `None`
+    /// if the descriptor doesn't declare it
+    pub fn report_size(&self, kind: ReportKind, report_id: u8) -> Option<usize> {
+        self.fields
+            .iter()
+            .filter(|field| field.kind == kind && field.report_id == report_id)
+            .map(|field| field.bit_offset + field.bit_size * field.count)
+            .max()
+            .map(Self::bytes_with_report_id)
+    }
+
+    /// Find the variable field whose usage path ends with `path`. For
+    /// example, `[Output, Voltage]` in the Power Device page finds
+    /// `UPS.Output.Voltage` but not `UPS.Input.Voltage`.
+    pub fn find(&self, kind: ReportKind, path: &[Usage]) -> Option<&ReportField> {
+        self.fields
+            .iter()
+            .find(|field| field.kind == kind && field.matches_path(path))
+    }
+
+    /// Assemble a descriptor that [`parse`](Self::parse)s back into
+    /// `fields`, for backends that can't fetch the device's own. Fields of
+    /// each report must be in bit order.
+    ///
+    /// Gaps between fields become constant padding, and fields overlapping
+    /// an earlier one are dropped. Collections are all logical ones, apart
+    /// from the outermost, which are application collections.
+    pub fn encode(fields: &[ReportField]) -> Vec<u8> {
+        let mut descriptor = Vec::new();
+        let mut item = |prefix: u8, data: &[u8]| {
+            descriptor.push(prefix);
+            descriptor.extend_from_slice(data);
+        };
+        let extended =
+            |usage: Usage| ((u32::from(usage.page) << 16) | u32::from(usage.id)).to_le_bytes();
+
+        let mut collections: &[Usage] = &[];
+        let mut bit_offsets: HashMap<(ReportKind, u8), usize> = HashMap::new();
+
+        for field in fields {
+            let bit_offset = bit_offsets
+                .entry((field.kind, field.report_id))
+                .or_default();
+            if field.bit_offset < *bit_offset {
+                continue;
+            }
+
+            let common = collections
+                .iter()
+                .zip(&field.collections)
+                .take_while(|(a, b)| a == b)
+                .count();
+            for _ in common..collections.len() {
+                item(0xC0, &[]); // End Collection
+            }
+            for (depth, &usage) in field.collections.iter().enumerate().skip(common) {
+                item(0x0B, &extended(usage)); // Usage
+                item(0xA1, &[if depth == 0 { 1 } else { 2 }]); // Collection
+            }
+            collections = &field.collections;
+
+            if field.report_id != 0 {
+                item(0x85, &[field.report_id]); // Report ID
+            }
+
+            let main_item = match field.kind {
+                ReportKind::Input => 0x82,
+                ReportKind::Output => 0x92,
+                ReportKind::Feature => 0xB2,
+            };
+
+            // Report Count is capped, so long gaps take several items
+            let mut gap = field.bit_offset - *bit_offset;
+            while gap > 0 {
+                let count = gap.min(MAX_REPORT_COUNT);
+                item(0x77, &1u32.to_le_bytes()); // Report Size
+                item(0x97, &(count as u32).to_le_bytes()); // Report Count
+                item(
+                    main_item,
+                    &(ItemFlags::CONSTANT.bits() as u16).to_le_bytes(),
+                );
+                gap -= count;
+            }
+
+            item(0x17, &field.logical_minimum.to_le_bytes());
+            item(0x27, &field.logical_maximum.to_le_bytes());
+            item(0x37, &field.physical_minimum.to_le_bytes());
+            item(0x47, &field.physical_maximum.to_le_bytes());
+            item(0x55, &[field.unit_exponent as u8]);
+            item(0x67, &field.unit.to_le_bytes());
+            item(0x77, &(field.bit_size as u32).to_le_bytes());
+            item(0x97, &(field.count as u32).to_le_bytes());
+            for &usage in &field.usages {
+                item(0x0B, &extended(usage));
+            }
+            item(main_item, &(field.flags.bits() as u16).to_le_bytes());
+
+            *bit_offset = field.bit_offset + field.bit_size * field.count;
+        }
+
+        for _ in collections {
+            item(0xC0, &[]);
+        }
+
+        descriptor
+    }
+
+    fn bytes_with_report_id(bits: usize) -> usize {
+        // Sizes always include the report ID byte, even when the device
+        // doesn't use report IDs. This matches HidP_GetCaps.
+        1 + bits.div_ceil(8)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Globals {
+    usage_page: u16,
+    logical_minimum: i32,
+    /// Raw value and size, since its signedness depends on the minimum
+    logical_maximum: (u32, usize),
+    physical_minimum: i32,
+    physical_maximum: i32,
+    unit_exponent: i8,
+    unit: u32,
+    report_size: usize,
+    report_id: u8,
+    report_count: usize,
+}
+
+impl Globals {
+    fn logical_maximum(&self) -> i32 {
+        let (value, size) = self.logical_maximum;
+        let signed = sign_extend(value, size * 8);
+
+        // Lots of devices declare e.g. 0..255 in one byte each, which
+        // strictly read is 0..-1.
+        if signed < self.logical_minimum {
+            value as i32
+        } else {
+            signed
+        }
+    }
+}
+
+/// Local items. Short usages are combined with the usage page in effect when
+/// they're declared, and Usage Minimum/Maximum ranges are expanded once both
+/// ends are known, so `usages` stays in declaration order.
+#[derive(Debug, Clone, Default)]
+struct Locals {
+    usages: Vec<Usage>,
+    usage_minimum: Option<Usage>,
+    usage_maximum: Option<Usage>,
+}
+
+impl Locals {
+    fn usage(usage_page: u16, value: u32, size: usize) -> Usage {
+        // A 4-byte usage carries its own usage page in the high word
+        if size == 4 {
+            Usage::from_extended(value)
+        } else {
+            Usage::new(usage_page, value as u16)
+        }
+    }
+
+    fn add_usages(&mut self, usages: &[Usage]) -> Result<()> {
+        if self.usages.len() + usages.len() > MAX_USAGES {
+            bail!(UpsError::Framing(format!(
+                "More than {} usages on one item",
+                MAX_USAGES
+            )));
+        }
+
+        self.usages.extend_from_slice(usages);
+        Ok(())
+    }
+
+    fn add_range(&mut self) -> Result<()> {
+        if let (Some(minimum), Some(maximum)) = (self.usage_minimum, self.usage_maximum) {
+            self.usage_minimum = None;
+            self.usage_maximum = None;
+
+            let range: Vec<_> = (minimum.id..=maximum.id)
+                .take(MAX_USAGES + 1)
+                .map(|id| Usage::new(minimum.page, id))
+                .collect();
+            self.add_usages(&range)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn sign_extend(value: u32, bits: usize) -> i32 {
+    if bits == 0 || bits >= 32 {
+        return value as i32;
+    }
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POWER_DEVICE: u16 = 0x84;
+    const BATTERY_SYSTEM: u16 = 0x85;
+
+    /// Shaped like the vendor interface of the 0665:5161 Megatec/Voltronic
+    /// units: one application collection with unnumbered 8-byte input and
+    /// output reports.
+    const VENDOR_DESCRIPTOR: &[u8] = &[
+        0x06, 0x00, 0xFF, // Usage Page (Vendor Defined 0xFF00)
+        0x09, 0x01, // Usage (0x01)
+        0xA1, 0x01, // Collection (Application)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x26, 0xFF, 0x00, //   Logical Maximum (255)
+        0x75, 0x08, //   Report Size (8)
+        0x95, 0x08, //   Report Count (8)
+        0x09, 0x01, //   Usage (0x01)
+        0x81, 0x02, //   Input (Data,Var,Abs)
+        0x09, 0x01, //   Usage (0x01)
+        0x91, 0x02, //   Output (Data,Var,Abs)
+        0xC0, // End Collection
+    ];
+
+    /// Hand-assembled in the shape HID Power Device UPSes use: numbered
+    /// feature reports under UPS.PowerSummary and UPS.Output, a PresentStatus
+    /// bit field with padding, a 0..255 range written in one byte, units
+    /// with exponents, and Push/Pop. The report layout follows what such
+    /// devices report, but the bytes are not a dump of a particular unit.
+    const POWER_DEVICE_DESCRIPTOR: &[u8] = &[
+        0x05, 0x84, // Usage Page (Power Device)
+        0x09, 0x04, // Usage (UPS)
+        0xA1, 0x01, // Collection (Application)
+        0x09, 0x24, //   Usage (PowerSummary)
+        0xA1, 0x02, //   Collection (Logical)
+        0x85, 0x01, //     Report ID (1)
+        0x05, 0x85, //     Usage Page (Battery System)
+        0x09, 0x66, //     Usage (RemainingCapacity)
+        0x09, 0x68, //     Usage (RunTimeToEmpty)
+        0x15, 0x00, //     Logical Minimum (0)
+        0x26, 0xFF, 0x7F, //     Logical Maximum (32767)
+        0x75, 0x10, //     Report Size (16)
+        0x95, 0x02, //     Report Count (2)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0x05, 0x84, //     Usage Page (Power Device)
+        0x09, 0x30, //     Usage (Voltage)
+        0x67, 0x21, 0xD1, 0xF0, 0x00, //     Unit (SI Linear: Volt)
+        0x55, 0x0E, //     Unit Exponent (-2)
+        0x26, 0xFF, 0x7F, //     Logical Maximum (32767)
+        0x95, 0x01, //     Report Count (1)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0x65, 0x00, //     Unit (None)
+        0x55, 0x00, //     Unit Exponent (0)
+        0x09, 0x02, //     Usage (PresentStatus)
+        0xA1, 0x02, //     Collection (Logical)
+        0x85, 0x02, //       Report ID (2)
+        0x05, 0x85, //       Usage Page (Battery System)
+        0x09, 0xD0, //       Usage (ACPresent)
+        0x09, 0x44, //       Usage (Charging)
+        0x09, 0x45, //       Usage (Discharging)
+        0x05, 0x84, //       Usage Page (Power Device)
+        0x09, 0x69, //       Usage (ShutdownImminent)
+        0x25, 0x01, //       Logical Maximum (1)
+        0x75, 0x01, //       Report Size (1)
+        0x95, 0x04, //       Report Count (4)
+        0x81, 0x02, //       Input (Data,Var,Abs)
+        0x05, 0x85, //       Usage Page (Battery System)
+        0x09, 0xD0, //       Usage (ACPresent)
+        0x09, 0x44, //       Usage (Charging)
+        0x09, 0x45, //       Usage (Discharging)
+        0x05, 0x84, //       Usage Page (Power Device)
+        0x09, 0x69, //       Usage (ShutdownImminent)
+        0xB1, 0x02, //       Feature (Data,Var,Abs)
+        0x95, 0x04, //       Report Count (4)
+        0x81, 0x03, //       Input (Cnst,Var,Abs)
+        0xB1, 0x03, //       Feature (Cnst,Var,Abs)
+        0xC0, //     End Collection
+        0x85, 0x03, //     Report ID (3)
+        0xA4, //     Push
+        0x05, 0x85, //     Usage Page (Battery System)
+        0x09, 0x5A, //     Usage (AudibleAlarmControl)
+        0x15, 0x01, //     Logical Minimum (1)
+        0x25, 0x03, //     Logical Maximum (3)
+        0x75, 0x08, //     Report Size (8)
+        0x95, 0x01, //     Report Count (1)
+        0xB1, 0x22, //     Feature (Data,Var,Abs,NoPref)
+        0xB4, //     Pop
+        0xC0, //   End Collection
+        0x09, 0x1C, //   Usage (Output)
+        0xA1, 0x02, //   Collection (Logical)
+        0x85, 0x04, //     Report ID (4)
+        0x09, 0x35, //     Usage (PercentLoad)
+        0x25, 0xFF, //     Logical Maximum (255)
+        0x75, 0x08, //     Report Size (8)
+        0x95, 0x01, //     Report Count (1)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0x09, 0x30, //     Usage (Voltage)
+        0x67, 0x21, 0xD1, 0xF0, 0x00, //     Unit (SI Linear: Volt)
+        0x55, 0x0F, //     Unit Exponent (-1)
+        0x26, 0xFF, 0x00, //     Logical Maximum (255)
+        0x35, 0x00, //     Physical Minimum (0)
+        0x46, 0xEC, 0x13, //     Physical Maximum (5100)
+        0xB1, 0x02, //     Feature (Data,Var,Abs)
+        0xC0, //   End Collection
+        0xC0, // End Collection
+    ];
+
+    fn usage(page: u16, id: u16) -> Usage {
+        Usage::new(page, id)
+    }
+
+    #[test]
+    fn vendor_descriptor() {
+        let descriptor = ReportDescriptor::parse(VENDOR_DESCRIPTOR).unwrap();
+
+        assert_eq!(descriptor.applications, [usage(0xFF00, 0x01)]);
+        assert!(!descriptor.uses_report_ids);
+        assert_eq!(descriptor.input_report_size, 9);
+        assert_eq!(descriptor.output_report_size, 9);
+        assert_eq!(descriptor.feature_report_size, 0);
+        assert_eq!(descriptor.report_size(ReportKind::Input, 0), Some(9));
+        assert_eq!(descriptor.report_size(ReportKind::Feature, 0), None);
+
+        // Eight variable values, all sharing the last usage
+        let inputs: Vec<_> = descriptor
+            .fields
+            .iter()
+            .filter(|field| field.kind == ReportKind::Input)
+            .collect();
+        assert_eq!(inputs.len(), 8);
+        assert_eq!(inputs[7].bit_offset, 56);
+        assert_eq!(inputs[7].usages, [usage(0xFF00, 0x01)]);
+        assert_eq!(inputs[7].logical_maximum, 255);
+    }
+
+    #[test]
+    fn power_device_layout() {
+        let descriptor = ReportDescriptor::parse(POWER_DEVICE_DESCRIPTOR).unwrap();
+
+        assert_eq!(descriptor.applications, [usage(POWER_DEVICE, 0x04)]);
+        assert!(descriptor.uses_report_ids);
+        assert_eq!(descriptor.input_report_size, 2);
+        assert_eq!(descriptor.output_report_size, 0);
+        assert_eq!(descriptor.feature_report_size, 7);
+        assert_eq!(descriptor.report_size(ReportKind::Feature, 1), Some(7));
+        assert_eq!(descriptor.report_size(ReportKind::Feature, 2), Some(2));
+        assert_eq!(descriptor.report_size(ReportKind::Feature, 3), Some(2));
+        assert_eq!(descriptor.report_size(ReportKind::Feature, 4), Some(3));
+
+        let run_time = descriptor
+            .find(ReportKind::Feature, &[usage(BATTERY_SYSTEM, 0x68)])
+            .unwrap();
+        assert_eq!(run_time.report_id, 1);
+        assert_eq!(run_time.bit_offset, 16);
+        assert_eq!(run_time.bit_size, 16);
+        assert_eq!(
+            run_time.collections,
+            [usage(POWER_DEVICE, 0x04), usage(POWER_DEVICE, 0x24)]
+        );
+
+        let discharging = descriptor
+            .find(
+                ReportKind::Input,
+                &[usage(POWER_DEVICE, 0x02), usage(BATTERY_SYSTEM, 0x45)],
+            )
+            .unwrap();
+        assert_eq!(discharging.report_id, 2);
+        assert_eq!(discharging.bit_offset, 2);
+        assert_eq!(discharging.bit_size, 1);
+
+        let padding = descriptor
+            .fields
+            .iter()
+            .find(|field| field.kind == ReportKind::Input && field.is_constant())
+            .unwrap();
+        assert_eq!((padding.bit_offset, padding.count), (4, 4));
+        assert!(padding.usages.is_empty());
+    }
+
+    #[test]
+    fn paths_distinguish_collections() {
+        let descriptor = ReportDescriptor::parse(POWER_DEVICE_DESCRIPTOR).unwrap();
+
+        let summary_voltage = descriptor
+            .find(
+                ReportKind::Feature,
+                &[usage(POWER_DEVICE, 0x24), usage(POWER_DEVICE, 0x30)],
+            )
+            .unwrap();
+        assert_eq!(summary_voltage.report_id, 1);
+
+        let output_voltage = descriptor
+            .find(
+                ReportKind::Feature,
+                &[usage(POWER_DEVICE, 0x1C), usage(POWER_DEVICE, 0x30)],
+            )
+            .unwrap();
+        assert_eq!(output_voltage.report_id, 4);
+
+        assert!(descriptor
+            .find(
+                ReportKind::Feature,
+                &[usage(POWER_DEVICE, 0x1A), usage(POWER_DEVICE, 0x30)],
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn units_and_ranges() {
+        let descriptor = ReportDescriptor::parse(POWER_DEVICE_DESCRIPTOR).unwrap();
+
+        let summary_voltage = descriptor
+            .find(
+                ReportKind::Feature,
+                &[usage(POWER_DEVICE, 0x24), usage(POWER_DEVICE, 0x30)],
+            )
+            .unwrap();
+        assert_eq!(summary_voltage.unit, 0x00F0D121);
+        assert_eq!(summary_voltage.unit_exponent, -2);
+        assert!((summary_voltage.to_physical(1350) - 13.5).abs() < 1e-9);
+
+        // A physical range 20 times the logical one, in tenths of a volt
+        let output_voltage = descriptor
+            .find(
+                ReportKind::Feature,
+                &[usage(POWER_DEVICE, 0x1C), usage(POWER_DEVICE, 0x30)],
+            )
+            .unwrap();
+        assert_eq!(output_voltage.unit_exponent, -1);
+        assert!((output_voltage.to_physical(115) - 230.0).abs() < 1e-9);
+
+        // One-byte 0..255 is read as unsigned. The minimum of 0 is the one
+        // Pop restored after the alarm control's 1..3.
+        let percent_load = descriptor
+            .find(ReportKind::Feature, &[usage(POWER_DEVICE, 0x35)])
+            .unwrap();
+        assert_eq!(percent_load.logical_minimum, 0);
+        assert_eq!(percent_load.logical_maximum, 255);
+        assert_eq!(percent_load.extract(&[200, 0], 0).unwrap(), 200);
+
+        let alarm = descriptor
+            .find(ReportKind::Feature, &[usage(BATTERY_SYSTEM, 0x5A)])
+            .unwrap();
+        assert_eq!(alarm.report_id, 3);
+        assert_eq!((alarm.logical_minimum, alarm.logical_maximum), (1, 3));
+        assert!(alarm.flags.contains(ItemFlags::NO_PREFERRED));
+    }
+
+    #[test]
+    fn extract_and_insert() {
+        let descriptor = ReportDescriptor::parse(POWER_DEVICE_DESCRIPTOR).unwrap();
+        let field = |id| {
+            descriptor
+                .find(ReportKind::Input, &[usage(BATTERY_SYSTEM, id)])
+                .unwrap()
+        };
+
+        let mut payload = [0u8; 1];
+        field(0xD0).insert(&mut payload, 0, 1).unwrap();
+        field(0x45).insert(&mut payload, 0, 1).unwrap();
+        assert_eq!(payload, [0b0000_0101]);
+        assert_eq!(field(0x44).extract(&payload, 0).unwrap(), 0);
+        assert_eq!(field(0x45).extract(&payload, 0).unwrap(), 1);
+
+        field(0xD0).insert(&mut payload, 0, 0).unwrap();
+        assert_eq!(payload, [0b0000_0100]);
+
+        let run_time = descriptor
+            .find(ReportKind::Feature, &[usage(BATTERY_SYSTEM, 0x68)])
+            .unwrap();
+        assert_eq!(run_time.extract(&[0x64, 0x00, 0x2C, 0x01], 0).unwrap(), 300);
+        assert!(run_time.extract(&[0x64, 0x00, 0x2C], 0).is_err());
+        assert!(run_time.extract(&[0x64, 0x00, 0x2C, 0x01], 1).is_err());
+    }
+
+    #[test]
+    fn signed_values() {
+        let descriptor = ReportDescriptor::parse(&[
+            0x05, 0x01, // Usage Page (Generic Desktop)
+            0x09, 0x02, // Usage (Mouse)
+            0xA1, 0x01, // Collection (Application)
+            0x09, 0x30, //   Usage (X)
+            0x15, 0x81, //   Logical Minimum (-127)
+            0x25, 0x7F, //   Logical Maximum (127)
+            0x75, 0x08, //   Report Size (8)
+            0x95, 0x01, //   Report Count (1)
+            0x81, 0x06, //   Input (Data,Var,Rel)
+            0xC0, // End Collection
+        ])
+        .unwrap();
+
+        let x = &descriptor.fields[0];
+        assert_eq!((x.logical_minimum, x.logical_maximum), (-127, 127));
+        assert_eq!(x.extract(&[0xFE], 0).unwrap(), -2);
+        assert!(x.flags.contains(ItemFlags::RELATIVE));
+    }
+
+    #[test]
+    fn usage_ranges_and_extended_usages() {
+        let descriptor = ReportDescriptor::parse(&[
+            0x05, 0x84, // Usage Page (Power Device)
+            0x09, 0x04, // Usage (UPS)
+            0xA1, 0x01, // Collection (Application)
+            0x09, 0x02, //   Usage (PresentStatus)
+            0x19, 0x03, //   Usage Minimum (0x03)
+            0x29, 0x05, //   Usage Maximum (0x05)
+            0x0B, 0x44, 0x00, 0x85, 0x00, //   Usage (Battery System: Charging)
+            0x29, 0x07, //   Usage Maximum (0x07)
+            0x19, 0x06, //   Usage Minimum (0x06)
+            0x75, 0x01, //   Report Size (1)
+            0x95, 0x04, //   Report Count (4)
+            0x25, 0x01, //   Logical Maximum (1)
+            0x81, 0x00, //   Input (Data,Array,Abs)
+            0xC0, // End Collection
+        ])
+        .unwrap();
+
+        let field = &descriptor.fields[0];
+        assert!(!field.is_variable());
+        assert_eq!(field.count, 4);
+        // In declaration order, whichever end of a range comes first
+        assert_eq!(
+            field.usages,
+            [
+                usage(POWER_DEVICE, 0x02),
+                usage(POWER_DEVICE, 0x03),
+                usage(POWER_DEVICE, 0x04),
+                usage(POWER_DEVICE, 0x05),
+                usage(BATTERY_SYSTEM, 0x44),
+                usage(POWER_DEVICE, 0x06),
+                usage(POWER_DEVICE, 0x07),
+            ]
+        );
+    }
+
+    #[test]
+    fn encoded_descriptors_parse_back() {
+        for original in [VENDOR_DESCRIPTOR, POWER_DEVICE_DESCRIPTOR] {
+            let descriptor = ReportDescriptor::parse(original).unwrap();
+            let encoded = ReportDescriptor::encode(&descriptor.fields);
+            assert_eq!(ReportDescriptor::parse(&encoded).unwrap(), descriptor);
+        }
+    }
+
+    #[test]
+    fn encoding_pads_missing_fields() {
+        // Preparsed data on Windows has no trace of constant fields
+        let descriptor = ReportDescriptor::parse(POWER_DEVICE_DESCRIPTOR).unwrap();
+        let data_fields: Vec<_> = descriptor
+            .fields
+            .iter()
+            .filter(|field| !field.is_constant())
+            .cloned()
+            .collect();
+
+        let encoded =
+            ReportDescriptor::parse(&ReportDescriptor::encode(&data_fields[1..])).unwrap();
+
+        assert_eq!(encoded.applications, descriptor.applications);
+        assert_eq!(encoded.feature_report_size, descriptor.feature_report_size);
+        for field in &data_fields[1..] {
+            let kind = field.kind;
+            assert_eq!(
+                encoded.find(kind, &field.usages),
+                descriptor.find(kind, &field.usages)
+            );
+        }
+
+        // The first field's bits are now padding
+        let padding = &encoded.fields[0];
+        assert!(padding.is_constant());
+        assert_eq!(
+            padding.bit_offset + padding.bit_size * padding.count,
+            data_fields[1].bit_offset
+        );
+    }
+
+    #[test]
+    fn malformed_descriptors_are_errors() {
+        // Truncated item
+        assert!(ReportDescriptor::parse(&[0x05, 0x84, 0x09]).is_err());
+        // Unbalanced collections
+        assert!(ReportDescriptor::parse(&[0xC0]).is_err());
+        assert!(ReportDescriptor::parse(&[0x09, 0x04, 0xA1, 0x01]).is_err());
+        // Report ID 0 is reserved
+        assert!(ReportDescriptor::parse(&[0x85, 0x00]).is_err());
+        // Pop without Push
+        assert!(ReportDescriptor::parse(&[0xB4]).is_err());
+    }
+
+    #[test]
+    fn hostile_sizes_are_framing_errors() {
+        let is_framing = |descriptor: &[u8]| {
+            let error = ReportDescriptor::parse(descriptor).unwrap_err();
+            matches!(UpsError::of(&error), Some(UpsError::Framing(_)))
+        };
+
+        // Report Size (0xFFFFFFFF)
+        assert!(is_framing(&[0x77, 0xFF, 0xFF, 0xFF, 0xFF]));
+        // Report Count (0xFFFFFFFF)
+        assert!(is_framing(&[0x97, 0xFF, 0xFF, 0xFF, 0xFF]));
+
+        // Fields that are each within limits but add up to a huge report
+        let mut descriptor = vec![
+            0x75, 0x08, // Report Size (8)
+            0x96, 0x00, 0x10, // Report Count (4096)
+        ];
+        for _ in 0..5 {
+            descriptor.extend([0x81, 0x03]); // Input (Cnst,Var,Abs)
+        }
+        assert!(ReportDescriptor::parse(&descriptor[..descriptor.len() - 2]).is_ok());
+        assert!(is_framing(&descriptor));
+
+        // Usage Minimum (0), Usage Maximum (0xFFFF)
+        assert!(is_framing(&[
+            0x19, 0x00, 0x2A, 0xFF, 0xFF, 0x75, 0x01, 0x95, 0x01, 0x81, 0x00,
+        ]));
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use anyhow::Result;
+use tokio::io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{error::UpsError, stream_transport::StreamTransport};
+
+/// A UPS connected to a serial port (RS-232 or a USB-CDC adapter)
+pub type SerialTransport = StreamTransport<SerialPort>;
+
+impl SerialTransport {
+    pub fn open(path: impl AsRef<Path>, config: &SerialConfig) -> Result<Self> {
+        Ok(Self::new(SerialPort::open(path, config)?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub baud_rate: u32,
+    pub parity: Parity,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        // What Megatec-compatible units ship with
+        Self {
+            baud_rate: 2400,
+            parity: Parity::None,
+        }
+    }
+}
+
+/// A tty configured for raw 8-bit I/O, with one stop bit and no flow control
+#[derive(Debug)]
+pub struct SerialPort {
+    file: AsyncFd<File>,
+}
+
+impl SerialPort {
+    pub fn open(path: impl AsRef<Path>, config: &SerialConfig) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(path)?;
+
+        Self::configure(&file, config)?;
+
+        Ok(Self {
+            file: AsyncFd::new(file)?,
+        })
+    }
+
+    fn configure(file: &File, config: &SerialConfig) -> Result<()> {
+        let speed = Self::speed(config.baud_rate)?;
+        let fd = file.as_raw_fd();
+
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            libc::cfmakeraw(&mut termios);
+            termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+            termios.c_cflag &= !(libc::PARENB | libc::PARODD | libc::CSTOPB | libc::CRTSCTS);
+            termios.c_cflag |= match config.parity {
+                Parity::None => 0,
+                Parity::Odd => libc::PARENB | libc::PARODD,
+                Parity::Even => libc::PARENB,
+            };
+
+            if libc::cfsetispeed(&mut termios, speed) != 0
+                || libc::cfsetospeed(&mut termios, speed) != 0
+            {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            // Don't let stale bytes from before we opened the port show up
+            // as the start of a response.
+            if libc::tcflush(fd, libc::TCIOFLUSH) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn speed(baud_rate: u32) -> Result<libc::speed_t> {
+        Ok(match baud_rate {
+            1200 => libc::B1200,
+            2400 => libc::B2400,
+            4800 => libc::B4800,
+            9600 => libc::B9600,
+            19200 => libc::B19200,
+            38400 => libc::B38400,
+            57600 => libc::B57600,
+            115200 => libc::B115200,
+            _ => {
+                return Err(
+                    UpsError::Unsupported(format!("Unsupported baud rate {}", baud_rate)).into(),
+                )
+            }
+        })
+    }
+}
+
+impl AsyncRead for SerialPort {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.file.poll_read_ready(cx))?;
+
+            let unfilled = buf.initialize_unfilled();
+            if let Ok(result) = guard.try_io(|file| file.get_ref().read(unfilled)) {
+                let read = result?;
+                buf.advance(read);
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl AsyncWrite for SerialPort {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.file.poll_write_ready(cx))?;
+
+            if let Ok(result) = guard.try_io(|file| file.get_ref().write(buf)) {
+                return Poll::Ready(result);
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CStr, fs::File, os::unix::io::FromRawFd, thread};
+
+    use super::*;
+    use crate::{
+        megatec_serial_ups::MegatecSerialUps,
+        ups::{Ups, UpsStatusFlags},
+        voltronic_hid_ups::VoltronicHidUps,
+    };
+
+    /// Opens a pseudo-terminal, returning the master and the slave's path
+    fn open_pty() -> (File, String) {
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(master >= 0);
+            assert_eq!(libc::grantpt(master), 0);
+            assert_eq!(libc::unlockpt(master), 0);
+
+            let mut name = [0 as libc::c_char; 128];
+            assert_eq!(libc::ptsname_r(master, name.as_mut_ptr(), name.len()), 0);
+            let name = CStr::from_ptr(name.as_ptr()).to_str().unwrap().to_string();
+
+            (File::from_raw_fd(master), name)
+        }
+    }
+
+    /// Plays the UPS side of the conversation on the pty master
+    fn serve(mut master: File, script: &'static [(&'static str, &'static str)]) {
+        thread::spawn(move || {
+            for (command, response) in script {
+                let mut received = Vec::new();
+                while !received.ends_with(b"\r") {
+                    let mut byte = [0u8; 1];
+                    master.read_exact(&mut byte).unwrap();
+                    received.push(byte[0]);
+                }
+                assert_eq!(received, command.as_bytes());
+
+                master.write_all(response.as_bytes()).unwrap();
+            }
+
+            // Closing the master hangs up the slave and discards whatever it
+            // hasn't read yet, so wait for the other side to go away first.
+            let _ = master.read(&mut [0u8; 1]);
+        });
+    }
+
+    #[tokio::test]
+    async fn voltronic_status_over_pty() {
+        let (master, slave) = open_pty();
+        serve(
+            master,
+            &[
+                ("M\r", "V\r"),
+                ("QS\r", "(215.0 195.0 230.0 014 49.0 27.5 30.0 00001001\r"),
+            ],
+        );
+
+        let transport = SerialTransport::open(&slave, &SerialConfig::default()).unwrap();
+        let ups = VoltronicHidUps::new(transport).unwrap();
+
+        let status = ups.status().await.unwrap();
+        assert_eq!(status.input_voltage, 215.0);
+        assert_eq!(status.output_load_level, 14);
+        assert!(status.flags.contains(UpsStatusFlags::BEEPER_ACTIVE));
+    }
+
+    #[tokio::test]
+    async fn megatec_status_over_pty() {
+        let (master, slave) = open_pty();
+        serve(
+            master,
+            &[("Q1\r", "(208.4 140.0 208.4 034 59.9 2.05 35.0 00110000\r")],
+        );
+
+        let config = SerialConfig {
+            baud_rate: 9600,
+            parity: Parity::Even,
+        };
+        let transport = SerialTransport::open(&slave, &config).unwrap();
+        let ups = MegatecSerialUps::new(transport).unwrap();
+
+        let status = ups.status().await.unwrap();
+        assert_eq!(status.battery_voltage, 2.05);
+        assert!(status.flags.contains(UpsStatusFlags::UPS_FAULT));
+    }
+
+    #[test]
+    fn unsupported_baud_rate_is_rejected() {
+        let (_master, slave) = open_pty();
+        let config = SerialConfig {
+            baud_rate: 1234,
+            parity: Parity::None,
+        };
+        assert!(SerialTransport::open(&slave, &config).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::Mutex,
+};
+
+use crate::{error::UpsError, transport::Transport};
+
+const REPORT_ID: u8 = 0;
+
+const MAX_PACKET_SIZE: usize = 64;
+
+/// A [`Transport`] over a plain byte stream, such as a serial port.
+///
+/// Output reports are written to the stream as-is, and every read returns
+/// whatever bytes are available as a single input report. Report ID 0 is the
+/// only ID streams carry, which is what the ASCII command protocols use.
+///
+/// Anything left unread when a command is sent, such as the tail of a
+/// response that timed out, is thrown away, so that it can't pass for the
+/// response to the new command.
+#[derive(Debug)]
+pub struct StreamTransport<S> {
+    stream: Mutex<S>,
+}
+
+impl<S> StreamTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: Mutex::new(stream),
+        }
+    }
+
+    /// Read whatever is already buffered, without waiting for more
+    fn discard_stale_input(stream: &mut S) {
+        let mut context = Context::from_waker(Waker::noop());
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        loop {
+            let mut buffer = ReadBuf::new(&mut buffer);
+            match Pin::new(&mut *stream).poll_read(&mut context, &mut buffer) {
+                Poll::Ready(Ok(())) if !buffer.filled().is_empty() => continue,
+                // Nothing left, or the stream is closed, which the next read
+                // reports
+                _ => return,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S> Transport for StreamTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn send_output_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        if report_id != REPORT_ID {
+            return Err(UpsError::Unsupported(format!(
+                "Streams only carry report ID {}",
+                REPORT_ID
+            ))
+            .into());
+        }
+
+        let mut stream = self.stream.lock().await;
+        Self::discard_stale_input(&mut stream);
+        stream.write_all(data).await?;
+        stream.flush().await?;
+
+        Ok(())
+    }
+
+    async fn read_input_report(&self) -> Result<(u8, Vec<u8>)> {
+        let mut packet = vec![0u8; MAX_PACKET_SIZE];
+
+        let read = self.stream.lock().await.read(&mut packet).await?;
+        if read == 0 {
+            return Err(anyhow!("Stream was closed"));
+        }
+        packet.truncate(read);
+
+        Ok((REPORT_ID, packet))
+    }
+
+    async fn get_indexed_string(&self, _index: u32) -> Result<String> {
+        Err(
+            UpsError::Unsupported("Indexed strings are not supported over streams".to_string())
+                .into(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::{megatec_serial_ups::MegatecSerialUps, ups::Ups};
+
+    #[tokio::test]
+    async fn stale_input_is_discarded() {
+        let (client, mut server) = duplex(MAX_PACKET_SIZE);
+        let ups = MegatecSerialUps::new(StreamTransport::new(client)).unwrap();
+
+        // A late response to an earlier command, and a stray line ending
+        server
+            .write_all(b"(208.4 140.0 208.4 034 59.9 2.05 35.0 00110000\r\n")
+            .await
+            .unwrap();
+
+        let exchange = async {
+            let mut command = [0u8; 3];
+            server.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"Q1\r");
+            server
+                .write_all(b"(215.0 195.0 230.0 014 49.0 27.5 30.0 00001001\r")
+                .await
+                .unwrap();
+        };
+        let (status, ()) = tokio::join!(ups.status(), exchange);
+
+        assert_eq!(status.unwrap().output_load_level, 14);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::{stream_transport::StreamTransport, transport::Transport};
+
+/// A UPS behind a serial-to-Ethernet server (ser2net and the like), reached
+/// over a raw TCP connection.
+///
+/// A broken connection is dropped, and re-established when the next command
+/// is sent. Connecting happens inside [`send_output_report`], so it is bounded
+/// by the same send timeout as the command itself.
+///
+/// [`send_output_report`]: Transport::send_output_report
+#[derive(Debug)]
+pub struct TcpTransport {
+    address: String,
+    inner: StreamTransport<TcpConnection>,
+    /// Bumped on every reconnect
+    connections: Arc<AtomicU64>,
+}
+
+impl TcpTransport {
+    /// Connect to `address`, given as `host:port`
+    pub async fn connect(address: impl Into<String>) -> Result<Self> {
+        let address = address.into();
+        let stream = open(address.clone()).await?;
+        let connections = Arc::new(AtomicU64::new(0));
+
+        Ok(Self {
+            inner: StreamTransport::new(TcpConnection {
+                address: address.clone(),
+                stream: Some(stream),
+                connecting: None,
+                connections: connections.clone(),
+            }),
+            address,
+            connections,
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn send_output_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        self.inner.send_output_report(report_id, data).await
+    }
+
+    async fn read_input_report(&self) -> Result<(u8, Vec<u8>)> {
+        self.inner.read_input_report().await
+    }
+
+    async fn get_indexed_string(&self, index: u32) -> Result<String> {
+        self.inner.get_indexed_string(index).await
+    }
+
+    fn connection_id(&self) -> u64 {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+async fn open(address: String) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(address).await?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// A TCP connection that drops itself once broken, reads as closed until
+/// then, and reconnects on the next write
+struct TcpConnection {
+    address: String,
+    stream: Option<TcpStream>,
+    connecting: Option<Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>>,
+    connections: Arc<AtomicU64>,
+}
+
+impl fmt::Debug for TcpConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TcpConnection")
+            .field("address", &self.address)
+            .field("stream", &self.stream)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for TcpConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Poll::Ready(Ok(())),
+        };
+
+        let filled = buf.filled().len();
+        let result = ready!(Pin::new(stream).poll_read(cx, buf));
+        if result.is_err() || buf.filled().len() == filled {
+            self.stream = None;
+        }
+
+        Poll::Ready(result)
+    }
+}
+
+impl AsyncWrite for TcpConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        if this.stream.is_none() {
+            let address = this.address.clone();
+            let connecting = this
+                .connecting
+                .get_or_insert_with(|| Box::pin(open(address)));
+            let result = ready!(connecting.as_mut().poll(cx));
+            this.connecting = None;
+            this.stream = Some(result?);
+            this.connections.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let result = ready!(Pin::new(this.stream.as_mut().unwrap()).poll_write(cx, buf));
+        if result.is_err() {
+            this.stream = None;
+        }
+
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.stream.as_mut() {
+            Some(stream) => Pin::new(stream).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.stream.as_mut() {
+            Some(stream) => Pin::new(stream).poll_shutdown(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{ups::Ups, voltronic_hid_ups::VoltronicHidUps};
+
+    const STATUS: &str = "(215.0 195.0 230.0 014 49.0 27.5 30.0 00001001\r";
+
+    /// Answers `answered` commands on one connection, then hangs up on the
+    /// next one. Returns the commands received.
+    async fn serve_connection(listener: &TcpListener, answered: usize) -> Vec<Vec<u8>> {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut socket = BufReader::new(socket);
+
+        let mut commands = Vec::new();
+        loop {
+            let mut command = Vec::new();
+            if socket.read_until(b'\r', &mut command).await.unwrap() == 0 {
+                // The client hung up first
+                break;
+            }
+            commands.push(command.clone());
+            if commands.len() > answered {
+                break;
+            }
+
+            let response = match &command[..] {
+                b"M\r" => "V\r",
+                b"QS\r" => STATUS,
+                _ => panic!("Unexpected command {:?}", command),
+            };
+            socket
+                .get_mut()
+                .write_all(response.as_bytes())
+                .await
+                .unwrap();
+        }
+
+        commands
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_connection_loss() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let server = tokio::spawn(async move {
+            let first = serve_connection(&listener, 2).await;
+            let second = serve_connection(&listener, usize::MAX).await;
+            (first, second)
+        });
+
+        let transport = Arc::new(TcpTransport::connect(address).await.unwrap());
+        let ups = VoltronicHidUps::new(transport.clone()).unwrap();
+
+        assert_eq!(ups.status().await.unwrap().output_load_level, 14);
+        assert_eq!(transport.connection_id(), 0);
+
+        // The server hangs up instead of answering
+        assert!(ups.status().await.is_err());
+
+        // The next poll reconnects, and probes the protocol again
+        assert_eq!(ups.status().await.unwrap().output_voltage, 230.0);
+        ups.status().await.unwrap();
+        assert_eq!(transport.connection_id(), 1);
+
+        drop(ups);
+        drop(transport);
+        let (first, second) = server.await.unwrap();
+        assert_eq!(first, [&b"M\r"[..], b"QS\r", b"QS\r"]);
+        assert_eq!(second, [&b"M\r"[..], b"QS\r", b"QS\r"]);
+    }
+
+    #[tokio::test]
+    async fn unreachable_server_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        assert!(TcpTransport::connect(address).await.is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::error::UpsError;
+
+/// A channel to a UPS that carries HID-style reports.
+///
+/// Protocol drivers are written against this trait, so they don't care whether
+/// the reports travel over a real HID device or something else entirely.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Send an output report with the given ID
+    async fn send_output_report(&self, report_id: u8, data: &[u8]) -> Result<()>;
+
+    /// Read a single input report, returning its ID and payload
+    async fn read_input_report(&self) -> Result<(u8, Vec<u8>)>;
+
+    /// Fetch the string with the given index from the device
+    async fn get_indexed_string(&self, index: u32) -> Result<String>;
+
+    /// Read the feature report with the given ID, returning its payload
+    async fn get_feature_report(&self, _report_id: u8) -> Result<Vec<u8>> {
+        Err(UpsError::Unsupported(
+            "Feature reports are not supported by this transport".to_string(),
+        )
+        .into())
+    }
+
+    /// Write the feature report with the given ID
+    async fn send_feature_report(&self, _report_id: u8, _data: &[u8]) -> Result<()> {
+        Err(UpsError::Unsupported(
+            "Feature reports are not supported by this transport".to_string(),
+        )
+        .into())
+    }
+
+    /// Fetch the device's HID report descriptor. On Windows this is rebuilt
+    /// from the preparsed data, so it has the same data fields as the raw
+    /// one but not necessarily the same bytes.
+    async fn report_descriptor(&self) -> Result<Vec<u8>> {
+        Err(UpsError::Unsupported(
+            "Report descriptors are not supported by this transport".to_string(),
+        )
+        .into())
+    }
+
+    /// Identifies the current connection to the device. Transports that
+    /// reconnect on their own change it every time they do, so that drivers
+    /// know to forget what they learned about the device.
+    fn connection_id(&self) -> u64 {
+        0
+    }
+}
+
+#[async_trait]
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    async fn send_output_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        (**self).send_output_report(report_id, data).await
+    }
+
+    async fn read_input_report(&self) -> Result<(u8, Vec<u8>)> {
+        (**self).read_input_report().await
+    }
+
+    async fn get_indexed_string(&self, index: u32) -> Result<String> {
+        (**self).get_indexed_string(index).await
+    }
+
+    async fn get_feature_report(&self, report_id: u8) -> Result<Vec<u8>> {
+        (**self).get_feature_report(report_id).await
+    }
+
+    async fn send_feature_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        (**self).send_feature_report(report_id, data).await
+    }
+
+    async fn report_descriptor(&self) -> Result<Vec<u8>> {
+        (**self).report_descriptor().await
+    }
+
+    fn connection_id(&self) -> u64 {
+        (**self).connection_id()
+    }
+}
+
+#[async_trait]
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    async fn send_output_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        (**self).send_output_report(report_id, data).await
+    }
+
+    async fn read_input_report(&self) -> Result<(u8, Vec<u8>)> {
+        (**self).read_input_report().await
+    }
+
+    async fn get_indexed_string(&self, index: u32) -> Result<String> {
+        (**self).get_indexed_string(index).await
+    }
+
+    async fn get_feature_report(&self, report_id: u8) -> Result<Vec<u8>> {
+        (**self).get_feature_report(report_id).await
+    }
+
+    async fn send_feature_report(&self, report_id: u8, data: &[u8]) -> Result<()> {
+        (**self).send_feature_report(report_id, data).await
+    }
+
+    async fn report_descriptor(&self) -> Result<Vec<u8>> {
+        (**self).report_descriptor().await
+    }
+
+    fn connection_id(&self) -> u64 {
+        (**self).connection_id()
+    }
+}
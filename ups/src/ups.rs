@@ -1,8 +1,11 @@
-use std::str::FromStr;
+use std::{fmt, future::Future, str::FromStr, time::Duration};
 
 use anyhow::{bail, Result};
 use async_trait::async_trait;
 use bitflags::bitflags;
+use tokio::time::{sleep, Instant};
+
+use crate::error::UpsError;
 
 #[async_trait]
 pub trait Ups {
@@ -11,9 +14,220 @@ pub trait Ups {
 
     /// Toggle the beeper
     async fn beeper_toggle(&self) -> Result<()>;
+
+    /// Turn the beeper on or off, returning once the UPS reports it so
+    async fn set_beeper(&self, enabled: bool) -> Result<()>;
+
+    /// What this UPS supports, beyond reporting its status
+    async fn capabilities(&self) -> Result<UpsCapabilities>;
+
+    /// Who made the UPS and which one it is
+    async fn info(&self) -> Result<UpsInfo>;
+
+    /// Nominal ratings, for UPSes with [`UpsCapabilities::RATINGS`]
+    async fn ratings(&self) -> Result<UpsRatings>;
+
+    /// Start a battery test, for UPSes with [`UpsCapabilities::SELF_TEST`]
+    async fn start_self_test(&self, test: SelfTest) -> Result<SelfTestHandle>;
+
+    /// Cancel a running battery test
+    async fn cancel_self_test(&self) -> Result<()>;
+
+    /// Turn the output off after `delay`, and back on `restore_after` later
+    /// if mains power is there, for UPSes with [`UpsCapabilities::SHUTDOWN`].
+    /// Without `restore_after` the output stays off. Either takes
+    /// [`UpsCapabilities::OUTLET_CONTROL`] as well, except a `restore_after`
+    /// of zero, which brings the output back as soon as mains power is.
+    /// Until the output goes off, the status has
+    /// [`UpsStatusFlags::UPS_SHUTDOWN_ACTIVE`].
+    async fn schedule_shutdown(
+        &self,
+        delay: Duration,
+        restore_after: Option<Duration>,
+    ) -> Result<()>;
+
+    /// Cancel a pending shutdown
+    async fn cancel_shutdown(&self) -> Result<()>;
+}
+
+/// How long the UPS gets to report a beeper change
+const BEEPER_TIMEOUT: Duration = Duration::from_secs(3);
+const BEEPER_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub(crate) async fn beeper_active<U: Ups + Sync + ?Sized>(ups: &U) -> Result<bool> {
+    Ok(ups
+        .status()
+        .await?
+        .flags
+        .contains(UpsStatusFlags::BEEPER_ACTIVE))
+}
+
+/// Set the beeper of a UPS that can only toggle it.
+///
+/// The toggle is sent at most once, as a status that is slow to catch up
+/// would otherwise make it flip back and forth.
+pub(crate) async fn set_beeper_by_toggle<U: Ups + Sync + ?Sized>(
+    ups: &U,
+    enabled: bool,
+) -> Result<()> {
+    if beeper_active(ups).await? == enabled {
+        return Ok(());
+    }
+
+    ups.beeper_toggle().await?;
+    wait_for_beeper(|| beeper_active(ups), enabled).await
+}
+
+/// Poll `beeper_active` until it says `enabled`
+pub(crate) async fn wait_for_beeper<F, Fut>(mut beeper_active: F, enabled: bool) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    let deadline = Instant::now() + BEEPER_TIMEOUT;
+    loop {
+        if beeper_active().await? == enabled {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            bail!(UpsError::Timeout(format!(
+                "Turning the beeper {}",
+                if enabled { "on" } else { "off" }
+            )));
+        }
+        sleep(BEEPER_POLL_INTERVAL).await;
+    }
+}
+
+/// The kinds of battery test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTest {
+    /// A test of about 10 seconds
+    Quick,
+    /// A test that runs until the battery is low
+    UntilBatteryLow,
+    /// A test of 1 to 99 minutes
+    Timed(u8),
+}
+
+/// How a battery test is going
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelfTestState {
+    /// Started, but not reported running yet
+    Starting,
+    Running,
+    Passed,
+    /// Holds why
+    Failed(String),
+}
+
+/// Follows a battery test through the statuses read after starting it
+#[derive(Debug, Clone)]
+pub struct SelfTestHandle {
+    test: SelfTest,
+    started_at: Instant,
+    state: SelfTestState,
+}
+
+impl SelfTestHandle {
+    /// How long the UPS gets to report the test running. Quick tests take
+    /// about 10 seconds, so statuses have to be read more often than that.
+    pub const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Start following `test`, which was just started
+    pub fn new(test: SelfTest) -> Self {
+        Self {
+            test,
+            started_at: Instant::now(),
+            state: SelfTestState::Starting,
+        }
+    }
+
+    pub fn test(&self) -> SelfTest {
+        self.test
+    }
+
+    pub fn state(&self) -> &SelfTestState {
+        &self.state
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, SelfTestState::Passed | SelfTestState::Failed(_))
+    }
+
+    /// Move the test along with a freshly read status
+    pub fn update(&mut self, status: &UpsStatus) -> &SelfTestState {
+        if self.is_finished() {
+            return &self.state;
+        }
+
+        let running = status.flags.contains(UpsStatusFlags::SELF_TEST_IN_PROGRESS);
+        self.state = match self.state {
+            _ if running => SelfTestState::Running,
+            SelfTestState::Starting if self.started_at.elapsed() < Self::START_TIMEOUT => {
+                SelfTestState::Starting
+            }
+            SelfTestState::Starting => {
+                SelfTestState::Failed("The UPS never reported the test running".to_string())
+            }
+            _ => self.verdict(status),
+        };
+
+        &self.state
+    }
+
+    /// Read statuses every `poll_interval` until the test is over
+    pub async fn wait(&mut self, ups: &dyn Ups, poll_interval: Duration) -> Result<SelfTestState> {
+        while !self.is_finished() {
+            sleep(poll_interval).await;
+            let status = ups.status().await?;
+            self.update(&status);
+        }
+
+        Ok(self.state.clone())
+    }
+
+    /// Judge a test that just ended by the status right after it
+    fn verdict(&self, status: &UpsStatus) -> SelfTestState {
+        if status.flags.contains(UpsStatusFlags::UPS_FAULT) {
+            SelfTestState::Failed("The UPS reported a fault".to_string())
+        } else if status.flags.contains(UpsStatusFlags::BATTERY_LOW)
+            && self.test != SelfTest::UntilBatteryLow
+        {
+            SelfTestState::Failed("The battery ran low".to_string())
+        } else {
+            SelfTestState::Passed
+        }
+    }
+}
+
+/// The identity of a UPS. Anything the UPS doesn't report is `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpsInfo {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub firmware_version: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+/// The nominal ratings of a UPS. Anything the UPS doesn't report is `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UpsRatings {
+    /// Output voltage
+    pub voltage: Option<f32>,
+    /// Output current
+    pub current: Option<f32>,
+    /// Output frequency
+    pub frequency: Option<f32>,
+    /// Apparent output power, in VA
+    pub apparent_power: Option<u32>,
+    /// Voltage of the whole battery string
+    pub battery_voltage: Option<f32>,
+    /// Batteries in the string, usually of 12 V each
+    pub battery_count: Option<u32>,
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct UpsStatus {
     pub input_voltage: f32,
     pub input_fault_voltage: f32,
@@ -22,46 +236,70 @@ pub struct UpsStatus {
     pub output_frequency: f32,
     pub battery_voltage: f32,
     pub internal_temperature: f32,
+    /// Remaining battery charge in percent, for UPSes that report it. For the
+    /// others, [`BatteryModel`](crate::battery::BatteryModel) can estimate it.
+    pub battery_capacity: Option<u32>,
+    /// Estimated time left on battery, for UPSes that report it
+    pub battery_run_time: Option<Duration>,
     pub flags: UpsStatusFlags,
+    /// Fields the UPS sent garbage for, which a lenient parse filled in with
+    /// NaN, 0 or no flags
+    pub invalid_fields: Vec<InvalidField>,
 }
 
-impl UpsStatus {
-    pub fn work_mode(&self) -> UpsWorkMode {
-        if self.flags.contains(UpsStatusFlags::UPS_FAULT) {
-            UpsWorkMode::Fault
-        } else if self.flags.contains(UpsStatusFlags::UTILITY_FAIL) {
-            UpsWorkMode::Battery
-        } else if self.flags.contains(UpsStatusFlags::SELF_TEST_IN_PROGRESS) {
-            UpsWorkMode::BatteryTest
-        } else {
-            UpsWorkMode::Line
-        }
+/// A status field that didn't parse, with what the UPS sent for it and why
+/// it was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidField {
+    pub field: UpsStatusFields,
+    pub value: String,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:?}: {}", self.field.name(), self.value, self.reason)
     }
 }
 
-impl FromStr for UpsStatus {
-    type Err = anyhow::Error;
+/// How to deal with status fields that don't parse
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// Fail, saying which field and why
+    Strict,
+    /// Fill in a placeholder and note the field in
+    /// [`UpsStatus::invalid_fields`]
+    #[default]
+    Lenient,
+}
 
-    fn from_str(string: &str) -> Result<Self> {
+impl UpsStatus {
+    /// Parse a Megatec status string, like
+    /// `(MMM.M NNN.N PPP.P QQQ RR.R S.SS TT.T b7b6b5b4b3b2b1b0<cr>`
+    pub fn parse(string: &str, mode: ParseMode) -> Result<Self> {
         const HEADER: char = '(';
         const TERMINATOR: char = '\r';
 
         match string.chars().nth(0) {
             Some(first_char) => {
                 if first_char != HEADER {
-                    bail!("Unexpected status string header");
+                    bail!(UpsError::Framing(
+                        "Unexpected status string header".to_string()
+                    ));
                 }
             }
-            None => bail!("Status string too short"),
+            None => bail!(UpsError::Framing("Status string too short".to_string())),
         }
 
         match string.chars().last() {
             Some(last_char) => {
                 if last_char != TERMINATOR {
-                    bail!("Unexpected status string terminator");
+                    bail!(UpsError::Framing(
+                        "Unexpected status string terminator".to_string()
+                    ));
                 }
             }
-            None => bail!("Status string too short"),
+            None => bail!(UpsError::Framing("Status string too short".to_string())),
         }
 
         assert!(HEADER.is_ascii());
@@ -70,21 +308,131 @@ impl FromStr for UpsStatus {
 
         let parts: Vec<_> = string.split_whitespace().collect();
         if parts.len() != 8 {
-            bail!("Unexpected number of status string parts");
-        }
-
-        let status = UpsStatus {
-            input_voltage: parts[0].parse().unwrap_or(f32::NAN),
-            input_fault_voltage: parts[1].parse().unwrap_or(f32::NAN),
-            output_voltage: parts[2].parse().unwrap_or(f32::NAN),
-            output_load_level: parts[3].parse().unwrap_or(0),
-            output_frequency: parts[4].parse().unwrap_or(f32::NAN),
-            battery_voltage: parts[5].parse().unwrap_or(f32::NAN),
-            internal_temperature: parts[6].parse().unwrap_or(f32::NAN),
-            flags: UpsStatusFlags::from_bits(u8::from_str_radix(parts[7], 2).unwrap_or(0)).unwrap(),
+            bail!(UpsError::Framing(format!(
+                "Unexpected number of status string parts: {:?}",
+                string
+            )));
+        }
+
+        let mut fields = FieldParser::new(mode);
+        let flags = match parts[7] {
+            bits if bits.len() == 8 && bits.bytes().all(|bit| matches!(bit, b'0' | b'1')) => {
+                UpsStatusFlags::from_bits(u8::from_str_radix(bits, 2)?).unwrap()
+            }
+            bits => fields.invalid(UpsStatusFields::FLAGS, bits, "not 8 binary digits")?,
         };
 
-        Ok(status)
+        Ok(UpsStatus {
+            input_voltage: fields.parse(UpsStatusFields::INPUT_VOLTAGE, parts[0], f32::NAN)?,
+            input_fault_voltage: fields.parse(
+                UpsStatusFields::INPUT_FAULT_VOLTAGE,
+                parts[1],
+                f32::NAN,
+            )?,
+            output_voltage: fields.parse(UpsStatusFields::OUTPUT_VOLTAGE, parts[2], f32::NAN)?,
+            output_load_level: fields.parse(UpsStatusFields::OUTPUT_LOAD_LEVEL, parts[3], 0)?,
+            output_frequency: fields.parse(
+                UpsStatusFields::OUTPUT_FREQUENCY,
+                parts[4],
+                f32::NAN,
+            )?,
+            battery_voltage: fields.parse(UpsStatusFields::BATTERY_VOLTAGE, parts[5], f32::NAN)?,
+            internal_temperature: fields.parse(
+                UpsStatusFields::INTERNAL_TEMPERATURE,
+                parts[6],
+                f32::NAN,
+            )?,
+            battery_capacity: None,
+            battery_run_time: None,
+            flags,
+            invalid_fields: fields.invalid_fields,
+        })
+    }
+
+    /// Whether every field parsed, so the status can be acted upon
+    pub fn is_valid(&self) -> bool {
+        self.invalid_fields.is_empty()
+    }
+
+    pub fn work_mode(&self) -> UpsWorkMode {
+        if self.flags.contains(UpsStatusFlags::UPS_FAULT) {
+            UpsWorkMode::Fault
+        } else if self.flags.contains(UpsStatusFlags::UTILITY_FAIL) {
+            UpsWorkMode::Battery
+        } else if self.flags.contains(UpsStatusFlags::SELF_TEST_IN_PROGRESS) {
+            UpsWorkMode::BatteryTest
+        } else {
+            UpsWorkMode::Line
+        }
+    }
+}
+
+impl FromStr for UpsStatus {
+    type Err = anyhow::Error;
+
+    /// Parse leniently, see [`UpsStatus::parse`]
+    fn from_str(string: &str) -> Result<Self> {
+        Self::parse(string, ParseMode::Lenient)
+    }
+}
+
+/// Parses status fields one by one, as the [`ParseMode`] says
+pub(crate) struct FieldParser {
+    mode: ParseMode,
+    pub invalid_fields: Vec<InvalidField>,
+}
+
+impl FieldParser {
+    pub fn new(mode: ParseMode) -> Self {
+        Self {
+            mode,
+            invalid_fields: Vec::new(),
+        }
+    }
+
+    /// Parse `value`, using `placeholder` for values the UPS dashed out
+    /// because it doesn't measure them
+    pub fn parse<V>(&mut self, field: UpsStatusFields, value: &str, placeholder: V) -> Result<V>
+    where
+        V: FromStr,
+        V::Err: fmt::Display,
+    {
+        if value.contains('-') && value.bytes().all(|byte| matches!(byte, b'-' | b'.')) {
+            return Ok(placeholder);
+        }
+
+        match value.parse() {
+            Ok(value) => Ok(value),
+            Err(error) => {
+                self.invalid::<()>(field, value, &error.to_string())?;
+                Ok(placeholder)
+            }
+        }
+    }
+
+    /// Deal with `value` of `field` being invalid for `reason`
+    pub fn invalid<V: Default>(
+        &mut self,
+        field: UpsStatusFields,
+        value: &str,
+        reason: &str,
+    ) -> Result<V> {
+        match self.mode {
+            ParseMode::Strict => Err(UpsError::InvalidField {
+                field: field.name(),
+                value: value.to_string(),
+                reason: reason.to_string(),
+            }
+            .into()),
+            ParseMode::Lenient => {
+                self.invalid_fields.push(InvalidField {
+                    field,
+                    value: value.to_string(),
+                    reason: reason.to_string(),
+                });
+                Ok(V::default())
+            }
+        }
     }
 }
 
@@ -102,6 +450,68 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Operations and readings a UPS may or may not support
+    #[derive(Default)]
+    pub struct UpsCapabilities: u8 {
+        const BEEPER         = 0b00000001;
+        const SELF_TEST      = 0b00000010;
+        const SHUTDOWN       = 0b00000100;
+        const RATINGS        = 0b00001000;
+        const TEMPERATURE    = 0b00010000;
+        /// Keeping the output off after a shutdown, or turning it back on
+        /// after a delay, as [`Ups::schedule_shutdown`] does
+        const OUTLET_CONTROL = 0b00100000;
+    }
+}
+
+impl UpsCapabilities {
+    /// Fail with [`UpsError::Unsupported`] unless all of `capabilities` are
+    /// there
+    pub fn require(&self, capabilities: UpsCapabilities) -> Result<()> {
+        let missing = capabilities - *self;
+        if !missing.is_empty() {
+            bail!(UpsError::Unsupported(format!(
+                "The UPS does not support {:?}",
+                missing
+            )));
+        }
+        Ok(())
+    }
+}
+
+bitflags! {
+    /// The fields of an [`UpsStatus`] that come from parsing
+    #[derive(Default)]
+    pub struct UpsStatusFields: u8 {
+        const INPUT_VOLTAGE        = 0b00000001;
+        const INPUT_FAULT_VOLTAGE  = 0b00000010;
+        const OUTPUT_VOLTAGE       = 0b00000100;
+        const OUTPUT_LOAD_LEVEL    = 0b00001000;
+        const OUTPUT_FREQUENCY     = 0b00010000;
+        const BATTERY_VOLTAGE      = 0b00100000;
+        const INTERNAL_TEMPERATURE = 0b01000000;
+        const FLAGS                = 0b10000000;
+    }
+}
+
+impl UpsStatusFields {
+    /// The name of a single field, as in [`UpsStatus`]
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Self::INPUT_VOLTAGE => "input_voltage",
+            Self::INPUT_FAULT_VOLTAGE => "input_fault_voltage",
+            Self::OUTPUT_VOLTAGE => "output_voltage",
+            Self::OUTPUT_LOAD_LEVEL => "output_load_level",
+            Self::OUTPUT_FREQUENCY => "output_frequency",
+            Self::BATTERY_VOLTAGE => "battery_voltage",
+            Self::INTERNAL_TEMPERATURE => "internal_temperature",
+            Self::FLAGS => "flags",
+            _ => "fields",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum UpsWorkMode {
     Line,
@@ -109,3 +519,172 @@ pub enum UpsWorkMode {
     BatteryTest,
     Fault,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = "(215.0 195.0 230.0 014 49.0 27.5 30.0 00001001\r";
+
+    #[test]
+    fn missing_capabilities_are_unsupported() {
+        let capabilities = UpsCapabilities::BEEPER | UpsCapabilities::TEMPERATURE;
+
+        capabilities.require(UpsCapabilities::BEEPER).unwrap();
+
+        let error = capabilities
+            .require(UpsCapabilities::BEEPER | UpsCapabilities::SHUTDOWN)
+            .unwrap_err();
+        assert_eq!(
+            UpsError::of(&error),
+            Some(&UpsError::Unsupported(
+                "The UPS does not support SHUTDOWN".to_string()
+            ))
+        );
+    }
+
+    fn status_with(flags: UpsStatusFlags) -> UpsStatus {
+        UpsStatus {
+            flags,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn self_test_passes() {
+        let mut handle = SelfTestHandle::new(SelfTest::Quick);
+
+        handle.update(&status_with(UpsStatusFlags::empty()));
+        assert_eq!(handle.state(), &SelfTestState::Starting);
+        handle.update(&status_with(UpsStatusFlags::SELF_TEST_IN_PROGRESS));
+        assert_eq!(handle.state(), &SelfTestState::Running);
+        handle.update(&status_with(UpsStatusFlags::empty()));
+        assert_eq!(handle.state(), &SelfTestState::Passed);
+
+        // The outcome sticks
+        handle.update(&status_with(UpsStatusFlags::UPS_FAULT));
+        assert_eq!(handle.state(), &SelfTestState::Passed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn self_test_fails_on_low_battery() {
+        for (test, passed) in [
+            (SelfTest::Timed(5), false),
+            (SelfTest::UntilBatteryLow, true),
+        ] {
+            let mut handle = SelfTestHandle::new(test);
+
+            handle.update(&status_with(UpsStatusFlags::SELF_TEST_IN_PROGRESS));
+            handle.update(&status_with(UpsStatusFlags::BATTERY_LOW));
+
+            assert!(handle.is_finished());
+            assert_eq!(handle.state() == &SelfTestState::Passed, passed);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn self_test_that_never_runs_fails() {
+        let mut handle = SelfTestHandle::new(SelfTest::Quick);
+
+        tokio::time::advance(SelfTestHandle::START_TIMEOUT).await;
+        handle.update(&status_with(UpsStatusFlags::empty()));
+
+        assert!(matches!(handle.state(), SelfTestState::Failed(_)));
+    }
+
+    #[test]
+    fn valid_status_parses_either_way() {
+        let strict = UpsStatus::parse(STATUS, ParseMode::Strict).unwrap();
+        let lenient = UpsStatus::parse(STATUS, ParseMode::Lenient).unwrap();
+
+        assert_eq!(strict, lenient);
+        assert!(strict.is_valid());
+        assert_eq!(strict.input_voltage, 215.0);
+    }
+
+    #[test]
+    fn strict_parse_names_the_field() {
+        let status = "(215.0 195.0 2#0.0 014 49.0 27.5 30.0 00001001\r";
+
+        let error = UpsStatus::parse(status, ParseMode::Strict).unwrap_err();
+
+        match UpsError::of(&error) {
+            Some(UpsError::InvalidField { field, value, .. }) => {
+                assert_eq!(*field, "output_voltage");
+                assert_eq!(value, "2#0.0");
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lenient_parse_records_invalid_fields() {
+        let status = "(215.0 195.0 2#0.0 -14 49.0 27.5 30.0 0000100X\r";
+
+        let status = UpsStatus::parse(status, ParseMode::Lenient).unwrap();
+
+        assert!(!status.is_valid());
+        let invalid = |field, value: &str, reason: &str| InvalidField {
+            field,
+            value: value.to_string(),
+            reason: reason.to_string(),
+        };
+        assert_eq!(
+            status.invalid_fields,
+            [
+                invalid(UpsStatusFields::FLAGS, "0000100X", "not 8 binary digits"),
+                invalid(
+                    UpsStatusFields::OUTPUT_VOLTAGE,
+                    "2#0.0",
+                    "invalid float literal"
+                ),
+                invalid(
+                    UpsStatusFields::OUTPUT_LOAD_LEVEL,
+                    "-14",
+                    "invalid digit found in string"
+                ),
+            ]
+        );
+        assert_eq!(
+            status.invalid_fields[1].to_string(),
+            "output_voltage \"2#0.0\": invalid float literal"
+        );
+        assert!(status.output_voltage.is_nan());
+        assert_eq!(status.output_load_level, 0);
+        assert_eq!(status.flags, UpsStatusFlags::empty());
+        assert_eq!(status.input_voltage, 215.0);
+    }
+
+    #[test]
+    fn wrong_part_count_is_a_framing_error() {
+        let error =
+            UpsStatus::parse("(215.0 195.0 230.0 014 49.0\r", ParseMode::Lenient).unwrap_err();
+
+        assert!(matches!(UpsError::of(&error), Some(UpsError::Framing(_))));
+    }
+
+    #[test]
+    fn garbage_flags_are_invalid() {
+        for flags in ["1001", "+0001001", "000010010"] {
+            let status = format!("(215.0 195.0 230.0 014 49.0 27.5 30.0 {}\r", flags);
+
+            assert!(UpsStatus::parse(&status, ParseMode::Strict).is_err());
+            let invalid_fields = status.parse::<UpsStatus>().unwrap().invalid_fields;
+            assert_eq!(invalid_fields.len(), 1);
+            assert_eq!(invalid_fields[0].field, UpsStatusFields::FLAGS);
+            assert_eq!(invalid_fields[0].value, flags);
+        }
+    }
+
+    #[test]
+    fn dashed_out_fields_are_not_invalid() {
+        let status = "(215.0 ---.- 230.0 014 --.- 27.5 -05.0 00001001\r";
+
+        let status = UpsStatus::parse(status, ParseMode::Strict).unwrap();
+
+        assert!(status.is_valid());
+        assert!(status.input_fault_voltage.is_nan());
+        assert!(status.output_frequency.is_nan());
+        assert_eq!(status.internal_temperature, -5.0);
+    }
+}
@@ -38,5 +38,5 @@ pub fn ioctl_number_to_class(ioctl: u32) -> Result<IIOControlCode> {
         _ => unreachable!("There are only two bits!"),
     };
 
-    Ok(IOControlCode::CreateIOControlCode(device_type, function, access, method)?.cast()?)
+    IOControlCode::CreateIOControlCode(device_type, function, access, method)?.cast()
 }
@@ -1,144 +1,353 @@
 use std::time::Duration;
 
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Result};
 use async_trait::async_trait;
-use tokio::{sync::Mutex, time::timeout};
+use tokio::sync::Mutex;
 
 use crate::{
-    hid_device::HidDevice,
-    ups::{Ups, UpsStatus},
+    command::{self, Command, Response},
+    error::UpsError,
+    framing::{self, CrcPolicy},
+    transport::Transport,
+    ups::{
+        beeper_active, set_beeper_by_toggle, wait_for_beeper, SelfTest, SelfTestHandle, Ups,
+        UpsCapabilities, UpsInfo, UpsRatings, UpsStatus, UpsStatusFlags,
+    },
 };
 
-const REPORT_ID: u8 = 0;
-
-const TERMINATOR: char = '\r';
-
-const SEND_TIMEOUT_MS: u64 = 1000;
-const RECEIVE_TIMEOUT_MS: u64 = 250;
-const RECEIVE_TOTAL_TIMEOUT_MS: u64 = 2400;
-
 #[derive(Debug)]
-pub struct VoltronicHidUps {
-    device: Mutex<HidDevice>,
+pub struct VoltronicHidUps<T: Transport> {
+    device: Mutex<T>,
+    /// What `M` last returned and on which connection, so that it isn't
+    /// asked before every command
+    protocol: std::sync::Mutex<Option<(u64, UpsProtocol)>>,
+    /// The input and output phase counts from `QMD`, cached alongside the
+    /// protocol, as T protocol statuses depend on them
+    phases: std::sync::Mutex<Option<(u64, (u8, u8))>>,
+    crc: CrcPolicy,
 }
 
-impl VoltronicHidUps {
-    pub fn new(device: HidDevice) -> Result<Self> {
+impl<T: Transport> VoltronicHidUps<T> {
+    pub fn new(device: T) -> Result<Self> {
         Ok(Self {
             device: Mutex::new(device),
+            protocol: std::sync::Mutex::new(None),
+            phases: std::sync::Mutex::new(None),
+            crc: CrcPolicy::default(),
         })
     }
 
-    pub async fn protocol(&self) -> Result<UpsProtocol> {
-        let response = self.transact_command("M").await?;
-        Ok(match response.as_str() {
-            "P" => UpsProtocol::P,
-            "T" => UpsProtocol::T,
-            "V" => UpsProtocol::V,
-            _ => UpsProtocol::Unknown,
-        })
+    /// Set how the CRC on responses is checked. Defaults to
+    /// [`CrcPolicy::Optional`].
+    pub fn set_crc_policy(&mut self, crc: CrcPolicy) {
+        self.crc = crc;
     }
 
-    async fn transact_command(&self, command: &str) -> Result<String> {
-        let device = self.device.lock().await;
-        Self::send_command(&*device, command).await?;
-        let response = Self::read_response(&*device).await?;
-
-        Ok(response)
+    /// Wrap a UPS whose protocol is already known, e.g. from
+    /// [`detect`](crate::detect::detect)
+    pub fn with_protocol(device: T, protocol: UpsProtocol) -> Result<Self> {
+        let connection_id = device.connection_id();
+        let ups = Self::new(device)?;
+        if protocol != UpsProtocol::Unknown {
+            *ups.protocol.lock().unwrap() = Some((connection_id, protocol));
+        }
+        Ok(ups)
     }
 
-    async fn send_command(device: &HidDevice, command: &str) -> Result<()> {
-        assert!(TERMINATOR.is_ascii());
+    /// The protocol the UPS speaks, asking it only if not known yet
+    pub async fn protocol(&self) -> Result<UpsProtocol> {
+        if let Some(protocol) = self.cached_protocol().await {
+            return Ok(protocol);
+        }
 
-        let mut command = command.to_string();
-        command.push(TERMINATOR);
+        let protocol = self.send(Command::Protocol).await?.into_protocol()?;
 
-        let future = device.send_output_report(REPORT_ID, command.as_bytes());
-        let future = timeout(Duration::from_millis(SEND_TIMEOUT_MS), future);
-        match future.await {
-            Ok(result) => result?,
-            Err(_) => return Err(anyhow!("Sending command timed-out")),
-        };
+        // Nothing is supported for an unknown protocol, so ask again next
+        // time in case it was line noise after all
+        if protocol != UpsProtocol::Unknown {
+            let connection_id = self.device.lock().await.connection_id();
+            *self.protocol.lock().unwrap() = Some((connection_id, protocol));
+        }
 
-        Ok(())
+        Ok(protocol)
     }
 
-    async fn read_response(device: &HidDevice) -> Result<String> {
-        let future = Self::read_all_response_packets(device);
-        let future = timeout(Duration::from_millis(RECEIVE_TOTAL_TIMEOUT_MS), future);
-        let response = match future.await {
-            Ok(result) => result?,
-            Err(_) => return Err(anyhow!("Receiving response timed-out")),
-        };
-
-        let response = match String::from_utf8(response) {
-            Ok(response) => response,
-            Err(_) => return Err(anyhow!("UPS response is not valid UTF-8")),
-        };
-        let response = &response[0..response.find(TERMINATOR).unwrap()];
+    /// The protocol found by the last successful probe, unless the transport
+    /// has reconnected since
+    pub async fn cached_protocol(&self) -> Option<UpsProtocol> {
+        let connection_id = self.device.lock().await.connection_id();
+        match *self.protocol.lock().unwrap() {
+            Some((cached_connection_id, protocol)) if cached_connection_id == connection_id => {
+                Some(protocol)
+            }
+            _ => None,
+        }
+    }
 
-        Ok(response.to_string())
+    /// Forget the cached protocol, so that the next command probes again.
+    /// Transports that reconnect on their own don't need this, as changing
+    /// [`connection_id`](Transport::connection_id) has the same effect.
+    pub fn invalidate_protocol(&self) {
+        *self.protocol.lock().unwrap() = None;
+        *self.phases.lock().unwrap() = None;
     }
 
-    async fn read_all_response_packets(device: &HidDevice) -> Result<Vec<u8>> {
-        assert!(TERMINATOR.is_ascii());
+    /// The input and output phase counts, asking `QMD` only if not known yet
+    async fn phases(&self) -> Result<(u8, u8)> {
+        let connection_id = self.device.lock().await.connection_id();
+        if let Some((cached_connection_id, phases)) = *self.phases.lock().unwrap() {
+            if cached_connection_id == connection_id {
+                return Ok(phases);
+            }
+        }
 
-        let mut response: Vec<u8> = Vec::new();
-        loop {
-            let packet = Self::read_single_response_packet(device).await?;
+        let model = self.send(Command::Model).await?.into_model()?;
+        let phases = (model.input_phases, model.output_phases);
+        *self.phases.lock().unwrap() = Some((connection_id, phases));
 
-            response.extend(&packet);
+        Ok(phases)
+    }
 
-            if packet
-                .iter()
-                .find(|&&elem| elem == TERMINATOR as u8)
-                .is_some()
-            {
-                break;
+    /// Invalidate the cached protocol if `result` is a communication error,
+    /// as the UPS may have been swapped or restarted
+    fn check_result<R>(&self, result: Result<R>) -> Result<R> {
+        if let Err(error) = &result {
+            // A refusal means the UPS understood the command just fine
+            if !matches!(UpsError::of(error), Some(UpsError::Nak(_))) {
+                self.invalidate_protocol();
             }
         }
+        result
+    }
+
+    async fn send(&self, command: Command) -> Result<Response> {
+        let device = self.device.lock().await;
+        command::transact(&*device, command, self.crc).await
+    }
 
-        Ok(response)
+    /// Send a command without waiting for a response, for the V protocol
+    /// commands the UPS acts on silently
+    async fn send_unanswered(&self, command: Command) -> Result<()> {
+        let device = self.device.lock().await;
+        framing::send_command(&*device, &command.to_string(), self.crc).await
     }
 
-    async fn read_single_response_packet(device: &HidDevice) -> Result<Vec<u8>> {
-        let future = device.read_input_report();
-        let future = timeout(Duration::from_millis(RECEIVE_TIMEOUT_MS), future);
-        let (report_id, report) = match future.await {
-            Ok(result) => result?,
-            Err(_) => return Err(anyhow!("Receiving response timed-out")),
+    /// Send a command all protocols share, like the battery tests. V
+    /// protocol UPSes don't answer these, while P and T ones acknowledge
+    /// them.
+    async fn send_common(&self, command: Command) -> Result<()> {
+        let result = match self.protocol().await? {
+            UpsProtocol::V => self.send_unanswered(command).await,
+            UpsProtocol::P | UpsProtocol::T => self.send(command).await.map(|_| ()),
+            protocol => bail!(UpsError::Unsupported(format!(
+                "Voltronic protocol {:?} is not supported",
+                protocol
+            ))),
         };
+        self.check_result(result)
+    }
+
+    /// Status of P and T protocol UPSes, which share the single-phase commands
+    async fn status_p(&self) -> Result<UpsStatus> {
+        let mut status = self.send(Command::GeneralStatus).await?.into_status()?;
+        status.flags |= self.send(Command::Mode).await?.into_mode()?.flags();
+        status.flags |= self.send(Command::Warnings).await?.into_warnings()?.flags();
+
+        Ok(status)
+    }
 
-        if report_id != REPORT_ID {
-            return Err(anyhow!("Unexpected HID report ID"));
+    async fn status_t(&self) -> Result<UpsStatus> {
+        let mut status = self.status_p().await?;
+
+        // QGS only covers the first phase, so fill in the others on
+        // three-phase units
+        let (input_phases, output_phases) = self.phases().await?;
+        if input_phases > 1 {
+            let voltages = self
+                .send(Command::PhaseInputVoltages)
+                .await?
+                .into_phase_values()?;
+            status.input_voltage = voltages.into_iter().fold(f32::NAN, f32::min);
+        }
+        if output_phases > 1 {
+            let voltages = self
+                .send(Command::PhaseOutputVoltages)
+                .await?
+                .into_phase_values()?;
+            status.output_voltage = voltages.into_iter().fold(f32::NAN, f32::min);
+
+            let loads = self.send(Command::PhaseLoads).await?.into_phase_values()?;
+            status.output_load_level = loads.into_iter().fold(0.0, f32::max) as u32;
         }
 
-        Ok(report)
+        Ok(status)
+    }
+
+    /// Identity of P and T protocol UPSes, which keep it in three places
+    async fn info_p(&self) -> Result<UpsInfo> {
+        let model = self.send(Command::Model).await?.into_model()?;
+        let serial_number = refused_as_none(self.send(Command::SerialNumber).await)?
+            .map(Response::into_text)
+            .transpose()?;
+        let firmware_version = refused_as_none(self.send(Command::FirmwareVersion).await)?
+            .map(Response::into_text)
+            .transpose()?;
+
+        Ok(UpsInfo {
+            manufacturer: None,
+            model: Some(model.model),
+            firmware_version,
+            serial_number,
+        })
+    }
+
+    /// Ratings of P and T protocol UPSes, with the power rating and battery
+    /// count filled in from the model information
+    async fn ratings_p(&self) -> Result<UpsRatings> {
+        let mut ratings = self.send(Command::RatedInformation).await?.into_ratings()?;
+        let model = self.send(Command::Model).await?.into_model()?;
+        ratings.apparent_power = Some(model.rated_va);
+        ratings.battery_count = Some(model.battery_count);
+
+        Ok(ratings)
+    }
+
+    /// Toggle the beeper on P and T protocol UPSes
+    async fn beeper_toggle_p(&self) -> Result<()> {
+        let status = self.send(Command::GeneralStatus).await?.into_status()?;
+
+        // The beeper is the "a" flag
+        let command = if status.flags.contains(UpsStatusFlags::BEEPER_ACTIVE) {
+            Command::DisableFlag('a')
+        } else {
+            Command::EnableFlag('a')
+        };
+        self.send(command).await?;
+
+        Ok(())
     }
 }
 
 #[async_trait]
-impl Ups for VoltronicHidUps {
+impl<T: Transport> Ups for VoltronicHidUps<T> {
     async fn status(&self) -> Result<UpsStatus> {
-        match self.protocol().await? {
-            UpsProtocol::V => {}
-            _ => todo!("Protocol not implemented"),
+        let result = match self.protocol().await? {
+            UpsProtocol::V => self
+                .send(Command::Status)
+                .await
+                .and_then(Response::into_status),
+            UpsProtocol::P => self.status_p().await,
+            UpsProtocol::T => self.status_t().await,
+            protocol => bail!(UpsError::Unsupported(format!(
+                "Voltronic protocol {:?} is not supported",
+                protocol
+            ))),
         };
-
-        let response = self.transact_command("QS").await?;
-
-        Ok(response.parse()?)
+        self.check_result(result)
     }
 
     async fn beeper_toggle(&self) -> Result<()> {
+        let result = match self.protocol().await? {
+            UpsProtocol::V => self.send(Command::BeeperToggle).await.map(|_| ()),
+            UpsProtocol::P | UpsProtocol::T => self.beeper_toggle_p().await,
+            protocol => bail!(UpsError::Unsupported(format!(
+                "Voltronic protocol {:?} is not supported",
+                protocol
+            ))),
+        };
+        self.check_result(result)
+    }
+
+    async fn set_beeper(&self, enabled: bool) -> Result<()> {
         match self.protocol().await? {
-            UpsProtocol::V => {}
-            _ => todo!("Protocol not implemented"),
+            UpsProtocol::V => set_beeper_by_toggle(self, enabled).await,
+            UpsProtocol::P | UpsProtocol::T => {
+                // The beeper is the "a" flag
+                let command = if enabled {
+                    Command::EnableFlag('a')
+                } else {
+                    Command::DisableFlag('a')
+                };
+                self.check_result(self.send(command).await)?;
+                wait_for_beeper(|| beeper_active(self), enabled).await
+            }
+            protocol => bail!(UpsError::Unsupported(format!(
+                "Voltronic protocol {:?} is not supported",
+                protocol
+            ))),
+        }
+    }
+
+    async fn capabilities(&self) -> Result<UpsCapabilities> {
+        Ok(match self.check_result(self.protocol().await)? {
+            UpsProtocol::V | UpsProtocol::P | UpsProtocol::T => {
+                UpsCapabilities::BEEPER
+                    | UpsCapabilities::SELF_TEST
+                    | UpsCapabilities::SHUTDOWN
+                    | UpsCapabilities::OUTLET_CONTROL
+                    | UpsCapabilities::RATINGS
+                    | UpsCapabilities::TEMPERATURE
+            }
+            UpsProtocol::Unknown => UpsCapabilities::empty(),
+        })
+    }
+
+    async fn info(&self) -> Result<UpsInfo> {
+        let result = match self.protocol().await? {
+            UpsProtocol::V => self.send(Command::Info).await.and_then(Response::into_info),
+            UpsProtocol::P | UpsProtocol::T => self.info_p().await,
+            protocol => bail!(UpsError::Unsupported(format!(
+                "Voltronic protocol {:?} is not supported",
+                protocol
+            ))),
+        };
+        self.check_result(result)
+    }
+
+    async fn ratings(&self) -> Result<UpsRatings> {
+        let result = match self.protocol().await? {
+            UpsProtocol::V => self
+                .send(Command::Ratings)
+                .await
+                .and_then(Response::into_ratings),
+            UpsProtocol::P | UpsProtocol::T => self.ratings_p().await,
+            protocol => bail!(UpsError::Unsupported(format!(
+                "Voltronic protocol {:?} is not supported",
+                protocol
+            ))),
         };
+        self.check_result(result)
+    }
 
-        self.transact_command("Q").await?;
+    async fn start_self_test(&self, test: SelfTest) -> Result<SelfTestHandle> {
+        self.send_common(test.try_into()?).await?;
+        Ok(SelfTestHandle::new(test))
+    }
 
-        Ok(())
+    async fn cancel_self_test(&self) -> Result<()> {
+        self.send_common(Command::CancelSelfTest).await
+    }
+
+    async fn schedule_shutdown(
+        &self,
+        delay: Duration,
+        restore_after: Option<Duration>,
+    ) -> Result<()> {
+        self.send_common(Command::shutdown(delay, restore_after)?)
+            .await
+    }
+
+    async fn cancel_shutdown(&self) -> Result<()> {
+        self.send_common(Command::CancelShutdown).await
+    }
+}
+
+/// Treat a refused command as the UPS not having what it asks for
+fn refused_as_none<R>(result: Result<R>) -> Result<Option<R>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(error) if matches!(UpsError::of(&error), Some(UpsError::Nak(_))) => Ok(None),
+        Err(error) => Err(error),
     }
 }
 
@@ -149,3 +358,569 @@ pub enum UpsProtocol {
     V,
     Unknown,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock_transport::MockTransport, ups::UpsStatusFlags};
+
+    const STATUS: &str = "(215.0 195.0 230.0 014 49.0 27.5 30.0 00001001";
+
+    #[tokio::test]
+    async fn detects_protocol() {
+        for (response, protocol) in [
+            ("P", UpsProtocol::P),
+            ("T", UpsProtocol::T),
+            ("V", UpsProtocol::V),
+            ("X", UpsProtocol::Unknown),
+        ] {
+            let mock = MockTransport::new().expect_command("M", response);
+            let ups = VoltronicHidUps::new(mock).unwrap();
+
+            assert_eq!(ups.protocol().await.unwrap(), protocol);
+        }
+    }
+
+    #[tokio::test]
+    async fn status_spans_multiple_packets() {
+        let mock = MockTransport::new()
+            .expect_command("M", "V")
+            .expect_command("QS", STATUS);
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        let status = ups.status().await.unwrap();
+
+        assert_eq!(status.input_voltage, 215.0);
+        assert_eq!(status.input_fault_voltage, 195.0);
+        assert_eq!(status.output_voltage, 230.0);
+        assert_eq!(status.output_load_level, 14);
+        assert_eq!(status.output_frequency, 49.0);
+        assert_eq!(status.battery_voltage, 27.5);
+        assert_eq!(status.internal_temperature, 30.0);
+        assert_eq!(
+            status.flags,
+            UpsStatusFlags::UPS_LINE_INTERACTIVE | UpsStatusFlags::BEEPER_ACTIVE
+        );
+    }
+
+    #[tokio::test]
+    async fn beeper_toggle_sends_q() {
+        let mock = MockTransport::new()
+            .expect_command("M", "V")
+            .expect_command("Q", "");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        ups.beeper_toggle().await.unwrap();
+    }
+
+    const GENERAL_STATUS: &str =
+        "(234.9 50.0 229.8 50.0 000 012 369.1 ---.- 026.5 ---.- 018.8 011000000100";
+    const NO_WARNINGS: &str = "(00000000000000000000000000000000";
+
+    #[tokio::test]
+    async fn p_status_combines_qgs_qmod_and_qws() {
+        let mock = MockTransport::new()
+            .expect_command("M", "P")
+            .expect_command("QGS", GENERAL_STATUS)
+            .expect_command("QMOD", "(B")
+            .expect_command("QWS", "(00000001000000000000000000000000");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        let status = ups.status().await.unwrap();
+
+        assert_eq!(status.input_voltage, 234.9);
+        assert!(status.input_fault_voltage.is_nan());
+        assert_eq!(status.output_voltage, 229.8);
+        assert_eq!(status.output_load_level, 12);
+        assert_eq!(status.output_frequency, 50.0);
+        assert_eq!(status.battery_voltage, 26.5);
+        assert_eq!(status.internal_temperature, 18.8);
+        assert_eq!(
+            status.flags,
+            UpsStatusFlags::UPS_LINE_INTERACTIVE
+                | UpsStatusFlags::UTILITY_FAIL
+                | UpsStatusFlags::BATTERY_LOW
+                | UpsStatusFlags::BEEPER_ACTIVE
+        );
+    }
+
+    #[tokio::test]
+    async fn p_beeper_toggle_uses_flags() {
+        let mock = MockTransport::new()
+            .expect_command("M", "P")
+            .expect_command("QGS", GENERAL_STATUS)
+            .expect_command("PDa", "(ACK")
+            .expect_command(
+                "QGS",
+                "(234.9 50.0 229.8 50.0 000 012 369.1 ---.- 026.5 ---.- 018.8 010000000000",
+            )
+            .expect_command("PEa", "(NAK");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        ups.beeper_toggle().await.unwrap();
+        let error = ups.beeper_toggle().await.unwrap_err();
+        assert_eq!(
+            UpsError::of(&error),
+            Some(&UpsError::Nak("PEa".to_string()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn p_set_beeper_uses_flags_and_waits_for_qgs() {
+        let mock = MockTransport::new()
+            .expect_command("PDa", "(ACK")
+            .expect_command("QGS", GENERAL_STATUS)
+            .expect_command("QMOD", "(L")
+            .expect_command("QWS", NO_WARNINGS)
+            .expect_command(
+                "QGS",
+                "(234.9 50.0 229.8 50.0 000 012 369.1 ---.- 026.5 ---.- 018.8 010000000000",
+            )
+            .expect_command("QMOD", "(L")
+            .expect_command("QWS", NO_WARNINGS);
+        let ups = VoltronicHidUps::with_protocol(mock, UpsProtocol::P).unwrap();
+
+        ups.set_beeper(false).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn v_set_beeper_leaves_matching_state_alone() {
+        let mock = MockTransport::new()
+            .expect_command("M", "V")
+            .expect_command("QS", STATUS);
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        ups.set_beeper(true).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn v_set_beeper_toggles_once_then_times_out() {
+        let mut mock = MockTransport::new()
+            .expect_command("M", "V")
+            .expect_command("QS", STATUS)
+            .expect_command("Q", "");
+        // The status keeps showing the beeper as on, every 250ms for 3s
+        for _ in 0..13 {
+            mock = mock.expect_command("QS", STATUS);
+        }
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        let error = ups.set_beeper(false).await.unwrap_err();
+
+        assert!(matches!(UpsError::of(&error), Some(UpsError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn p_mode_without_warnings() {
+        let mock = MockTransport::new()
+            .expect_command("M", "P")
+            .expect_command(
+                "QGS",
+                "(234.9 50.0 229.8 50.0 000 012 369.1 ---.- 026.5 ---.- 018.8 100000000000",
+            )
+            .expect_command("QMOD", "(F")
+            .expect_command("QWS", NO_WARNINGS);
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        let status = ups.status().await.unwrap();
+
+        assert_eq!(status.flags, UpsStatusFlags::UPS_FAULT);
+    }
+
+    const SINGLE_PHASE_MODEL: &str = "(#######OLHVT1K0 ###1000 80 1/1 230 230 02 12.0";
+
+    #[tokio::test]
+    async fn t_status_on_single_phase_unit() {
+        let mock = MockTransport::new()
+            .expect_command("M", "T")
+            .expect_command("QGS", GENERAL_STATUS)
+            .expect_command("QMOD", "(L")
+            .expect_command("QWS", NO_WARNINGS)
+            .expect_command("QMD", SINGLE_PHASE_MODEL);
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        let status = ups.status().await.unwrap();
+
+        assert_eq!(status.input_voltage, 234.9);
+        assert_eq!(status.output_voltage, 229.8);
+        assert_eq!(status.output_load_level, 12);
+    }
+
+    #[tokio::test]
+    async fn t_status_on_three_phase_unit() {
+        let mock = MockTransport::new()
+            .expect_command("M", "T")
+            .expect_command(
+                "QGS",
+                "(231.0 50.0 230.1 50.0 021 030 380.2 379.8 218.4 218.6 031.5 100000000000",
+            )
+            .expect_command("QMOD", "(Y")
+            .expect_command("QWS", NO_WARNINGS)
+            .expect_command("QMD", "(######ONLINE10K3 ##10000 90 3/3 220 220 16 12.0")
+            .expect_command("Q3PV", "(231.0 228.5 233.2 398.1 397.6 401.0")
+            .expect_command("Q3OV", "(230.1 229.9 230.3")
+            .expect_command("Q3LD", "(030 045 ---");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        let status = ups.status().await.unwrap();
+
+        assert_eq!(status.input_voltage, 228.5);
+        assert_eq!(status.output_voltage, 229.9);
+        assert_eq!(status.output_load_level, 45);
+        assert_eq!(status.battery_voltage, 218.4);
+        assert_eq!(status.flags, UpsStatusFlags::empty());
+    }
+
+    #[tokio::test]
+    async fn t_status_with_three_phase_input_only() {
+        let mock = MockTransport::new()
+            .expect_command("M", "T")
+            .expect_command("QGS", GENERAL_STATUS)
+            .expect_command("QMOD", "(B")
+            .expect_command("QWS", NO_WARNINGS)
+            .expect_command("QMD", "(#######OLHVT6K0 ###6000 90 3/1 220 220 16 12.0")
+            .expect_command("Q3PV", "(000.0 000.0 000.0 000.0 000.0 000.0");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        let status = ups.status().await.unwrap();
+
+        assert_eq!(status.input_voltage, 0.0);
+        assert_eq!(status.output_voltage, 229.8);
+        assert!(status.flags.contains(UpsStatusFlags::UTILITY_FAIL));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn phases_are_cached_with_the_protocol() {
+        let mock = MockTransport::new()
+            .expect_command("M", "T")
+            .expect_command("QGS", GENERAL_STATUS)
+            .expect_command("QMOD", "(L")
+            .expect_command("QWS", NO_WARNINGS)
+            .expect_command("QMD", SINGLE_PHASE_MODEL)
+            .expect_command("QGS", GENERAL_STATUS)
+            .expect_command("QMOD", "(L")
+            .expect_command("QWS", NO_WARNINGS)
+            .expect_command_unanswered("QGS")
+            .expect_command("M", "T")
+            .expect_command("QGS", GENERAL_STATUS)
+            .expect_command("QMOD", "(L")
+            .expect_command("QWS", NO_WARNINGS)
+            .expect_command("QMD", SINGLE_PHASE_MODEL);
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        ups.status().await.unwrap();
+        ups.status().await.unwrap();
+
+        // Losing the protocol loses the phases too
+        assert!(ups.status().await.is_err());
+        ups.status().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn t_beeper_toggle_uses_flags() {
+        let mock = MockTransport::new()
+            .expect_command("M", "T")
+            .expect_command("QGS", GENERAL_STATUS)
+            .expect_command("PDa", "(ACK");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        ups.beeper_toggle().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn capabilities_depend_on_protocol() {
+        let mock = MockTransport::new().expect_command("M", "X");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+        assert_eq!(ups.capabilities().await.unwrap(), UpsCapabilities::empty());
+
+        let ups = VoltronicHidUps::with_protocol(MockTransport::new(), UpsProtocol::P).unwrap();
+        assert!(ups
+            .capabilities()
+            .await
+            .unwrap()
+            .contains(UpsCapabilities::BEEPER | UpsCapabilities::OUTLET_CONTROL));
+    }
+
+    #[tokio::test]
+    async fn p_info_and_ratings() {
+        let mock = MockTransport::new()
+            .expect_command("QMD", SINGLE_PHASE_MODEL)
+            .expect_command("QID", "(92931707100843")
+            .expect_command("QVFW", "(NAK")
+            .expect_command("QRI", "(230.0 004 024.0 50.0")
+            .expect_command("QMD", SINGLE_PHASE_MODEL);
+        let ups = VoltronicHidUps::with_protocol(mock, UpsProtocol::P).unwrap();
+
+        let info = ups.info().await.unwrap();
+        assert_eq!(
+            info,
+            UpsInfo {
+                manufacturer: None,
+                model: Some("OLHVT1K0".to_string()),
+                firmware_version: None,
+                serial_number: Some("92931707100843".to_string()),
+            }
+        );
+
+        let ratings = ups.ratings().await.unwrap();
+        assert_eq!(
+            ratings,
+            UpsRatings {
+                voltage: Some(230.0),
+                current: Some(4.0),
+                frequency: Some(50.0),
+                apparent_power: Some(1000),
+                battery_voltage: Some(24.0),
+                battery_count: Some(2),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn v_info_and_ratings() {
+        let mock = MockTransport::new()
+            .expect_command("I", "#UPS Inc.        Line 650   V1.0      ")
+            .expect_command("F", "#230.0 002 12.00 50.0");
+        let ups = VoltronicHidUps::with_protocol(mock, UpsProtocol::V).unwrap();
+
+        assert_eq!(
+            ups.info().await.unwrap().firmware_version.as_deref(),
+            Some("V1.0")
+        );
+        assert_eq!(ups.ratings().await.unwrap().current, Some(2.0));
+    }
+
+    #[tokio::test]
+    async fn self_test_commands() {
+        let mock = MockTransport::new()
+            .expect_command("M", "P")
+            .expect_command("T", "(ACK")
+            .expect_command("T10", "(ACK")
+            .expect_command("TL", "(NAK")
+            .expect_command("CT", "(ACK");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        let handle = ups.start_self_test(SelfTest::Quick).await.unwrap();
+        assert_eq!(handle.test(), SelfTest::Quick);
+        ups.start_self_test(SelfTest::Timed(10)).await.unwrap();
+        assert!(ups
+            .start_self_test(SelfTest::UntilBatteryLow)
+            .await
+            .is_err());
+        ups.cancel_self_test().await.unwrap();
+
+        assert!(ups.start_self_test(SelfTest::Timed(100)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v_self_test_commands_are_unanswered() {
+        let mock = MockTransport::new()
+            .expect_command("M", "V")
+            .expect_command_sent("T")
+            .expect_command_sent("T10")
+            .expect_command_sent("TL")
+            .expect_command_sent("CT");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        ups.start_self_test(SelfTest::Quick).await.unwrap();
+        ups.start_self_test(SelfTest::Timed(10)).await.unwrap();
+        ups.start_self_test(SelfTest::UntilBatteryLow)
+            .await
+            .unwrap();
+        ups.cancel_self_test().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_commands() {
+        let mock = MockTransport::new()
+            .expect_command("S.5R0002", "(ACK")
+            .expect_command("S03R0000", "(ACK")
+            .expect_command("C", "(ACK");
+        let ups = VoltronicHidUps::with_protocol(mock, UpsProtocol::P).unwrap();
+
+        ups.schedule_shutdown(Duration::from_secs(30), Some(Duration::from_secs(120)))
+            .await
+            .unwrap();
+        ups.schedule_shutdown(Duration::from_secs(180), None)
+            .await
+            .unwrap();
+        ups.cancel_shutdown().await.unwrap();
+
+        assert!(ups
+            .schedule_shutdown(Duration::from_secs(1), None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn v_shutdown_commands_are_unanswered() {
+        let mock = MockTransport::new()
+            .expect_command_sent("S.5R0002")
+            .expect_command_sent("S03R0000")
+            .expect_command_sent("C");
+        let ups = VoltronicHidUps::with_protocol(mock, UpsProtocol::V).unwrap();
+
+        ups.schedule_shutdown(Duration::from_secs(30), Some(Duration::from_secs(120)))
+            .await
+            .unwrap();
+        ups.schedule_shutdown(Duration::from_secs(180), None)
+            .await
+            .unwrap();
+        ups.cancel_shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn protocol_is_cached() {
+        let mock = MockTransport::new()
+            .expect_command("M", "V")
+            .expect_command("QS", STATUS)
+            .expect_command("QS", STATUS)
+            .expect_command("Q", "");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+        assert_eq!(ups.cached_protocol().await, None);
+
+        ups.status().await.unwrap();
+        ups.status().await.unwrap();
+        ups.beeper_toggle().await.unwrap();
+
+        assert_eq!(ups.cached_protocol().await, Some(UpsProtocol::V));
+    }
+
+    #[tokio::test]
+    async fn unknown_protocol_is_not_cached() {
+        let mock = MockTransport::new()
+            .expect_command("M", "X")
+            .expect_command("M", "V");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        assert_eq!(ups.protocol().await.unwrap(), UpsProtocol::Unknown);
+        assert_eq!(ups.protocol().await.unwrap(), UpsProtocol::V);
+    }
+
+    #[tokio::test]
+    async fn garbled_protocol_is_a_framing_error() {
+        let mock = MockTransport::new()
+            .expect_command("M", "#V")
+            .expect_command("M", "V")
+            .expect_command("QS", STATUS);
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        let error = ups.status().await.unwrap_err();
+        assert!(matches!(UpsError::of(&error), Some(UpsError::Framing(_))));
+
+        ups.status().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn communication_error_invalidates_protocol() {
+        let mock = MockTransport::new()
+            .expect_command("M", "V")
+            .expect_command_unanswered("QS")
+            .expect_command("M", "P")
+            .expect_command("QGS", GENERAL_STATUS)
+            .expect_command("QMOD", "(L")
+            .expect_command("QWS", NO_WARNINGS);
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        assert!(ups.status().await.is_err());
+        assert_eq!(ups.cached_protocol().await, None);
+
+        ups.status().await.unwrap();
+        assert_eq!(ups.cached_protocol().await, Some(UpsProtocol::P));
+    }
+
+    #[tokio::test]
+    async fn nak_keeps_protocol() {
+        let mock = MockTransport::new()
+            .expect_command("QGS", GENERAL_STATUS)
+            .expect_command("PDa", "(NAK");
+        let ups = VoltronicHidUps::with_protocol(mock, UpsProtocol::P).unwrap();
+
+        assert!(ups.beeper_toggle().await.is_err());
+        assert_eq!(ups.cached_protocol().await, Some(UpsProtocol::P));
+    }
+
+    #[tokio::test]
+    async fn invalidation_forces_a_new_probe() {
+        let mock = MockTransport::new()
+            .expect_command("M", "V")
+            .expect_command("QS", STATUS);
+        let ups = VoltronicHidUps::with_protocol(mock, UpsProtocol::P).unwrap();
+
+        ups.invalidate_protocol();
+        ups.status().await.unwrap();
+
+        assert_eq!(ups.cached_protocol().await, Some(UpsProtocol::V));
+    }
+
+    #[tokio::test]
+    async fn corrupted_response_fails_crc() {
+        let with_crc = |data: &[u8]| {
+            let mut data = data.to_vec();
+            data.extend(crate::framing::crc16_xmodem(&data).to_be_bytes());
+            data
+        };
+        let mut response = with_crc(STATUS.as_bytes());
+        response[2] = b'9';
+
+        let mock = MockTransport::new().expect_command_bytes(&with_crc(b"QS"), &response);
+        let mut ups = VoltronicHidUps::with_protocol(mock, UpsProtocol::V).unwrap();
+        ups.set_crc_policy(CrcPolicy::Required);
+
+        let error = ups.status().await.unwrap_err();
+        assert!(matches!(
+            UpsError::of(&error),
+            Some(UpsError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn wrong_report_id_is_an_error() {
+        let mock = MockTransport::new()
+            .expect_output(0, b"M\r")
+            .input(1, b"V\r\0\0\0\0\0\0");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        let error = ups.protocol().await.unwrap_err();
+        assert_eq!(
+            UpsError::of(&error),
+            Some(&UpsError::UnexpectedReportId {
+                expected: 0,
+                actual: 1
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_command_times_out() {
+        let mock = MockTransport::new().expect_command_unanswered("M");
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        let error = ups.status().await.unwrap_err();
+        assert!(matches!(UpsError::of(&error), Some(UpsError::Timeout(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_packet_times_out() {
+        let mock = MockTransport::new().expect_output(0, b"M\r").input_after(
+            Duration::from_millis(300),
+            0,
+            b"V\r\0\0\0\0\0\0",
+        );
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        assert!(ups.protocol().await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_response_times_out() {
+        // Every packet arrives in time, but the response as a whole doesn't
+        let mut mock = MockTransport::new().expect_output(0, b"M\r");
+        for _ in 0..11 {
+            mock = mock.input_after(Duration::from_millis(220), 0, b"VVVVVVVV");
+        }
+        let ups = VoltronicHidUps::new(mock).unwrap();
+
+        assert!(ups.protocol().await.is_err());
+    }
+}